@@ -3,6 +3,9 @@ import json
 import math
 
 filename = "vertical_2000.json"
+# Pitch motor steps for half a turn, half of `steps_per_rev * gear_ratio`
+# from `[kinematics.pitch]` in lidarino_config.toml.
+PITCH_STEPS_PER_PI = 4000
 
 data = json.load(open(filename))
 print(data)
@@ -11,7 +14,7 @@ x_data = []
 y_data = []
 
 for line in data:
-    angle = math.radians(90.0 + line["pitch"] / 2000.0 * 90.0)
+    angle = math.radians(90.0 + line["pitch"] / PITCH_STEPS_PER_PI * 180.0)
     distance = line["distance_mm"] / 1000
     x, y = distance * math.sin(angle), distance * math.cos(angle)
     x_data.append(x)
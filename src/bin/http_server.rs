@@ -1,49 +1,65 @@
 use std::time::Duration;
 
 use lazy_static::lazy_static;
-use lidarino::config::{Config, CONFIG_PATH};
+use lidarino::config::{self, Config};
+use lidarino::export::ExportFormat;
 use lidarino::hardware::distance::DistanceReading;
 use lidarino::hardware::mpu::OrientationController;
 use lidarino::hardware::mpu::*;
 use lidarino::hardware::{
-    DISTANCE_CONTROLLER, ORIENTATION_CONTROLLER, PITCH_CONTROLLER, YAW_CONTROLLER,
+    DISTANCE_CONTROLLER, KINEMATICS, ORIENTATION_CONTROLLER, PITCH_CONTROLLER, YAW_CONTROLLER,
 };
+use lidarino::logging;
+use lidarino::metrics;
+use lidarino::motion::{self, Axis, JogCommand, MotionSettings, MoveCommand, Unit};
+use lidarino::pattern::PatternSpec;
+use lidarino::scan::{ScanError, ScanJob, ScanState};
+use lidarino::server::{HttpConfig, Role};
+use lidarino::shutdown;
+use lidarino::sphere::ScanOptions;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::Mutex;
+use std::convert::Infallible;
+use tracing::{debug, info, warn};
+use warp::http::uri::Authority;
+use warp::http::{StatusCode, Uri};
+use warp::path::FullPath;
+use warp::reply::{Reply, Response};
 use warp::ws::{Message, WebSocket};
-use warp::Filter;
+use warp::{Filter, Rejection};
 
 lazy_static! {
-    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
+    static ref SCAN_JOB: ScanJob = ScanJob::new();
 }
 
 fn init_orientation() {
     let mut orientation_controller = ORIENTATION_CONTROLLER.lock().unwrap();
     if orientation_controller.is_none() {
-        let mpu_config = CONFIG.lock().unwrap().mpu_config.unwrap();
-        let mpu = Mpu::new(mpu_config);
+        let mpu = Mpu::from_config(&config::current());
         let new_c = OrientationController::new(mpu);
         *orientation_controller = Some(new_c);
-        println!("Done initialization, pls dont access MPU using other means. FIXME");
+        info!("Done initialization, pls dont access MPU using other means. FIXME");
     }
 }
 
 fn main() {
     println!("WELCOME TO LIDARINO WEB SERVER");
-    if CONFIG.lock().unwrap().load_from_file(CONFIG_PATH).is_ok() {
-        println!("Succesfully loaded config from \"{CONFIG_PATH}\"");
-    } else {
-        println!("Failed loading config from \"{CONFIG_PATH}\"");
-    };
+    shutdown::install().expect("Failed to install shutdown handlers");
+    let config = Config::from_args().unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    });
+    logging::init(&config.logging.clone().unwrap_or_default()).expect("Failed to set up logging");
+    info!(path = %config::path().display(), "Loaded config");
+    config::set_current(config);
+    config::reload_on_sighup().expect("Failed to install config reload handler");
     init_orientation();
-    env_logger::init();
     start_http();
     unreachable!();
 }
 
-fn measure_distance() -> warp::reply::Json {
-    let reading = DISTANCE_CONTROLLER.get_measurement();
+async fn measure_distance() -> Result<warp::reply::Json, Infallible> {
+    let reading = DISTANCE_CONTROLLER.measure().await;
     let reply = match reading {
         DistanceReading::Ok {
             distance,
@@ -53,7 +69,7 @@ fn measure_distance() -> warp::reply::Json {
             json!({
                 "distance_mm": distance.as_mm(),
                 "quality": quality,
-                "measuring_time_ms": measuring_time.as_millis(),
+                "measuring_time_ms": measuring_time.as_millis() as u64,
             })
         }
         _ => {
@@ -63,32 +79,52 @@ fn measure_distance() -> warp::reply::Json {
         }
     };
 
-    warp::reply::json(&reply)
+    Ok(warp::reply::json(&reply))
 }
-use futures_util::{FutureExt, SinkExt, StreamExt};
-use tokio::time::sleep;
+use futures_util::{SinkExt, StreamExt};
+use nalgebra::UnitQuaternion;
+use tokio::sync::watch;
 
-async fn orientation_connected(ws: WebSocket) {
+async fn orientation_connected(ws: WebSocket, orientation: watch::Receiver<UnitQuaternion<f32>>) {
     let (mut tx, mut rx) = ws.split();
     tokio::task::spawn(async move {
-        let mut dt = 0.0f32;
+        let mut interval = tokio::time::interval(Duration::from_secs(1) / 60);
         loop {
-            sleep(Duration::from_secs(1) / 60).await;
-            let (roll, pitch, yaw) = ORIENTATION_CONTROLLER
-                .lock()
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .get_quat()
-                .euler_angles();
+            interval.tick().await;
+            let (roll, pitch, yaw) = orientation.borrow().euler_angles();
 
             let message = Message::text(format!("{roll},{pitch},{yaw}"));
-            if let Err(e) = tx.send(message).await {
+            if tx.send(message).await.is_err() {
                 break;
             }
         }
     });
-    while let Some(result) = rx.next().await {}
+    while rx.next().await.is_some() {}
+}
+
+/// Snapshot of the current scan, then every scan event as it happens, as JSON text messages.
+async fn scan_events_connected(ws: WebSocket) {
+    let (mut tx, mut rx) = ws.split();
+    let (mut event, mut events) = SCAN_JOB.subscribe_with_snapshot();
+    loop {
+        let message = Message::text(serde_json::to_string(&event).unwrap());
+        if tx.send(message).await.is_err() {
+            return;
+        }
+        // Waits on the client too, so the subscription ends as soon as it's gone.
+        event = loop {
+            tokio::select! {
+                next = events.recv() => match next {
+                    Some(next) => break next,
+                    None => return,
+                },
+                message = rx.next() => match message {
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return,
+                },
+            }
+        };
+    }
 }
 
 fn send_current_state() -> warp::reply::Json {
@@ -106,6 +142,8 @@ fn send_current_state() -> warp::reply::Json {
     let reply = json!({
         "yaw": yaw,
         "pitch": pitch,
+        "yaw_deg": KINEMATICS.yaw.to_degrees(yaw),
+        "pitch_deg": KINEMATICS.pitch.to_degrees(pitch),
         "prev_dist_mm": distance,
         "prev_quality": quality,
     });
@@ -113,39 +151,406 @@ fn send_current_state() -> warp::reply::Json {
     warp::reply::json(&reply)
 }
 
+fn send_metrics() -> impl Reply {
+    let body = metrics::render(SCAN_JOB.status().as_ref());
+    warp::reply::with_header(body, "Content-Type", "text/plain; version=0.0.4")
+}
+
+/// Target position in motor steps, or axis angles in degrees if steps aren't given.
 #[derive(Serialize, Deserialize, Debug)]
 struct SetPosition {
     yaw: Option<i32>,
     pitch: Option<i32>,
+    yaw_deg: Option<f32>,
+    pitch_deg: Option<f32>,
 }
 
-fn set_position(cmd: SetPosition) -> warp::reply::Json {
-    println!("{cmd:?}");
-    let reply = json!("Ok");
+fn set_position(cmd: SetPosition) -> Response {
+    debug!(?cmd, "Setting position");
+    if let Err(e) = check_not_scanning() {
+        return scan_error_reply(e);
+    }
+    // Steps win over degrees given for the same axis.
+    let steps = MoveCommand {
+        yaw: cmd.yaw.map(|steps| steps as f32),
+        pitch: cmd.pitch.map(|steps| steps as f32),
+        unit: Unit::Steps,
+        relative: false,
+        wait: false,
+    };
+    let deg = MoveCommand {
+        yaw: cmd.yaw_deg.filter(|_| cmd.yaw.is_none()),
+        pitch: cmd.pitch_deg.filter(|_| cmd.pitch.is_none()),
+        unit: Unit::Deg,
+        ..steps.clone()
+    };
+    let moved = motion::check_move(&steps)
+        .and(motion::check_move(&deg))
+        .and_then(|_| motion::move_axes(&steps))
+        .and_then(|()| motion::move_axes(&deg));
+    match moved {
+        Ok(()) => json_reply(&json!("Ok"), StatusCode::OK),
+        Err(e) => error_reply(StatusCode::BAD_REQUEST, e),
+    }
+}
 
-    if let Some(yaw) = cmd.yaw {
-        YAW_CONTROLLER.set_target_pos(yaw);
+/// Axis of `zero` and `home`, both if missing.
+#[derive(Deserialize, Debug)]
+struct MotionQuery {
+    axis: Option<Axis>,
+    /// Reply once the motion has settled.
+    #[serde(default)]
+    wait: bool,
+}
+
+impl MotionQuery {
+    fn axes(&self) -> Vec<Axis> {
+        self.axis
+            .map_or_else(|| Axis::ALL.to_vec(), |axis| vec![axis])
     }
+}
 
-    if let Some(pitch) = cmd.pitch {
-        PITCH_CONTROLLER.set_target_pos(pitch);
+/// Manual motion would fight a running scan over the motors.
+fn check_not_scanning() -> Result<(), ScanError> {
+    match SCAN_JOB.status() {
+        Some(status) if status.info.state == ScanState::Scanning => Err(ScanError::Conflict(
+            "scan is running, pause it first".to_string(),
+        )),
+        _ => Ok(()),
     }
+}
 
-    warp::reply::json(&reply)
+/// Positions right away, or once both axes settled if `wait` is set.
+async fn motion_reply(wait: bool) -> Response {
+    if !wait {
+        return json_reply(&motion::status(), StatusCode::ACCEPTED);
+    }
+    motion::settled().await;
+    json_reply(&motion::status(), StatusCode::OK)
 }
 
-#[tokio::main(worker_threads = 1)]
-async fn start_http() {
+fn motion_status() -> Response {
+    json_reply(&motion::status(), StatusCode::OK)
+}
+
+async fn move_motion(cmd: MoveCommand) -> Result<Response, Infallible> {
+    if let Err(e) = check_not_scanning() {
+        return Ok(scan_error_reply(e));
+    }
+    if let Err(e) = motion::move_axes(&cmd) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, e));
+    }
+    Ok(motion_reply(cmd.wait).await)
+}
+
+fn jog_motion(cmd: JogCommand) -> Response {
+    if let Err(e) = check_not_scanning() {
+        return scan_error_reply(e);
+    }
+    motion::jog(&cmd);
+    json_reply(&motion::status(), StatusCode::OK)
+}
+
+/// Stop both axes, pausing the scan if one is running.
+fn stop_motion() -> Response {
+    SCAN_JOB.halt();
+    motion::stop();
+    json_reply(&motion::status(), StatusCode::OK)
+}
+
+fn zero_motion(query: MotionQuery) -> Response {
+    if let Err(e) = check_not_scanning() {
+        return scan_error_reply(e);
+    }
+    motion::zero(&query.axes());
+    json_reply(&motion::status(), StatusCode::OK)
+}
+
+async fn home_motion(query: MotionQuery) -> Result<Response, Infallible> {
+    if let Err(e) = check_not_scanning() {
+        return Ok(scan_error_reply(e));
+    }
+    motion::home(&query.axes());
+    Ok(motion_reply(query.wait).await)
+}
+
+fn get_motion_settings() -> Response {
+    json_reply(&motion::settings(), StatusCode::OK)
+}
+
+fn set_motion_settings(settings: MotionSettings) -> Response {
+    match motion::set_settings(&settings) {
+        Ok(()) => json_reply(&motion::settings(), StatusCode::OK),
+        Err(e) => error_reply(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Apply tuning values of the config file, see [`config::reload`]. API tokens change right
+/// away, listeners and allowed origins on restart.
+fn reload_config() -> Response {
+    match config::reload() {
+        Ok(config) => json_reply(
+            &json!({ "path": config::path(), "version": config.version }),
+            StatusCode::OK,
+        ),
+        Err(e) => error_reply(StatusCode::BAD_REQUEST, format!("{e:#}")),
+    }
+}
+
+fn json_reply<T: Serialize>(value: &T, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(value), status).into_response()
+}
+
+fn error_reply(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    json_reply(&json!({ "error": message.to_string() }), status)
+}
+
+fn scan_error_reply(e: ScanError) -> Response {
+    let status = match e {
+        ScanError::Invalid(_) => StatusCode::BAD_REQUEST,
+        ScanError::NotFound => StatusCode::NOT_FOUND,
+        ScanError::Conflict(_) => StatusCode::CONFLICT,
+        ScanError::Storage(_) | ScanError::Stopped => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_reply(status, e)
+}
+
+/// Scan id from the path, `current` for the scan being worked on.
+fn scan_id(id: &str) -> Result<u64, ScanError> {
+    match id {
+        "current" => SCAN_JOB
+            .status()
+            .map(|status| status.info.id)
+            .ok_or(ScanError::NotFound),
+        id => id.parse().map_err(|_| ScanError::NotFound),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateScan {
+    options: ScanOptions,
+    #[serde(default)]
+    pattern: PatternSpec,
+}
+
+/// Run `handler` on a blocking thread. It generates patterns or reads and writes scan
+/// files, the single runtime worker keeps serving websockets meanwhile.
+async fn blocking<F>(handler: F) -> Result<Response, Infallible>
+where
+    F: FnOnce() -> Response + Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(handler)
+        .await
+        .unwrap_or_else(|e| error_reply(StatusCode::INTERNAL_SERVER_ERROR, e)))
+}
+
+fn create_scan(cmd: CreateScan) -> Response {
+    match SCAN_JOB.create_scan(cmd.options, cmd.pattern) {
+        Ok(scan) => json_reply(&scan, StatusCode::CREATED),
+        Err(e) => scan_error_reply(e),
+    }
+}
+
+fn list_scans() -> Response {
+    match SCAN_JOB.scans() {
+        Ok(scans) => json_reply(&scans, StatusCode::OK),
+        Err(e) => scan_error_reply(e),
+    }
+}
+
+/// Progress of the current scan, or info of a saved one.
+fn get_scan(id: String) -> Response {
+    let result = scan_id(&id).and_then(|id| match SCAN_JOB.status() {
+        Some(status) if status.info.id == id => Ok(json_reply(&status, StatusCode::OK)),
+        _ => SCAN_JOB
+            .scan(id)
+            .map(|scan| json_reply(&scan, StatusCode::OK)),
+    });
+    result.unwrap_or_else(scan_error_reply)
+}
+
+fn get_path() -> Response {
+    match SCAN_JOB.path() {
+        Some(path) => json_reply(&path, StatusCode::OK),
+        None => scan_error_reply(ScanError::NotFound),
+    }
+}
+
+fn generate_path(pattern: PatternSpec) -> Response {
+    match SCAN_JOB.generate_path(pattern) {
+        Ok(()) => json_reply(&SCAN_JOB.status(), StatusCode::ACCEPTED),
+        Err(e) => scan_error_reply(e),
+    }
+}
+
+/// Start, pause, resume or cancel the current scan.
+fn control_scan(action: String) -> Response {
+    let result = match action.as_str() {
+        "start" => SCAN_JOB.start_scan(),
+        "pause" => SCAN_JOB.pause_scan(),
+        "resume" => SCAN_JOB.resume_scan(),
+        "cancel" => SCAN_JOB.cancel_scan(),
+        _ => {
+            return error_reply(
+                StatusCode::NOT_FOUND,
+                format!("unknown action \"{action}\""),
+            )
+        }
+    };
+    match result {
+        Ok(()) => json_reply(&SCAN_JOB.status(), StatusCode::ACCEPTED),
+        Err(e) => scan_error_reply(e),
+    }
+}
+
+/// Scanned points as a file in the format given by its extension.
+fn download_points(id: String, format: String) -> Response {
+    let format: ExportFormat = match format.parse() {
+        Ok(format) => format,
+        Err(e) => return error_reply(StatusCode::NOT_FOUND, e),
+    };
+    let result = scan_id(&id).and_then(|id| Ok((id, SCAN_JOB.export(id, format)?)));
+    match result {
+        Ok((id, body)) => {
+            let disposition = format!("attachment; filename=\"scan_{id}.{}\"", format.extension());
+            let reply = warp::reply::with_header(body, "Content-Type", format.content_type());
+            warp::reply::with_header(reply, "Content-Disposition", disposition).into_response()
+        }
+        Err(e) => scan_error_reply(e),
+    }
+}
+
+/// HTTP settings of the loaded config.
+fn http_config() -> HttpConfig {
+    config::current().http.unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct Forbidden;
+impl warp::reject::Reject for Forbidden {}
+
+/// Websocket subprotocol of the web UI. Browsers can't set headers on websockets, so it
+/// offers a second protocol `lidarino.token.<hex of the token>` carrying the API token.
+const WS_PROTOCOL: &str = "lidarino";
+const WS_TOKEN_PREFIX: &str = "lidarino.token.";
+
+fn decode_hex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Token offered through the websocket subprotocols, see [`WS_PROTOCOL`].
+fn protocol_token(protocols: &str) -> Option<String> {
+    protocols
+        .split(',')
+        .find_map(|protocol| protocol.trim().strip_prefix(WS_TOKEN_PREFIX))
+        .and_then(decode_hex)
+}
+
+/// Lets through clients whose API token has at least the `required` role. The token comes
+/// from a bearer `Authorization` header, or the websocket subprotocols of the web UI.
+fn authorized(required: Role) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and_then(
+            move |header: Option<String>, protocols: Option<String>| async move {
+                let token = header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .map(str::to_owned)
+                    .or_else(|| protocols.as_deref().and_then(protocol_token));
+                match http_config().role(token.as_deref()) {
+                    Some(role) if role >= required => Ok(()),
+                    Some(_) => Err(warp::reject::custom(Forbidden)),
+                    None => Err(warp::reject::custom(Unauthorized)),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Picks [`WS_PROTOCOL`] when the client offered it, browsers drop the connection otherwise.
+fn accept_protocol(reply: impl Reply, protocols: Option<String>) -> Response {
+    let offered = protocols
+        .iter()
+        .flat_map(|protocols| protocols.split(','))
+        .any(|protocol| protocol.trim() == WS_PROTOCOL);
+    if offered {
+        warp::reply::with_header(reply, "sec-websocket-protocol", WS_PROTOCOL).into_response()
+    } else {
+        reply.into_response()
+    }
+}
+
+/// Sends plain HTTP clients over to the HTTPS listener on `tls_port`, same host and path.
+fn redirect_to_tls(tls_port: u16) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::header::optional::<Authority>("host")
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .map(|query: String| format!("?{query}"))
+                .or(warp::any().map(String::new))
+                .unify(),
+        )
+        .map(
+            move |host: Option<Authority>, path: FullPath, query: String| match host {
+                Some(host) => {
+                    let location =
+                        format!("https://{}:{tls_port}{}{query}", host.host(), path.as_str());
+                    match location.parse::<Uri>() {
+                        Ok(location) => warp::redirect::permanent(location).into_response(),
+                        Err(e) => error_reply(StatusCode::BAD_REQUEST, e),
+                    }
+                }
+                None => error_reply(StatusCode::BAD_REQUEST, "missing Host header"),
+            },
+        )
+}
+
+/// Requests from the server's own pages, or from outside a browser. They skip the CORS check,
+/// which would turn them away when they aren't in the allowed origins.
+fn same_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and_then(|origin: Option<String>, host: Option<String>| async move {
+            let same = match (&origin, &host) {
+                (None, _) => true,
+                (Some(origin), Some(host)) => origin.split("://").nth(1) == Some(host.as_str()),
+                (Some(_), None) => false,
+            };
+            if same {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// CORS for the configured origins, `None` if only the server's own web UI may call the API.
+fn cors(http: &HttpConfig) -> Option<warp::cors::Builder> {
     use warp::http::Method;
+    if http.cors_origins.is_empty() {
+        return None;
+    }
     let cors = warp::cors()
-        .allow_any_origin()
         .allow_headers(vec![
             "Access-Control-Allow-Headers",
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
             "Origin",
             "Accept",
-            "Accept",
+            "Authorization",
             "X-Requested-With",
             "Content-Type",
         ])
@@ -158,29 +563,218 @@ async fn start_http() {
             Method::OPTIONS,
             Method::HEAD,
         ]);
+    if http.cors_origins.iter().any(|origin| origin == "*") {
+        Some(cors.allow_any_origin())
+    } else {
+        Some(cors.allow_origins(http.cors_origins.iter().map(String::as_str)))
+    }
+}
+
+/// JSON errors for requests no route took.
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    let reply = if rejection.find::<Unauthorized>().is_some() {
+        let reply = error_reply(StatusCode::UNAUTHORIZED, "missing or unknown API token");
+        warp::reply::with_header(reply, "WWW-Authenticate", "Bearer").into_response()
+    } else if rejection.find::<Forbidden>().is_some() {
+        error_reply(StatusCode::FORBIDDEN, "API token doesn't allow this")
+    } else if rejection.is_not_found() {
+        error_reply(StatusCode::NOT_FOUND, "not found")
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        error_reply(StatusCode::BAD_REQUEST, e)
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        error_reply(StatusCode::BAD_REQUEST, e)
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error_reply(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else {
+        error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("{rejection:?}"))
+    };
+    Ok(reply)
+}
+
+/// Web UI, bundled into the binary.
+const INDEX_HTML: &str = include_str!("../../web/index.html");
+const APP_JS: &str = include_str!("../../web/app.js");
+const STYLE_CSS: &str = include_str!("../../web/style.css");
+
+#[tokio::main(worker_threads = 1)]
+async fn start_http() {
+    let http = http_config();
+    if !http.auth_enabled() {
+        warn!("No API tokens configured, anyone on the network can control the scanner");
+    }
 
     let command = warp::post()
         .and(warp::path!("position"))
+        .and(authorized(Role::Control))
         .and(warp::filters::body::json())
         .map(set_position);
 
     let status = warp::get()
         .and(warp::path!("status"))
+        .and(authorized(Role::ReadOnly))
         .map(send_current_state);
 
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(authorized(Role::ReadOnly))
+        .map(send_metrics);
+
+    let reload_config = warp::post()
+        .and(warp::path!("config" / "reload"))
+        .and(authorized(Role::Control))
+        .map(reload_config);
+
     let measure_distance = warp::post()
         .and(warp::path!("measure_distance"))
-        .map(measure_distance);
+        .and(authorized(Role::Control))
+        .and_then(measure_distance);
 
+    let motion = warp::path("motion").and(
+        (warp::get()
+            .and(warp::path::end())
+            .and(authorized(Role::ReadOnly))
+            .map(motion_status))
+        .or(warp::post()
+            .and(warp::path!("move"))
+            .and(authorized(Role::Control))
+            .and(warp::body::json())
+            .and_then(move_motion))
+        .or(warp::post()
+            .and(warp::path!("jog"))
+            .and(authorized(Role::Control))
+            .and(warp::body::json())
+            .map(jog_motion))
+        .or(warp::post()
+            .and(warp::path!("stop"))
+            .and(authorized(Role::Control))
+            .map(stop_motion))
+        .or(warp::post()
+            .and(warp::path!("zero"))
+            .and(authorized(Role::Control))
+            .and(warp::query())
+            .map(zero_motion))
+        .or(warp::post()
+            .and(warp::path!("home"))
+            .and(authorized(Role::Control))
+            .and(warp::query())
+            .and_then(home_motion))
+        .or(warp::get()
+            .and(warp::path!("settings"))
+            .and(authorized(Role::ReadOnly))
+            .map(get_motion_settings))
+        .or(warp::post()
+            .and(warp::path!("settings"))
+            .and(authorized(Role::Control))
+            .and(warp::body::json())
+            .map(set_motion_settings)),
+    );
+
+    // Subscribed once here, handlers don't touch the controller's lock.
+    let orientation = ORIENTATION_CONTROLLER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .expect("Orientation controller is initialized before the server")
+        .subscribe();
     let orientation_websocket = warp::path("orientation")
+        .and(authorized(Role::ReadOnly))
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(orientation_connected));
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(move |ws: warp::ws::Ws, protocols| {
+            let orientation = orientation.clone();
+            accept_protocol(
+                ws.on_upgrade(move |ws| orientation_connected(ws, orientation)),
+                protocols,
+            )
+        });
 
-    let tree = orientation_websocket
+    let scan_events = warp::path!("scans" / "events")
+        .and(authorized(Role::ReadOnly))
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(|ws: warp::ws::Ws, protocols| {
+            accept_protocol(ws.on_upgrade(scan_events_connected), protocols)
+        });
+
+    let scans = warp::path("scans").and(
+        (warp::get()
+            .and(warp::path::end())
+            .and(authorized(Role::ReadOnly))
+            .and_then(|| blocking(list_scans)))
+        .or(warp::post()
+            .and(warp::path::end())
+            .and(authorized(Role::Control))
+            .and(warp::body::json())
+            .and_then(|cmd| blocking(move || create_scan(cmd))))
+        .or(warp::get()
+            .and(warp::path!("current" / "path"))
+            .and(authorized(Role::ReadOnly))
+            .map(get_path))
+        .or(warp::post()
+            .and(warp::path!("current" / "path"))
+            .and(authorized(Role::Control))
+            .and(warp::body::json())
+            .and_then(|pattern| blocking(move || generate_path(pattern))))
+        .or(warp::post()
+            .and(warp::path!("current" / String))
+            .and(authorized(Role::Control))
+            .and_then(|action| blocking(move || control_scan(action))))
+        .or(warp::get()
+            .and(warp::path!(String))
+            .and(authorized(Role::ReadOnly))
+            .and_then(|id| blocking(move || get_scan(id))))
+        .or(warp::get()
+            .and(warp::path!(String / "points" / String))
+            .and(authorized(Role::ReadOnly))
+            .and_then(|id, format| blocking(move || download_points(id, format)))),
+    );
+
+    let index = warp::get()
+        .and(warp::path::end())
+        .map(|| warp::reply::html(INDEX_HTML));
+
+    let app_js = warp::get()
+        .and(warp::path!("app.js"))
+        .map(|| warp::reply::with_header(APP_JS, "Content-Type", "application/javascript"));
+
+    let style_css = warp::get()
+        .and(warp::path!("style.css"))
+        .map(|| warp::reply::with_header(STYLE_CSS, "Content-Type", "text/css"));
+
+    let tree = index
+        .or(app_js)
+        .or(style_css)
+        .or(orientation_websocket)
+        .or(scan_events)
         .or(command)
         .or(status)
+        .or(metrics)
+        .or(reload_config)
         .or(measure_distance)
-        .with(cors);
+        .or(motion)
+        .or(scans)
+        .recover(handle_rejection);
+    let tree = match cors(&http) {
+        Some(cors) => same_origin()
+            .and(tree.clone())
+            .map(Reply::into_response)
+            .or(tree.with(cors).map(Reply::into_response))
+            .unify()
+            .boxed(),
+        None => tree.map(Reply::into_response).boxed(),
+    };
 
-    warp::serve(tree).run(([0, 0, 0, 0], 8000)).await;
+    match http.tls {
+        Some(tls) => {
+            // Tokens would go over the plain listener in the clear, it only points to HTTPS.
+            let plain = warp::serve(redirect_to_tls(tls.port)).run(([0, 0, 0, 0], http.port));
+            let secure = warp::serve(tree)
+                .tls()
+                .cert_path(tls.cert_path)
+                .key_path(tls.key_path)
+                .run(([0, 0, 0, 0], tls.port));
+            tokio::join!(plain, secure);
+        }
+        None => warp::serve(tree).run(([0, 0, 0, 0], http.port)).await,
+    }
 }
@@ -1,22 +1,24 @@
 use lazy_static::lazy_static;
-use lidarino::config::{Config, CONFIG_PATH};
-use lidarino::hardware::distance::DistanceReading;
+use lidarino::config::{self, Config};
+use lidarino::hardware::accel_calibration::{calibrate_accel, AccelCalibrationOptions};
+use lidarino::hardware::backlash_calibration::{calibrate_backlash, BacklashCalibrationOptions};
+use lidarino::hardware::motor::StepMotorController;
+use lidarino::hardware::imu_recording::ImuReplay;
 use lidarino::hardware::mpu::OrientationController;
 use lidarino::hardware::mpu::*;
 use lidarino::hardware::{
-    DISTANCE_CONTROLLER, MPU_CONTROLLER, ORIENTATION_CONTROLLER, PITCH_CONTROLLER, YAW_CONTROLLER,
+    DISTANCE_CONTROLLER, KINEMATICS, MPU_CONTROLLER, ORIENTATION_CONTROLLER, PITCH_CONTROLLER,
+    YAW_CONTROLLER,
 };
+use lidarino::pattern::*;
+use lidarino::refine::RefinementOptions;
+use lidarino::region::{PitchRange, ScanRegion, YawRange};
+use lidarino::shutdown;
 use lidarino::sphere::*;
-use serde::{Deserialize, Serialize};
-use spinners::{Spinner, Spinners};
-use std::sync::Mutex;
 use std::time::{Duration, Instant};
+use spin_sleep::LoopHelper;
 use lidarino::scan::*;
 
-lazy_static! {
-    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
-}
-
 lazy_static! {
     static ref SCAN_JOB: ScanJob = ScanJob::new();
 }
@@ -44,7 +46,9 @@ fn manual_control() {
                     .unwrap()
                     .get_quat()
                     .euler_angles();
-                println!("current_yaw: {yaw}, current_pitch: {pitch}, roll: {roll_a}, pitch: {pitch_a}, yaw: {yaw_a}");
+                let yaw_deg = KINEMATICS.yaw.to_degrees(yaw);
+                let pitch_deg = KINEMATICS.pitch.to_degrees(pitch);
+                println!("current_yaw: {yaw} ({yaw_deg:.1}°), current_pitch: {pitch} ({pitch_deg:.1}°), roll: {roll_a}, pitch: {pitch_a}, yaw: {yaw_a}");
             }
             ["yaw" | "y", angle] => {
                 let angle: i32 = angle.parse().unwrap();
@@ -69,34 +73,87 @@ fn manual_control() {
                 let measurement = DISTANCE_CONTROLLER.get_measurement();
                 println!("measurement: {measurement:?}");
             }
-            ["gen_path"] => {
-            /*
-             let opts = ScanOptions {
-                    amount_of_points: 1000,
-                    pitch_start: 0.0,
-                    pitch_end: 160.0,
-                    yaw_start: 180.0 - 90.0,
-                    yaw_end: 180.0 + 90.0,
-                }; */
-
-             let opts = ScanOptions {
+            ["gen_path", ref args @ ..] => {
+                let (refinement, pattern) = match args {
+                    ["refine", pattern @ ..] => (Some(RefinementOptions::default()), pattern),
+                    pattern => (None, pattern),
+                };
+                // Yaw axis is reversed, so lower steps are higher angles.
+                let opts = ScanOptions {
                     amount_of_points: 3000,
-                    pitch_start: (2100.0 / 2000.0 * 90.0),
-                    pitch_end: (2900.0 / 2000.0 * 90.0),
-                    yaw_start: 180.0 - (-300.0 / 2000.0 * 90.0),
-                    yaw_end: 180.0 + (2200.0 / 2000.0 * 90.0),
+                    region: ScanRegion {
+                        yaw: YawRange {
+                            from: KINEMATICS.yaw.to_degrees(2200),
+                            to: KINEMATICS.yaw.to_degrees(300),
+                        },
+                        pitch: PitchRange {
+                            from: KINEMATICS.pitch.to_degrees(2100),
+                            to: KINEMATICS.pitch.to_degrees(2900),
+                        },
+                        exclude: Vec::new(),
+                    },
+                    refinement,
+                };
+                let pattern = match pattern {
+                    [] | ["fibonacci"] => Ok(PatternSpec::Fibonacci),
+                    ["grid"] => Ok(PatternSpec::Grid),
+                    ["equal_area"] => Ok(PatternSpec::EqualArea),
+                    ["serpentine"] => Ok(PatternSpec::Serpentine),
+                    ["vertical", yaw] => {
+                        let yaw: f32 = yaw.parse().unwrap();
+                        Ok(PatternSpec::Vertical { yaw })
+                    }
+                    ["horizontal", pitch] => {
+                        let pitch: f32 = pitch.parse().unwrap();
+                        Ok(PatternSpec::Horizontal { pitch })
+                    }
+                    ["list", path] => std::fs::read_to_string(path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|json| Ok(serde_json::from_str::<AngleList>(&json)?))
+                        .map(PatternSpec::List),
+                    _ => {
+                        println!("Unknown pattern, use [refine] followed by one of: fibonacci, grid, equal_area, serpentine, vertical <yaw>, horizontal <pitch>, list <path>");
+                        user_input.clear();
+                        continue;
+                    }
                 };
-                SCAN_JOB.generate_path(opts)
+                let result = pattern.and_then(|pattern| Ok(SCAN_JOB.create_scan(opts, pattern)?));
+                match result {
+                    Ok(scan) => println!("Created scan {}", scan.id),
+                    Err(e) => println!("Error generating a path: {e:?}"),
+                }
+            }
+            ["cancel_planning"] => {
+                SCAN_JOB.cancel_planning()
             }
             ["start_scan"] => {
-                SCAN_JOB.start_scan()
+                if let Err(e) = SCAN_JOB.start_scan() {
+                    println!("Can't start a scan: {e}");
+                }
             }
             ["pause_scan"] => {
-                SCAN_JOB.pause_scan()
+                if let Err(e) = SCAN_JOB.pause_scan() {
+                    println!("Can't pause a scan: {e}");
+                }
+            }
+            ["cancel_scan"] => {
+                if let Err(e) = SCAN_JOB.cancel_scan() {
+                    println!("Can't cancel a scan: {e}");
+                }
             }
             ["save_scan"] => {
-                SCAN_JOB.save_file()
+                if let Err(e) = SCAN_JOB.save_file() {
+                    println!("Can't save a scan: {e}");
+                }
             }
+            ["reload"] => match config::reload() {
+                Ok(_) => {
+                    println!("Reloaded config.");
+                }
+                Err(e) => {
+                    println!("Can't reload config: {e:#}");
+                }
+            },
             ["reset" | "r"] => {
                 println!("Yaw and Pitch set as 0.");
                 YAW_CONTROLLER.reset();
@@ -108,14 +165,9 @@ fn manual_control() {
                 let gyro_bias =
                     lidarino::hardware::mpu::calculate_gyro_bias(&mut mpu, &Duration::from_secs(3));
                 drop(mpu);
-                let mut config = CONFIG.lock().unwrap();
-                match &mut config.mpu_config {
-                    None => {
-                        panic!("wtf is this config");
-                    }
-                    Some(mpu_config) => mpu_config.gyro_bias = gyro_bias,
-                }
-                match config.save_to_file(CONFIG_PATH) {
+                let mut config = config::current();
+                config.imu_calibration.get_or_insert_with(Default::default).gyro_bias = gyro_bias;
+                match config.save_section(config::path(), "imu_calibration") {
                     Ok(_) => {
                         println!("Saved config to file.");
                     }
@@ -123,24 +175,84 @@ fn manual_control() {
                         println!("Error writing a config: {e:?}");
                     }
                 }
+                config::set_current(config);
             }
             ["calibrate", "accel"] | ["ca"] => {
                 println!("Accelerometer calibration started.");
+                println!("Hold MPU still with every axis pointing up and down, 6 orientations total.");
                 let mut mpu = MPU_CONTROLLER.lock().unwrap();
-                let (accel_bias, accel_scale) =
-                    lidarino::hardware::mpu::calculate_accel_bias_and_scale(&mut mpu);
+                let calibration = calibrate_accel(
+                    &mut mpu,
+                    AccelCalibrationOptions::default(),
+                    Duration::from_secs(300),
+                    |event| println!("{event}"),
+                );
                 drop(mpu);
-                let mut config = CONFIG.lock().unwrap();
-                match &mut config.mpu_config {
-                    None => {
-                        panic!("wtf is this config");
+                let calibration = match calibration {
+                    Ok(calibration) => calibration,
+                    Err(e) => {
+                        println!("Accelerometer calibration failed: {e}");
+                        user_input.clear();
+                        continue;
+                    }
+                };
+                println!(
+                    "bias: {:?}, scale: {:?}, residual: {} m/s²",
+                    calibration.bias, calibration.scale, calibration.residual_rms
+                );
+                let mut config = config::current();
+                calibration.apply_to(config.imu_calibration.get_or_insert_with(Default::default));
+                match config.save_section(config::path(), "imu_calibration") {
+                    Ok(_) => {
+                        println!("Saved config to file.");
                     }
-                    Some(mpu_config) => {
-                        mpu_config.accel_bias = accel_bias;
-                        mpu_config.accel_scale = accel_scale;
+                    Err(e) => {
+                        println!("Error writing a config: {e:?}");
                     }
                 }
-                match config.save_to_file(CONFIG_PATH) {
+                config::set_current(config);
+            }
+            ["calibrate", "backlash", axis @ ("yaw" | "pitch")] => {
+                let motor: &StepMotorController = match axis {
+                    "yaw" => &YAW_CONTROLLER,
+                    _ => &PITCH_CONTROLLER,
+                };
+                println!("Backlash calibration of {axis} axis started.");
+                println!("Laser should point at a flat target at an angle, sweeping forward from here.");
+                let estimate = calibrate_backlash(
+                    motor,
+                    &DISTANCE_CONTROLLER,
+                    BacklashCalibrationOptions::default(),
+                    |sample| println!("{} {}mm", sample.pos, sample.distance_mm),
+                );
+                let estimate = match estimate {
+                    Ok(estimate) => estimate,
+                    Err(e) => {
+                        println!("Backlash calibration failed: {e}");
+                        user_input.clear();
+                        continue;
+                    }
+                };
+                println!(
+                    "backlash: {} steps, residual: {}mm",
+                    estimate.steps, estimate.residual_rms_mm
+                );
+                let mut motor_config = motor.get_config();
+                motor_config.backlash_steps = estimate.steps;
+                motor.set_config(motor_config);
+
+                let mut config = config::current();
+                let section = match axis {
+                    "yaw" => {
+                        config.yaw_motor = Some(motor_config);
+                        "yaw_motor"
+                    }
+                    _ => {
+                        config.pitch_motor = Some(motor_config);
+                        "pitch_motor"
+                    }
+                };
+                match config.save_section(config::path(), section) {
                     Ok(_) => {
                         println!("Saved config to file.");
                     }
@@ -148,6 +260,7 @@ fn manual_control() {
                         println!("Error writing a config: {e:?}");
                     }
                 }
+                config::set_current(config);
             }
             ["magdump"] => {
                 let mut mpu = MPU_CONTROLLER.lock().unwrap();
@@ -164,8 +277,7 @@ fn manual_control() {
             ["init_orientation"] => {
                 let mut orientation_controller = ORIENTATION_CONTROLLER.lock().unwrap();
                 if orientation_controller.is_none() {
-                    let mpu_config = CONFIG.lock().unwrap().mpu_config.unwrap();
-                    let mpu = Mpu::new(mpu_config);
+                    let mpu = Mpu::from_config(&config::current());
                     let new_c = OrientationController::new(mpu);
                     *orientation_controller = Some(new_c);
                     println!("Done initialization, pls dont access MPU using other means. FIXME");
@@ -173,6 +285,58 @@ fn manual_control() {
                     println!("Error, orientation controller allready initialized");
                 }
             }
+            ["record_imu", path, seconds] => {
+                let seconds: u64 = match seconds.parse() {
+                    Ok(seconds) => seconds,
+                    Err(e) => {
+                        println!("Recording time should be whole seconds, got \"{seconds}\": {e}");
+                        user_input.clear();
+                        continue;
+                    }
+                };
+                println!("Recording IMU into \"{path}\" for {seconds} seconds.");
+                let mut mpu = MPU_CONTROLLER.lock().unwrap();
+                // Sampled at the rate of the orientation loop, so replays see what it would.
+                let mut loop_helper =
+                    LoopHelper::builder().build_with_target_rate(mpu.imu.rate_hz as f32);
+                let result = mpu.start_recording(path).and_then(|_| {
+                    let start_time = Instant::now();
+                    while start_time.elapsed() < Duration::from_secs(seconds) {
+                        loop_helper.loop_start();
+                        mpu.read_sample()?;
+                        loop_helper.loop_sleep();
+                    }
+                    mpu.stop_recording()
+                });
+                match result {
+                    Ok(_) => println!("Done recording."),
+                    Err(e) => println!("Error recording IMU: {e:?}"),
+                }
+            }
+            ["init_orientation", "replay", path, speed] => {
+                let mut orientation_controller = ORIENTATION_CONTROLLER.lock().unwrap();
+                if orientation_controller.is_some() {
+                    println!("Error, orientation controller allready initialized");
+                } else {
+                    let speed: f32 = match speed.parse() {
+                        Ok(speed) if speed > 0.0 && f32::is_finite(speed) => speed,
+                        _ => {
+                            println!("Replay speed should be a positive number, got \"{speed}\"");
+                            user_input.clear();
+                            continue;
+                        }
+                    };
+                    match ImuReplay::from_file(path, speed) {
+                        Ok(replay) => {
+                            let mpu_config = config::current().imu_calibration.unwrap_or_default();
+                            let mpu = Mpu::replay(mpu_config, replay);
+                            *orientation_controller = Some(OrientationController::new(mpu));
+                            println!("Replaying \"{path}\" at {speed}x.");
+                        }
+                        Err(e) => println!("Error loading IMU recording: {e:?}"),
+                    }
+                }
+            }
             _ => {
                 println!("{split:?}, {user_input}")
             }
@@ -181,30 +345,20 @@ fn manual_control() {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct ScannedCheckpoint {
-    x: f32,
-    y: f32,
-    z: f32,
-    waypoint_yaw: i32,
-    waypoint_pitch: i32,
-    current_yaw: i32,
-    current_pitch: i32,
-    roll: f32,
-    pitch: f32,
-    yaw: f32,
-    distance: u32,
-    quality: u32,
-}
-
 fn main() {
     println!("WELCOME TO LIDARINO");
-    if CONFIG.lock().unwrap().load_from_file(CONFIG_PATH).is_ok() {
-        println!("Succesfully loaded config from \"{CONFIG_PATH}\"");
-    } else {
-        println!("Failed loading config from \"{CONFIG_PATH}\"");
-    };
+    shutdown::install().expect("Failed to install shutdown handlers");
+    let config = Config::from_args().unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    });
+    println!("Loaded config from \"{}\"", config::path().display());
+    lidarino::logging::init(&config.logging.clone().unwrap_or_default())
+        .expect("Failed to set up logging");
+    config::set_current(config);
+    config::reload_on_sighup().expect("Failed to install config reload handler");
 
     //lazy_static::initialize(&ORIENTATION_CONTROLLER);
     manual_control();
+    shutdown::shutdown(0);
 }
@@ -1,21 +1,31 @@
+use lidarino::kinematics::HeadKinematics;
+use lidarino::pattern::{Fibonacci, ScanPattern};
+use lidarino::region::{PitchRange, ScanRegion, YawRange};
 use lidarino::sphere::*;
 use std::time::Duration;
 
 fn main() {
     let opts = ScanOptions {
         amount_of_points: 1000,
-        pitch_start: 0.0,
-        pitch_end: 120.0,
-        yaw_start: 180.0 - 90.0,
-        yaw_end: 180.0 + 90.0,
+        region: ScanRegion {
+            yaw: YawRange {
+                from: -90.0,
+                to: 90.0,
+            },
+            pitch: PitchRange {
+                from: 0.0,
+                to: 120.0,
+            },
+            exclude: Vec::new(),
+        },
+        refinement: None,
     };
-    let points = lidarino::sphere::generate_points(opts);
-    for p in &points {
-        let (pitch, yaw) = p.as_pitch_yaw();
-        let p = Point::from_yaw_pitch_distance(yaw, pitch, 100);
+    let kinematics = HeadKinematics::default();
+    let waypoints = Fibonacci.waypoints(&opts, &kinematics);
+    for waypoint in &waypoints {
+        let p = kinematics.point(*waypoint, 100);
         println!("{} {} {}", p.x, p.y, p.z);
     }
-    let waypoints: Vec<Waypoint> = points.into_iter().map(|p| p.into()).collect();
     let waypoints = optimize_path(waypoints, Duration::from_secs(1));
     for _waypoint in waypoints {
         //println!("{} {}", _waypoint.yaw, _waypoint.pitch);
@@ -1,26 +1,45 @@
+use lidarino::config::Config;
 use lidarino::hardware::mcp23s17::*;
 use rppal::gpio::Level;
 use std::io;
 use std::io::Write;
 
 fn main() {
-    let mcp23s17_controller = Mcp23s17Controller::new();
+    let config = Config::from_args()
+        .unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        })
+        .mcp23s17
+        .unwrap_or_default();
+    let mcp23s17_controller = Mcp23s17Controller::from_config(&config).unwrap();
     let c = mcp23s17_controller;
     let stdin = io::stdin();
     let mut input_buf = String::with_capacity(100);
 
-    println!("USAGE: [pin_number][t|f], for e.x.: '0f' or '7t' '5t'");
+    println!("USAGE: [chip:][pin_number][t|f], for e.x.: '0f' or '15t' '1:5t'");
     println!("Crashes on incorrect input :-)");
     loop {
         print!("[MANUAL MCP23S13 PIN CONTROL]-> ");
 
         io::stdout().flush().unwrap();
+        input_buf.clear();
         stdin.read_line(&mut input_buf).unwrap();
 
         let cmd = input_buf.trim();
+        let (chip, cmd) = match cmd.split_once(':') {
+            Some((chip, cmd)) => (chip.parse().unwrap(), cmd),
+            None => (config.chips[0].address, cmd),
+        };
 
-        let pin_num: u8 = cmd[0..1].parse().unwrap();
-        let pin_value: Level = (&cmd[1..2] == "t").into();
-        c.output_pin(pin_num).write(pin_value);
+        let (pin_num, pin_value) = cmd.split_at(cmd.len() - 1);
+        let pin_num: u8 = pin_num.parse().unwrap();
+        let pin_value: Level = (pin_value == "t").into();
+        if let Err(e) = c
+            .output_pin_at(PinAddress::new(chip, pin_num))
+            .write(pin_value)
+        {
+            println!("{e}, health: {:?}", c.health());
+        }
     }
 }
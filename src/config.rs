@@ -1,38 +1,502 @@
-use crate::hardware::mpu::MpuConfig;
-use anyhow::Result;
+//! Settings of every subsystem, one optional section each, defaults for missing ones.
+//!
+//! Binaries take the config file from `--config <path>`, see [`Config::from_args`],
+//! [`CONFIG_PATH`] otherwise.
+//!
+//! Files of older versions are migrated when loaded. Calibration results are written back
+//! with [`Config::save_section`], which keeps comments and formatting of the file, and
+//! tuning values are applied to running hardware by [`reload`].
+
+use crate::hardware::distance::DistanceSensorConfig;
+use crate::hardware::mcp23s17::{Mcp23s17Config, PinAddress};
+use crate::hardware::motor::MotorConfig;
+use crate::hardware::mpu::{ImuConfig, MpuConfig};
+use crate::hardware::mpu_mock::MockImuConfig;
+use crate::hardware::stall_detection::StallDetectionConfig;
+use crate::hardware::{PITCH_PINS, YAW_PINS};
+use crate::kinematics::HeadKinematics;
+use crate::logging::LoggingConfig;
+use crate::planner::PlannerOptions;
+use crate::server::HttpConfig;
+use anyhow::{bail, ensure, format_err, Context, Result};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use toml_edit::{Document, Item, Table};
+use tracing::{error, info};
 
 pub const CONFIG_PATH: &str = "lidarino_config.toml";
+/// Schema version of config files written now. Files without a version are version 1.
+pub const CONFIG_VERSION: u32 = 2;
+
+type Migration = fn(&mut Document) -> Result<()>;
+
+/// Upgrades of a config file to the next version, the first one turns version 1 into 2.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize - 1] = [rename_mpu_config];
+
+lazy_static! {
+    static ref PATH: Mutex<PathBuf> = Mutex::new(PathBuf::from(CONFIG_PATH));
+    static ref CURRENT: Mutex<Option<Config>> = Mutex::new(None);
+}
+
+/// Config the process runs with, hardware is set up from it. Binaries load it at startup
+/// and [`set_current`] it, otherwise it's [`Config::load`]ed on first use.
+///
+/// # Panics
+/// If it's loaded here and the file is invalid, so hardware is never set up from defaults
+/// in place of a broken file.
+pub fn current() -> Config {
+    if let Some(config) = &*CURRENT.lock().unwrap() {
+        return config.clone();
+    }
+    let config = Config::load().unwrap_or_else(|e| panic!("{:#}", e));
+    CURRENT.lock().unwrap().get_or_insert(config).clone()
+}
+
+pub fn set_current(config: Config) {
+    *CURRENT.lock().unwrap() = Some(config);
+}
+
+/// Config file hardware and scans are set up from.
+pub fn path() -> PathBuf {
+    PATH.lock().unwrap().clone()
+}
+
+pub fn set_path<P: Into<PathBuf>>(path: P) {
+    *PATH.lock().unwrap() = path.into();
+}
+
+/// Use the config file given as `--config <path>` or `--config=<path>` in `args`,
+/// returning the other arguments.
+pub fn path_from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Vec<String>> {
+    let mut args = args.into_iter();
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let path = args
+                .next()
+                .ok_or_else(|| format_err!("--config needs a path"))?;
+            set_path(path);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            set_path(path);
+        } else {
+            rest.push(arg);
+        }
+    }
+    Ok(rest)
+}
+
+fn current_version() -> u32 {
+    CONFIG_VERSION
+}
+
+/// Version 2: IMU calibration moved from `mpu_config` to `imu_calibration`.
+fn rename_mpu_config(doc: &mut Document) -> Result<()> {
+    let root = doc.as_table_mut();
+    if let Some(calibration) = root.remove("mpu_config") {
+        ensure!(
+            !root.contains_key("imu_calibration"),
+            "both mpu_config and imu_calibration are set"
+        );
+        root.insert("imu_calibration", calibration);
+    }
+    Ok(())
+}
+
+/// Bring `doc` to [`CONFIG_VERSION`], returning the version it had.
+fn migrate(doc: &mut Document) -> Result<u32> {
+    let version = match doc.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|&version| version > 0)
+            .ok_or_else(|| format_err!("version must be a positive integer"))?,
+    };
+    ensure!(
+        version <= CONFIG_VERSION,
+        "config version {version} is newer than the supported {CONFIG_VERSION}"
+    );
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(doc)?;
+    }
+    let current = toml_edit::value(i64::from(CONFIG_VERSION));
+    set_key(doc.as_table_mut(), "version", Some(current));
+    Ok(version)
+}
+
+/// Set `key` of `table` to `new` with [`merge`], or remove it if `new` is `None`.
+fn set_key(table: &mut Table, key: &str, new: Option<Item>) {
+    match (table.get_mut(key), new) {
+        (Some(old), Some(new)) => merge(old, new),
+        (None, Some(new)) => {
+            table.insert(key, new);
+        }
+        (_, None) => {
+            table.remove(key);
+        }
+    }
+}
+
+/// Replace `old` with `new`, keeping comments and formatting of keys and tables in both.
+/// Keys `new` doesn't have are removed.
+fn merge(old: &mut Item, new: Item) {
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => {
+            let removed: Vec<String> = old
+                .iter()
+                .map(|(key, _)| key.to_string())
+                .filter(|key| !new.contains_key(key))
+                .collect();
+            for key in removed {
+                old.remove(&key);
+            }
+            for (key, new) in new {
+                match old.get_mut(&key) {
+                    Some(old) => merge(old, new),
+                    None => {
+                        old.insert(&key, new);
+                    }
+                }
+            }
+        }
+        (Item::Value(old), Item::Value(mut new)) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+        (old, new) => *old = new,
+    }
+}
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+fn read_document(path: &Path) -> Result<Document> {
+    let string = std::fs::read_to_string(path)?;
+    Ok(string.parse()?)
+}
+
+/// Load the config file again, make it [`current`] and apply its tuning values to running
+/// hardware, see [`apply_tuning`](crate::hardware::apply_tuning). Nothing is changed if
+/// it's invalid.
+pub fn reload() -> Result<Config> {
+    let config = Config::load()?;
+    crate::hardware::apply_tuning(&config);
+    set_current(config.clone());
+    info!(path = %path().display(), "Reloaded config");
+    Ok(config)
+}
+
+/// [`reload`] on every SIGHUP.
+pub fn reload_on_sighup() -> Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if let Err(e) = reload() {
+                error!(error = format!("{e:#}"), "Failed to reload config");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
-    pub mpu_config: Option<MpuConfig>,
+    /// Schema version, see [`CONFIG_VERSION`].
+    #[serde(default = "current_version")]
+    pub version: u32,
+    /// Gyroscope and accelerometer calibration, none applied if missing.
+    pub imu_calibration: Option<MpuConfig>,
+    /// MPU9250 bus and orientation filter, `/dev/i2c-1` at 500 Hz if missing.
+    pub imu: Option<ImuConfig>,
+    /// Simulated IMU settings, used with `mock_hardware` feature.
+    pub mock_imu: Option<MockImuConfig>,
+    /// Pin expander chips, single chip on SPI0 CS0 if missing.
+    pub mcp23s17: Option<Mcp23s17Config>,
+    /// Yaw axis pins, speed, hold policy and thermal budget, defaults if missing.
+    pub yaw_motor: Option<MotorConfig>,
+    /// Pitch axis pins, speed, hold policy and thermal budget, defaults if missing.
+    pub pitch_motor: Option<MotorConfig>,
+    /// HI50 serial port and reading mode, `/dev/ttyS0` if missing.
+    pub distance_sensor: Option<DistanceSensorConfig>,
+    /// Missed step detection during scans, defaults if missing.
+    pub stall_detection: Option<StallDetectionConfig>,
+    /// Steps to angle conversion of both axes, defaults if missing.
+    pub kinematics: Option<HeadKinematics>,
+    /// Path planning budget, defaults if missing.
+    pub path_planner: Option<PlannerOptions>,
+    /// HTTP server listeners and access, open plain HTTP on port 8000 if missing.
+    pub http: Option<HttpConfig>,
+    /// Log level, format and files, info and up on stderr if missing.
+    pub logging: Option<LoggingConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            mpu_config: Some(MpuConfig::default()),
+            version: CONFIG_VERSION,
+            imu_calibration: None,
+            imu: None,
+            mock_imu: None,
+            mcp23s17: None,
+            yaw_motor: None,
+            pitch_motor: None,
+            distance_sensor: None,
+            stall_detection: None,
+            kinematics: None,
+            path_planner: None,
+            http: None,
+            logging: None,
         }
     }
 }
 
 impl Config {
-    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        let string = std::fs::read_to_string(path)?;
-        *self = toml::from_str(&string)?;
+    /// Load the config file given with `--config`, for binaries taking no other arguments.
+    pub fn from_args() -> Result<Self> {
+        let rest = path_from_args(std::env::args().skip(1))?;
+        if let Some(arg) = rest.first() {
+            bail!("unexpected argument \"{arg}\", usage: [--config <path>]");
+        }
+        Self::load()
+    }
 
-        if self.mpu_config.is_none() {
-            self.mpu_config = Some(MpuConfig::default());
+    /// Load and validate the config file at [`path`], defaults if there's no file.
+    pub fn load() -> Result<Self> {
+        let path = path();
+        let mut config = Config::default();
+        if path.exists() {
+            config
+                .load_from_file(&path)
+                .with_context(|| format!("invalid config \"{}\"", path.display()))?;
         }
+        Ok(config)
+    }
 
+    /// Load and validate config from `path`, migrating it from older versions.
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut doc = read_document(path)?;
+        let version = migrate(&mut doc)?;
+        let config: Config = toml::from_str(&doc.to_string())?;
+        config.validate()?;
+        *self = config;
+        if version != CONFIG_VERSION {
+            info!(
+                path = %path.display(),
+                from = version,
+                to = CONFIG_VERSION,
+                "Migrated config, file is updated on the next save"
+            );
+        }
         Ok(())
     }
 
+    /// Write the whole config to `path`, comments of an existing file are lost.
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let string = toml::to_string_pretty(self)?;
         std::fs::write(path, string)?;
         Ok(())
     }
+
+    /// Write `section` of this config to the file at `path`, keeping everything else in the
+    /// file as it is, comments included. The file is migrated to [`CONFIG_VERSION`] and
+    /// created if it doesn't exist.
+    pub fn save_section<P: AsRef<Path>>(&self, path: P, section: &str) -> Result<()> {
+        let path = path.as_ref();
+        let mut doc = if path.exists() {
+            read_document(path)?
+        } else {
+            Document::new()
+        };
+        migrate(&mut doc)?;
+        let mut new: Document = toml::to_string(self)?.parse()?;
+        set_key(doc.as_table_mut(), section, new.remove(section));
+        let config: Config = toml::from_str(&doc.to_string())?;
+        config.validate()?;
+        std::fs::write(path, doc.to_string())?;
+        Ok(())
+    }
+
+    /// Check every section for out of range values and conflicting pins.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.version <= CONFIG_VERSION,
+            "config version {} is newer than the supported {CONFIG_VERSION}",
+            self.version
+        );
+        if let Some(imu) = &self.imu {
+            imu.validate().context("imu")?;
+        }
+        let mcp23s17 = self.mcp23s17.clone().unwrap_or_default();
+        mcp23s17.validate().context("mcp23s17")?;
+        let motors = [
+            ("yaw_motor", &self.yaw_motor, self.yaw_pins()),
+            ("pitch_motor", &self.pitch_motor, self.pitch_pins()),
+        ];
+        let mut used: Vec<(PinAddress, &str)> = Vec::new();
+        for (name, motor, pins) in motors {
+            if let Some(motor) = motor {
+                motor.validate().context(name)?;
+            }
+            for pin in pins {
+                if mcp23s17.is_input(pin) != Some(false) {
+                    bail!(
+                        "{name}: pin {} of chip {} isn't an output of a configured chip",
+                        pin.pin,
+                        pin.chip
+                    );
+                }
+                if let Some((_, other)) = used.iter().find(|(used, _)| *used == pin) {
+                    bail!(
+                        "{name}: pin {} of chip {} is used by {other} too",
+                        pin.pin,
+                        pin.chip
+                    );
+                }
+                used.push((pin, name));
+            }
+        }
+        if let Some(distance_sensor) = &self.distance_sensor {
+            distance_sensor.validate().context("distance_sensor")?;
+        }
+        if let Some(stall_detection) = &self.stall_detection {
+            stall_detection.validate().context("stall_detection")?;
+        }
+        if let Some(kinematics) = &self.kinematics {
+            kinematics.validate().context("kinematics")?;
+        }
+        if let Some(path_planner) = &self.path_planner {
+            path_planner.validate().context("path_planner")?;
+        }
+        if let Some(http) = &self.http {
+            http.validate().context("http")?;
+        }
+        Ok(())
+    }
+
+    pub fn yaw_pins(&self) -> [PinAddress; 4] {
+        self.motor_pins(&self.yaw_motor, YAW_PINS)
+    }
+
+    pub fn pitch_pins(&self) -> [PinAddress; 4] {
+        self.motor_pins(&self.pitch_motor, PITCH_PINS)
+    }
+
+    /// Pins of `motor`, `default_pins` on the first MCP23S17 chip if it has none.
+    fn motor_pins(&self, motor: &Option<MotorConfig>, default_pins: [u8; 4]) -> [PinAddress; 4] {
+        if let Some(pins) = motor.and_then(|motor| motor.pins) {
+            return pins;
+        }
+        let chip = self
+            .mcp23s17
+            .as_ref()
+            .and_then(|mcp23s17| mcp23s17.chips.first())
+            .map_or(0, |chip| chip.address);
+        default_pins.map(|pin| PinAddress::new(chip, pin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `text` to a file of its own, for the test to load and save.
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lidarino_{name}_{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    const V1: &str = "\
+# Scanner head on the desk
+[yaw_motor]
+step_delay_ms = 9 # slow, belt slips
+
+# calibrated 2024-01-01
+[mpu_config]
+gyro_bias = [0.5, 0.25, -1.0]
+accel_bias = [0.0, 0.0, 0.0]
+accel_scale = [1.0, 1.0, 1.0]
+";
+
+    #[test]
+    fn migrates_v1() {
+        let mut doc: Document = V1.parse().unwrap();
+        assert_eq!(migrate(&mut doc).unwrap(), 1);
+        assert_eq!(doc["version"].as_integer(), Some(i64::from(CONFIG_VERSION)));
+        assert!(!doc.contains_key("mpu_config"));
+        assert_eq!(
+            doc["imu_calibration"]["gyro_bias"].to_string(),
+            " [0.5, 0.25, -1.0]"
+        );
+        assert_eq!(doc["yaw_motor"]["step_delay_ms"].as_integer(), Some(9));
+
+        let path = temp_file("migrates_v1", V1);
+        let mut config = Config::default();
+        let loaded = config.load_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.imu_calibration.unwrap().gyro_bias, [0.5, 0.25, -1.0]);
+
+        // Saving any section writes the migrated file, comments of the renamed one included.
+        let path = temp_file("migrates_v1_on_save", V1);
+        let saved = Config::default()
+            .save_section(&path, "stall_detection")
+            .and_then(|()| Ok(std::fs::read_to_string(&path)?));
+        std::fs::remove_file(&path).unwrap();
+        let saved = saved.unwrap();
+        assert!(saved.starts_with(&format!("version = {CONFIG_VERSION}\n")));
+        assert!(saved.contains("step_delay_ms = 9 # slow, belt slips\n"));
+        assert!(saved.contains(
+            "# calibrated 2024-01-01\n[imu_calibration]\ngyro_bias = [0.5, 0.25, -1.0]\n"
+        ));
+        assert!(!saved.contains("mpu_config"));
+    }
+
+    #[test]
+    fn rejects_bad_versions() {
+        let newer = format!("version = {}", CONFIG_VERSION + 1);
+        let both = "[mpu_config]\n[imu_calibration]\n";
+        for text in ["version = 0", "version = \"2\"", &newer, both] {
+            let mut doc: Document = text.parse().unwrap();
+            assert!(migrate(&mut doc).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn save_section_keeps_the_rest() {
+        let text = "\
+# Scanner head on the desk
+version = 2
+
+[yaw_motor]
+step_delay_ms = 9 # slow, belt slips
+backlash_steps = 3
+
+# calibrated 2024-01-01
+[imu_calibration]
+gyro_bias = [0.5, 0.25, -1.0] # at 20 °C
+accel_bias = [0.0, 0.0, 0.0]
+accel_scale = [1.0, 1.0, 1.0]
+accel_misalignment = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+
+[distance_sensor]
+mode = \"fast\" # good enough indoors
+";
+        let path = temp_file("save_section", text);
+        let mut config = Config::default();
+        config.load_from_file(&path).unwrap();
+        config.imu_calibration.as_mut().unwrap().gyro_bias[0] = 0.0;
+        config.save_section(&path, "imu_calibration").unwrap();
+        let saved = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            saved.unwrap(),
+            text.replace("[0.5, 0.25, -1.0]", "[0.0, 0.25, -1.0]")
+        );
+    }
 }
@@ -0,0 +1,116 @@
+//! Scanned points in formats other tools can read.
+
+use crate::scan::ScannedCheckpoint;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// Every checkpoint field, same as the scan file.
+    Json,
+    /// Every checkpoint field, one checkpoint per row.
+    Csv,
+    /// ASCII point cloud with distance, quality and pass of every point.
+    Ply,
+    /// Bare `x y z` lines.
+    Xyz,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 4] = [
+        ExportFormat::Json,
+        ExportFormat::Csv,
+        ExportFormat::Ply,
+        ExportFormat::Xyz,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ply => "ply",
+            ExportFormat::Xyz => "xyz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ply | ExportFormat::Xyz => "text/plain",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    /// Format by its extension.
+    fn from_str(s: &str) -> Result<Self> {
+        ExportFormat::ALL
+            .iter()
+            .copied()
+            .find(|format| format.extension() == s)
+            .ok_or_else(|| anyhow::format_err!("unknown export format \"{s}\""))
+    }
+}
+
+/// `points` written in `format`, coordinates in meters.
+pub fn export(points: &[ScannedCheckpoint], format: ExportFormat) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        ExportFormat::Json => out = serde_json::to_string(points)?,
+        ExportFormat::Csv => {
+            writeln!(
+                out,
+                "x,y,z,waypoint_yaw,waypoint_pitch,current_yaw,current_pitch,roll,pitch,yaw,distance,quality,misregistered,pass"
+            )?;
+            for p in points {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                    p.x,
+                    p.y,
+                    p.z,
+                    p.waypoint_yaw,
+                    p.waypoint_pitch,
+                    p.current_yaw,
+                    p.current_pitch,
+                    p.roll,
+                    p.pitch,
+                    p.yaw,
+                    p.distance,
+                    p.quality,
+                    p.misregistered,
+                    p.pass
+                )?;
+            }
+        }
+        ExportFormat::Ply => {
+            writeln!(out, "ply\nformat ascii 1.0")?;
+            writeln!(out, "element vertex {}", points.len())?;
+            writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+            writeln!(
+                out,
+                "property uint distance\nproperty uint quality\nproperty uint pass"
+            )?;
+            writeln!(out, "end_header")?;
+            for p in points {
+                writeln!(
+                    out,
+                    "{} {} {} {} {} {}",
+                    p.x, p.y, p.z, p.distance, p.quality, p.pass
+                )?;
+            }
+        }
+        ExportFormat::Xyz => {
+            for p in points {
+                writeln!(out, "{} {} {}", p.x, p.y, p.z)?;
+            }
+        }
+    }
+    Ok(out)
+}
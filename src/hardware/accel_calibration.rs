@@ -0,0 +1,562 @@
+//! Six-position accelerometer calibration.
+//!
+//! [`AccelCalibrator`] is a state machine fed with raw accelerometer samples. It detects
+//! when the board is held still in a new orientation (one axis pointing up or down),
+//! averages samples in every of the six orientations and then solves for bias, scale
+//! and cross-axis misalignment.
+//!
+//! # Example
+//! ```ignore
+//! let mut calibrator = AccelCalibrator::new(AccelCalibrationOptions::default());
+//! while !calibrator.is_complete() {
+//!     let accel = mpu.get_raw_accel()?;
+//!     if let Some(event) = calibrator.feed(accel) {
+//!         println!("{event}");
+//!     }
+//! }
+//! let calibration = calibrator.finish()?;
+//! calibration.apply_to(&mut mpu_config);
+//! ```
+
+use super::mpu::{Mpu, MpuConfig};
+use nalgebra::{Matrix3, Matrix4, Vector3, Vector4};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// One of six orientations, named by the axis pointing up (reading `+g`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Face {
+    const ALL: [Face; 6] = [
+        Face::PosX,
+        Face::NegX,
+        Face::PosY,
+        Face::NegY,
+        Face::PosZ,
+        Face::NegZ,
+    ];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn axis(&self) -> usize {
+        self.index() / 2
+    }
+
+    /// Expected accelerometer reading in this orientation.
+    fn gravity(&self) -> Vector3<f32> {
+        let sign = match self {
+            Face::PosX | Face::PosY | Face::PosZ => 1.0,
+            Face::NegX | Face::NegY | Face::NegZ => -1.0,
+        };
+        let mut gravity = Vector3::zeros();
+        gravity[self.axis()] = sign * mpu9250::G;
+        gravity
+    }
+
+    /// Find orientation closest to `accel` reading and a tilt (in degrees) from it.
+    fn closest(accel: &Vector3<f32>) -> (Face, f32) {
+        let axis = accel.iamax();
+        let face = Face::ALL[axis * 2 + usize::from(accel[axis] < 0.0)];
+        let cos = (accel[axis].abs() / accel.norm()).clamp(-1.0, 1.0);
+        (face, cos.acos().to_degrees())
+    }
+}
+
+impl fmt::Display for Face {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Face::PosX => "+X",
+            Face::NegX => "-X",
+            Face::PosY => "+Y",
+            Face::NegY => "-Y",
+            Face::PosZ => "+Z",
+            Face::NegZ => "-Z",
+        };
+        write!(f, "{name} up")
+    }
+}
+
+/// Tuning values for [`AccelCalibrator`]. Accelerations are in m/s².
+#[derive(Clone, Copy, Debug)]
+pub struct AccelCalibrationOptions {
+    /// Amount of samples used to decide if the board is held still.
+    pub still_window: usize,
+    /// Maximum standard deviation on each axis for the board to count as still.
+    pub still_threshold: f32,
+    /// Amount of samples averaged in every orientation.
+    pub samples_per_position: usize,
+    /// Maximum tilt (in degrees) from an axis-aligned orientation.
+    pub max_tilt_deg: f32,
+    /// Maximum RMS error of a calibrated reading against `g`.
+    pub max_residual: f32,
+    /// Allowed range for a per-axis scale.
+    pub scale_range: (f32, f32),
+    /// Maximum absolute bias on every axis.
+    pub max_bias: f32,
+}
+
+impl Default for AccelCalibrationOptions {
+    fn default() -> Self {
+        AccelCalibrationOptions {
+            still_window: 100,
+            still_threshold: 0.08,
+            samples_per_position: 500,
+            max_tilt_deg: 15.0,
+            max_residual: 0.3,
+            scale_range: (0.9, 1.1),
+            max_bias: 1.5,
+        }
+    }
+}
+
+/// Current state of [`AccelCalibrator`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationState {
+    /// Waiting for the board to be held still in an orientation which isn't captured yet.
+    WaitingForStill,
+    /// Board is still in a new orientation, averaging samples.
+    Collecting { face: Face, collected: usize },
+    /// All six orientations are captured.
+    Complete,
+}
+
+/// Notable transitions reported by [`AccelCalibrator::feed`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationEvent {
+    /// Board is still, but too far from any axis-aligned orientation.
+    Misaligned { tilt_deg: f32 },
+    /// Board is still in an orientation which was already captured.
+    AlreadyCaptured { face: Face },
+    /// Started averaging samples in a new orientation.
+    CollectingStarted { face: Face },
+    /// Board moved before enough samples were collected, orientation is dropped.
+    MovedDuringCollection { face: Face },
+    /// Orientation is captured.
+    PositionCaptured { face: Face, remaining: usize },
+    /// All six orientations are captured, call [`AccelCalibrator::finish`].
+    Complete,
+}
+
+impl fmt::Display for CalibrationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalibrationEvent::Misaligned { tilt_deg } => {
+                write!(f, "board is tilted {tilt_deg:.1}° from an axis, align it better")
+            }
+            CalibrationEvent::AlreadyCaptured { face } => {
+                write!(f, "{face} is already captured, rotate to a new orientation")
+            }
+            CalibrationEvent::CollectingStarted { face } => {
+                write!(f, "{face}: collecting samples, keep still")
+            }
+            CalibrationEvent::MovedDuringCollection { face } => {
+                write!(f, "{face}: board moved, hold it still again")
+            }
+            CalibrationEvent::PositionCaptured { face, remaining } => {
+                write!(f, "{face}: captured, {remaining} orientations left")
+            }
+            CalibrationEvent::Complete => write!(f, "all orientations captured"),
+        }
+    }
+}
+
+/// Result of accelerometer calibration.
+///
+/// Calibrated reading is `misalignment * ((raw - bias) * scale)`.
+#[derive(Clone, Copy, Debug)]
+pub struct AccelCalibration {
+    pub bias: [f32; 3],
+    pub scale: [f32; 3],
+    /// Cross-axis correction with unit diagonal.
+    pub misalignment: [[f32; 3]; 3],
+    /// RMS error of calibrated readings against `g`, in m/s².
+    pub residual_rms: f32,
+}
+
+impl AccelCalibration {
+    /// Write calibration into `config`.
+    pub fn apply_to(&self, config: &mut MpuConfig) {
+        config.accel_bias = self.bias;
+        config.accel_scale = self.scale;
+        config.accel_misalignment = self.misalignment;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccelCalibrationError {
+    /// [`AccelCalibrator::finish`] was called before all orientations were captured.
+    Incomplete { captured: usize },
+    /// Calibration wasn't finished in time.
+    Timeout { captured: usize },
+    /// Unable to read from the sensor.
+    SensorError,
+    /// Captured orientations don't define a solution.
+    Singular,
+    ResidualTooLarge { rms: f32, limit: f32 },
+    ScaleOutOfRange { axis: usize, scale: f32 },
+    BiasOutOfRange { axis: usize, bias: f32 },
+}
+
+impl fmt::Display for AccelCalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use AccelCalibrationError::*;
+        match self {
+            Incomplete { captured } => write!(f, "only {captured} of 6 orientations captured"),
+            Timeout { captured } => write!(f, "timed out with {captured} of 6 orientations captured"),
+            SensorError => write!(f, "unable to read accelerometer"),
+            Singular => write!(f, "captured orientations don't define a solution"),
+            ResidualTooLarge { rms, limit } => {
+                write!(f, "residual error {rms:.3} m/s² exceeds {limit:.3} m/s²")
+            }
+            ScaleOutOfRange { axis, scale } => write!(f, "axis {axis} scale {scale} out of range"),
+            BiasOutOfRange { axis, bias } => write!(f, "axis {axis} bias {bias} m/s² out of range"),
+        }
+    }
+}
+
+impl std::error::Error for AccelCalibrationError {}
+
+/// Six-position accelerometer calibration state machine.
+pub struct AccelCalibrator {
+    opts: AccelCalibrationOptions,
+    state: CalibrationState,
+    window: VecDeque<Vector3<f32>>,
+    sum: Vector3<f32>,
+    captured: [Option<Vector3<f32>>; 6],
+    /// Last event reported while waiting, so it isn't repeated on every sample.
+    last_waiting_event: Option<CalibrationEvent>,
+}
+
+impl AccelCalibrator {
+    pub fn new(opts: AccelCalibrationOptions) -> Self {
+        AccelCalibrator {
+            opts,
+            state: CalibrationState::WaitingForStill,
+            window: VecDeque::with_capacity(opts.still_window),
+            sum: Vector3::zeros(),
+            captured: [None; 6],
+            last_waiting_event: None,
+        }
+    }
+
+    pub fn state(&self) -> CalibrationState {
+        self.state
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state == CalibrationState::Complete
+    }
+
+    /// Amount of captured orientations.
+    pub fn captured(&self) -> usize {
+        self.captured.iter().filter(|c| c.is_some()).count()
+    }
+
+    /// Orientations which aren't captured yet.
+    pub fn missing(&self) -> Vec<Face> {
+        Face::ALL
+            .iter()
+            .copied()
+            .filter(|face| self.captured[face.index()].is_none())
+            .collect()
+    }
+
+    /// Feed a single raw accelerometer sample (m/s²).
+    pub fn feed(&mut self, accel: [f32; 3]) -> Option<CalibrationEvent> {
+        let accel = Vector3::from(accel);
+        if self.window.len() == self.opts.still_window {
+            self.window.pop_front();
+        }
+        self.window.push_back(accel);
+
+        match self.state {
+            CalibrationState::Complete => None,
+            CalibrationState::WaitingForStill => {
+                if !self.is_still() {
+                    self.last_waiting_event = None;
+                    return None;
+                }
+                let mean = self.window_mean();
+                let (face, tilt_deg) = Face::closest(&mean);
+                let event = if tilt_deg > self.opts.max_tilt_deg {
+                    CalibrationEvent::Misaligned { tilt_deg }
+                } else if self.captured[face.index()].is_some() {
+                    CalibrationEvent::AlreadyCaptured { face }
+                } else {
+                    self.last_waiting_event = None;
+                    self.sum = Vector3::zeros();
+                    self.state = CalibrationState::Collecting { face, collected: 0 };
+                    return Some(CalibrationEvent::CollectingStarted { face });
+                };
+                self.report_waiting(event)
+            }
+            CalibrationState::Collecting { face, collected } => {
+                if !self.is_still() || Face::closest(&accel).0 != face {
+                    self.state = CalibrationState::WaitingForStill;
+                    return Some(CalibrationEvent::MovedDuringCollection { face });
+                }
+                self.sum += accel;
+                let collected = collected + 1;
+                if collected < self.opts.samples_per_position {
+                    self.state = CalibrationState::Collecting { face, collected };
+                    return None;
+                }
+
+                self.captured[face.index()] = Some(self.sum / collected as f32);
+                let remaining = 6 - self.captured();
+                if remaining == 0 {
+                    self.state = CalibrationState::Complete;
+                    Some(CalibrationEvent::Complete)
+                } else {
+                    self.state = CalibrationState::WaitingForStill;
+                    self.last_waiting_event = Some(CalibrationEvent::AlreadyCaptured { face });
+                    Some(CalibrationEvent::PositionCaptured { face, remaining })
+                }
+            }
+        }
+    }
+
+    /// Solve for calibration and check its quality.
+    pub fn finish(&self) -> Result<AccelCalibration, AccelCalibrationError> {
+        let captured = self.captured();
+        let readings: Vec<(Face, Vector3<f32>)> = Face::ALL
+            .iter()
+            .copied()
+            .filter_map(|face| self.captured[face.index()].map(|r| (face, r)))
+            .collect();
+        if readings.len() < 6 {
+            return Err(AccelCalibrationError::Incomplete { captured });
+        }
+        solve(&readings, &self.opts)
+    }
+
+    fn report_waiting(&mut self, event: CalibrationEvent) -> Option<CalibrationEvent> {
+        let repeated = match (self.last_waiting_event, event) {
+            (Some(CalibrationEvent::Misaligned { .. }), CalibrationEvent::Misaligned { .. }) => true,
+            (last, event) => last == Some(event),
+        };
+        self.last_waiting_event = Some(event);
+        if repeated {
+            None
+        } else {
+            Some(event)
+        }
+    }
+
+    fn window_mean(&self) -> Vector3<f32> {
+        self.window.iter().sum::<Vector3<f32>>() / self.window.len() as f32
+    }
+
+    fn is_still(&self) -> bool {
+        if self.window.len() < self.opts.still_window {
+            return false;
+        }
+        let mean = self.window_mean();
+        let variance = self
+            .window
+            .iter()
+            .map(|s| (s - mean).component_mul(&(s - mean)))
+            .sum::<Vector3<f32>>()
+            / self.window.len() as f32;
+        let threshold = self.opts.still_threshold * self.opts.still_threshold;
+        variance.iter().all(|v| *v <= threshold)
+    }
+}
+
+/// Least squares fit of `gravity = m * raw + c` for every axis row.
+fn solve(
+    readings: &[(Face, Vector3<f32>)],
+    opts: &AccelCalibrationOptions,
+) -> Result<AccelCalibration, AccelCalibrationError> {
+    let rows: Vec<Vector4<f64>> = readings
+        .iter()
+        .map(|(_, r)| Vector4::new(r.x as f64, r.y as f64, r.z as f64, 1.0))
+        .collect();
+    let normal: Matrix4<f64> = rows.iter().map(|r| r * r.transpose()).sum();
+    let normal_inv = normal.try_inverse().ok_or(AccelCalibrationError::Singular)?;
+
+    let mut m = Matrix3::<f32>::zeros();
+    let mut c = Vector3::<f32>::zeros();
+    for axis in 0..3 {
+        let rhs: Vector4<f64> = rows
+            .iter()
+            .zip(readings)
+            .map(|(row, (face, _))| row * face.gravity()[axis] as f64)
+            .sum();
+        let solution = normal_inv * rhs;
+        for j in 0..3 {
+            m[(axis, j)] = solution[j] as f32;
+        }
+        c[axis] = solution[3] as f32;
+    }
+
+    let m_inv = m.try_inverse().ok_or(AccelCalibrationError::Singular)?;
+    let bias = -(m_inv * c);
+
+    let squared_error: f32 = readings
+        .iter()
+        .map(|(face, r)| (m * r + c - face.gravity()).norm_squared())
+        .sum();
+    let residual_rms = (squared_error / readings.len() as f32).sqrt();
+    if !residual_rms.is_finite() || residual_rms > opts.max_residual {
+        return Err(AccelCalibrationError::ResidualTooLarge {
+            rms: residual_rms,
+            limit: opts.max_residual,
+        });
+    }
+
+    let scale: [f32; 3] = core::array::from_fn(|axis| m[(axis, axis)]);
+    for (axis, scale) in scale.iter().enumerate() {
+        if !(opts.scale_range.0..=opts.scale_range.1).contains(scale) {
+            return Err(AccelCalibrationError::ScaleOutOfRange {
+                axis,
+                scale: *scale,
+            });
+        }
+    }
+    for (axis, bias) in bias.iter().enumerate() {
+        if bias.abs() > opts.max_bias {
+            return Err(AccelCalibrationError::BiasOutOfRange { axis, bias: *bias });
+        }
+    }
+
+    // m = misalignment * diag(scale)
+    let misalignment = m * Matrix3::from_diagonal(&Vector3::from(scale.map(|s| 1.0 / s)));
+    Ok(AccelCalibration {
+        bias: bias.into(),
+        scale,
+        misalignment: core::array::from_fn(|i| core::array::from_fn(|j| misalignment[(i, j)])),
+        residual_rms,
+    })
+}
+
+/// Run calibration on `mpu` until all orientations are captured or `timeout` runs out.
+/// Every [`CalibrationEvent`] is passed to `on_event`.
+pub fn calibrate_accel<F: FnMut(CalibrationEvent)>(
+    mpu: &mut Mpu,
+    opts: AccelCalibrationOptions,
+    timeout: Duration,
+    mut on_event: F,
+) -> Result<AccelCalibration, AccelCalibrationError> {
+    let start_time = Instant::now();
+    let mut calibrator = AccelCalibrator::new(opts);
+    while !calibrator.is_complete() {
+        if start_time.elapsed() > timeout {
+            return Err(AccelCalibrationError::Timeout {
+                captured: calibrator.captured(),
+            });
+        }
+        let accel = mpu
+            .get_raw_accel()
+            .map_err(|_e| AccelCalibrationError::SensorError)?;
+        if let Some(event) = calibrator.feed(accel) {
+            on_event(event);
+        }
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    calibrator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIAS: [f32; 3] = [0.3, -0.2, 0.5];
+    const SCALE: [f32; 3] = [1.02, 0.97, 1.05];
+
+    fn options() -> AccelCalibrationOptions {
+        AccelCalibrationOptions {
+            still_window: 10,
+            samples_per_position: 20,
+            ..AccelCalibrationOptions::default()
+        }
+    }
+
+    /// Raw reading of a sensor with [`BIAS`] and [`SCALE`] held still with `face` up.
+    fn raw(face: Face) -> Vector3<f32> {
+        face.gravity().component_div(&Vector3::from(SCALE)) + Vector3::from(BIAS)
+    }
+
+    /// Hold still with `face` up till the calibrator is done with it, with a bit of noise.
+    fn hold(calibrator: &mut AccelCalibrator, face: Face) -> Vec<CalibrationEvent> {
+        let opts = options();
+        (0..opts.still_window + opts.samples_per_position)
+            .filter_map(|i| {
+                let noise = if i % 2 == 0 { 0.01 } else { -0.01 };
+                calibrator.feed((raw(face) + Vector3::repeat(noise)).into())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn solves_bias_and_scale() {
+        let mut calibrator = AccelCalibrator::new(options());
+        for face in Face::ALL {
+            hold(&mut calibrator, face);
+        }
+        assert!(calibrator.is_complete());
+
+        let calibration = calibrator.finish().unwrap();
+        for axis in 0..3 {
+            assert!(
+                (calibration.bias[axis] - BIAS[axis]).abs() < 1e-3,
+                "{:?}",
+                calibration
+            );
+            assert!(
+                (calibration.scale[axis] - SCALE[axis]).abs() < 1e-3,
+                "{:?}",
+                calibration
+            );
+            for j in 0..3 {
+                let expected = if axis == j { 1.0 } else { 0.0 };
+                assert!((calibration.misalignment[axis][j] - expected).abs() < 1e-3);
+            }
+        }
+        assert!(calibration.residual_rms < 1e-3);
+    }
+
+    #[test]
+    fn rejects_duplicate_face() {
+        let mut calibrator = AccelCalibrator::new(options());
+        assert!(
+            hold(&mut calibrator, Face::PosZ).contains(&CalibrationEvent::PositionCaptured {
+                face: Face::PosZ,
+                remaining: 5
+            })
+        );
+        assert_eq!(hold(&mut calibrator, Face::NegZ).len(), 2);
+        // Turning back to a captured face is reported once and doesn't start collecting.
+        assert_eq!(
+            hold(&mut calibrator, Face::PosZ),
+            [CalibrationEvent::AlreadyCaptured { face: Face::PosZ }]
+        );
+        assert_eq!(calibrator.captured(), 2);
+        assert_eq!(calibrator.state(), CalibrationState::WaitingForStill);
+    }
+
+    #[test]
+    fn rejects_missing_face() {
+        let mut calibrator = AccelCalibrator::new(options());
+        for face in &Face::ALL[..5] {
+            hold(&mut calibrator, *face);
+        }
+        assert!(!calibrator.is_complete());
+        assert_eq!(calibrator.missing(), [Face::NegZ]);
+        assert_eq!(
+            calibrator.finish().unwrap_err(),
+            AccelCalibrationError::Incomplete { captured: 5 }
+        );
+    }
+}
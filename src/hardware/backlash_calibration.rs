@@ -0,0 +1,269 @@
+//! Gearbox backlash estimation from distance readings.
+//!
+//! Laser is pointed at a flat target at an angle, so measured distance changes steadily
+//! with the axis angle. The axis is swept forward and then back over the same positions.
+//! Going back the axis lags behind the motor by the backlash, so the backward distance
+//! profile is the forward one shifted by backlash steps, which is found by least squares.
+//!
+//! # Example
+//! ```ignore
+//! let estimate = calibrate_backlash(
+//!     &PITCH_CONTROLLER,
+//!     &DISTANCE_CONTROLLER,
+//!     BacklashCalibrationOptions::default(),
+//!     |sample| println!("{sample:?}"),
+//! )?;
+//! let mut config = PITCH_CONTROLLER.get_config();
+//! config.backlash_steps = estimate.steps;
+//! PITCH_CONTROLLER.set_config(config);
+//! ```
+
+use super::distance::{DistanceController, DistanceReading};
+use super::motor::{BacklashMode, StepMotorController};
+use anyhow::{format_err, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BacklashCalibrationOptions {
+    /// Length of the sweep, steps.
+    pub sweep_steps: u32,
+    /// Steps between measurements.
+    pub sample_every: u32,
+    /// Measurements averaged at every position.
+    pub samples_per_position: u32,
+    /// Largest backlash considered, steps.
+    pub max_backlash: u32,
+    /// Least distance change over the sweep, less means the target isn't angled enough.
+    pub min_distance_span_mm: f32,
+}
+
+impl Default for BacklashCalibrationOptions {
+    fn default() -> Self {
+        BacklashCalibrationOptions {
+            sweep_steps: 300,
+            sample_every: 5,
+            samples_per_position: 3,
+            max_backlash: 100,
+            min_distance_span_mm: 20.0,
+        }
+    }
+}
+
+/// Averaged distance at commanded motor position `pos`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProfileSample {
+    pub pos: i32,
+    pub distance_mm: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BacklashEstimate {
+    pub steps: u32,
+    /// RMS difference between backward and shifted forward profiles.
+    pub residual_rms_mm: f32,
+}
+
+/// Forward profile distance at `pos`, linearly interpolated. `forward` must be sorted by position.
+fn interpolate(forward: &[ProfileSample], pos: f32) -> Option<f32> {
+    let i = forward.iter().position(|s| s.pos as f32 >= pos)?;
+    let upper = forward[i];
+    if upper.pos as f32 == pos {
+        return Some(upper.distance_mm);
+    }
+    let lower = forward.get(i.checked_sub(1)?)?;
+    let fraction = (pos - lower.pos as f32) / (upper.pos - lower.pos) as f32;
+    Some(lower.distance_mm + (upper.distance_mm - lower.distance_mm) * fraction)
+}
+
+/// Find the shift of `backward` profile against `forward` one, up to `max_backlash` steps.
+///
+/// `forward` must be recorded with increasing positions, `backward` right after it with
+/// decreasing ones, starting from the last forward position.
+pub fn estimate_backlash(
+    forward: &[ProfileSample],
+    backward: &[ProfileSample],
+    max_backlash: u32,
+) -> Result<BacklashEstimate> {
+    let end = forward
+        .last()
+        .ok_or_else(|| format_err!("empty forward profile"))?
+        .pos;
+    // Shift must leave enough of the profiles overlapping to be trusted.
+    let min_overlap = (backward.len() / 2).max(5);
+
+    let mut best: Option<BacklashEstimate> = None;
+    for steps in 0..=max_backlash {
+        // Axis doesn't move untill the slack is taken up, those samples are skipped.
+        let residuals: Vec<f32> = backward
+            .iter()
+            .filter(|s| s.pos + steps as i32 <= end)
+            .filter_map(|s| {
+                interpolate(forward, (s.pos + steps as i32) as f32).map(|f| s.distance_mm - f)
+            })
+            .collect();
+        if residuals.len() < min_overlap {
+            break;
+        }
+        let residual_rms_mm =
+            (residuals.iter().map(|r| r * r).sum::<f32>() / residuals.len() as f32).sqrt();
+        if best.is_none_or(|b| residual_rms_mm < b.residual_rms_mm) {
+            best = Some(BacklashEstimate {
+                steps,
+                residual_rms_mm,
+            });
+        }
+    }
+    best.ok_or_else(|| format_err!("profiles are too short to compare"))
+}
+
+/// Average distance over `samples` measurements, failed ones are skipped.
+fn measure(distance: &DistanceController, samples: u32) -> Option<f32> {
+    let readings: Vec<f32> = (0..samples)
+        .filter_map(|_| match distance.get_measurement() {
+            DistanceReading::Ok { distance, .. } => Some(distance.as_mm() as f32),
+            _ => None,
+        })
+        .collect();
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings.iter().sum::<f32>() / readings.len() as f32)
+    }
+}
+
+fn move_to(motor: &StepMotorController, pos: i32) {
+    motor.set_target_pos(pos);
+    motor.wait_stop();
+}
+
+fn sweep<F: FnMut(ProfileSample)>(
+    motor: &StepMotorController,
+    distance: &DistanceController,
+    opts: &BacklashCalibrationOptions,
+    start: i32,
+    mut on_sample: F,
+) -> Result<(Vec<ProfileSample>, Vec<ProfileSample>)> {
+    let end = start + opts.sweep_steps as i32;
+    let positions: Vec<i32> = (start..=end).step_by(opts.sample_every as usize).collect();
+
+    // Take up the slack, so forward sweep starts with the gear loaded.
+    move_to(motor, start - opts.max_backlash as i32);
+
+    let mut profile = |pos: i32| -> Result<ProfileSample> {
+        move_to(motor, pos);
+        let distance_mm = measure(distance, opts.samples_per_position)
+            .ok_or_else(|| format_err!("no distance reading at position {pos}"))?;
+        let sample = ProfileSample { pos, distance_mm };
+        on_sample(sample);
+        Ok(sample)
+    };
+    let forward = positions
+        .iter()
+        .map(|pos| profile(*pos))
+        .collect::<Result<Vec<_>>>()?;
+    let backward = positions
+        .iter()
+        .rev()
+        .skip(1)
+        .map(|pos| profile(*pos))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((forward, backward))
+}
+
+/// Sweep `motor` forward from it's current position and back, measuring distance to
+/// an angled flat target. Every measured [`ProfileSample`] is passed to `on_sample`.
+///
+/// Backlash compensation is off during the sweep, motor is returned to where it started.
+pub fn calibrate_backlash<F: FnMut(ProfileSample)>(
+    motor: &StepMotorController,
+    distance: &DistanceController,
+    opts: BacklashCalibrationOptions,
+    on_sample: F,
+) -> Result<BacklashEstimate> {
+    if opts.sample_every == 0 || opts.sweep_steps < opts.max_backlash + 10 * opts.sample_every {
+        return Err(format_err!(
+            "sweep must be longer than max backlash plus 10 samples"
+        ));
+    }
+
+    let config = motor.get_config();
+    let mut uncompensated = config;
+    uncompensated.backlash_steps = 0;
+    uncompensated.backlash_mode = BacklashMode::TakeUp;
+    motor.set_config(uncompensated);
+
+    let start = motor.get_current_pos();
+    let profiles = sweep(motor, distance, &opts, start, on_sample);
+    move_to(motor, start);
+    motor.set_config(config);
+    let (forward, backward) = profiles?;
+
+    let (min, max) = forward.iter().fold((f32::MAX, f32::MIN), |(min, max), s| {
+        (min.min(s.distance_mm), max.max(s.distance_mm))
+    });
+    if max - min < opts.min_distance_span_mm {
+        return Err(format_err!(
+            "distance changed only {:.1}mm over the sweep, target should be at an angle",
+            max - min
+        ));
+    }
+    estimate_backlash(&forward, &backward, opts.max_backlash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Distance to an angled wall with the axis at `angle` steps.
+    fn wall(angle: i32) -> f32 {
+        let angle = angle as f32;
+        800.0 + 0.4 * angle + 0.001 * angle * angle
+    }
+
+    /// Sweep from 0 to 300 and back over a gearbox with `backlash` steps of dead band.
+    fn profiles(backlash: i32) -> (Vec<ProfileSample>, Vec<ProfileSample>) {
+        let positions: Vec<i32> = (0..=300).step_by(5).collect();
+        let end = *positions.last().unwrap();
+        let forward = positions
+            .iter()
+            .map(|&pos| ProfileSample {
+                pos,
+                distance_mm: wall(pos),
+            })
+            .collect();
+        // Going back the axis stays put till the motor has turned through the dead band.
+        let backward = positions
+            .iter()
+            .rev()
+            .skip(1)
+            .map(|&pos| ProfileSample {
+                pos,
+                distance_mm: wall((pos + backlash).min(end)),
+            })
+            .collect();
+        (forward, backward)
+    }
+
+    #[test]
+    fn finds_dead_band() {
+        for backlash in [0, 5, 23, 60] {
+            let (forward, backward) = profiles(backlash);
+            let estimate = estimate_backlash(&forward, &backward, 100).unwrap();
+            assert_eq!(estimate.steps, backlash as u32);
+            assert!(estimate.residual_rms_mm < 0.1, "{:?}", estimate);
+        }
+    }
+
+    #[test]
+    fn dead_band_beyond_max_backlash() {
+        let (forward, backward) = profiles(60);
+        let estimate = estimate_backlash(&forward, &backward, 30).unwrap();
+        assert_eq!(estimate.steps, 30);
+    }
+
+    #[test]
+    fn rejects_short_profiles() {
+        let (forward, backward) = profiles(0);
+        assert!(estimate_backlash(&[], &backward, 100).is_err());
+        assert!(estimate_backlash(&forward, &backward[..3], 100).is_err());
+    }
+}
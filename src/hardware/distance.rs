@@ -7,6 +7,9 @@
 //! // Request and wait for measurement, blocks thread
 //! let measurement = controller.get_measurement();
 //!
+//! // Or from async code, without blocking the executor
+//! let measurement = controller.measure().await;
+//!
 //! // Or...
 //! controller.request_measurement();
 //! // ... do some stuff ...
@@ -15,11 +18,16 @@
 //!
 //! ```
 
+use crate::metrics::{MEASUREMENTS, MEASUREMENT_DURATION};
 use crate::shared::{IsDead, SharedState};
+use anyhow::ensure;
 use mio_serial::*;
+use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, error};
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub struct Distance {
@@ -51,10 +59,13 @@ pub enum DistanceReading {
     },
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
 enum ReadingState {
+    #[default]
     Ready,
     Pending,
+    /// Laser should be turned off and the control loop stopped.
+    Stopping,
     Dead,
 }
 
@@ -64,12 +75,6 @@ impl IsDead for ReadingState {
     }
 }
 
-impl Default for ReadingState {
-    fn default() -> Self {
-        ReadingState::Ready
-    }
-}
-
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum DistanceReadingError {
     UnknownError = 0,
@@ -136,23 +141,70 @@ impl DistanceReadingError {
     }
 }
 
+/// Callers of [`DistanceController::measure`] waiting for the next reading.
+type Waiters = Arc<Mutex<Vec<oneshot::Sender<DistanceReading>>>>;
+
+/// Sets [`ReadingState::Dead`] when the control loop exits, even by panic,
+/// so nobody waits for it forever.
+struct DeadOnDrop(Arc<SharedState<ReadingState>>, Waiters);
+
+impl Drop for DeadOnDrop {
+    fn drop(&mut self) {
+        self.0.set_state(ReadingState::Dead);
+        self.1.lock().unwrap().clear();
+    }
+}
+
+fn record_metrics(reading: DistanceReading, mode: ReadingMode, duration: Duration) {
+    let error;
+    let labels: &[_] = match reading {
+        DistanceReading::NoReading => &[("result", "no_reading"), ("error", "")],
+        DistanceReading::Ok { .. } => &[("result", "ok"), ("error", "")],
+        DistanceReading::Err { error: e, .. } => {
+            debug!(error = ?e, code = e as u8, mode = mode.name(), "Distance measurement failed");
+            error = format!("{e:?}");
+            &[("result", "error"), ("error", &error)]
+        }
+    };
+    MEASUREMENTS.with(labels).inc();
+    MEASUREMENT_DURATION
+        .with(&[("mode", mode.name())])
+        .observe(duration.as_secs_f64());
+}
+
 /// Separate thread control loop for [`DistanceController`]
 fn distance_sensor_control_loop(
     mut distance_sensor: DistanceSensor,
     state: Arc<SharedState<ReadingState>>,
     mode: Arc<Mutex<ReadingMode>>,
     distance_reading: Arc<Mutex<DistanceReading>>,
+    waiters: Waiters,
 ) {
+    let _dead_on_drop = DeadOnDrop(state.clone(), waiters.clone());
     loop {
-        state.await_state(ReadingState::Pending);
-
-        if state.get_state().is_dead() {
-            break;
+        state.await_until(|s| s != ReadingState::Ready);
+
+        match state.get_state() {
+            ReadingState::Pending => {
+                // Not holding the lock while measuring, last reading is there any time.
+                let mode = *mode.lock().unwrap();
+                let start = Instant::now();
+                let reading = distance_sensor.read_distance_mode(mode);
+                record_metrics(reading, mode, start.elapsed());
+                *distance_reading.lock().unwrap() = reading;
+                state.set_state(ReadingState::Ready);
+                for waiter in waiters.lock().unwrap().drain(..) {
+                    let _ = waiter.send(reading);
+                }
+            }
+            ReadingState::Stopping => {
+                if let Err(e) = distance_sensor.stop() {
+                    error!(error = %e, "Failed to turn laser off");
+                }
+                break;
+            }
+            ReadingState::Ready | ReadingState::Dead => break,
         }
-
-        let mut reading_m = distance_reading.lock().unwrap();
-        *reading_m = distance_sensor.read_distance_mode(*mode.lock().unwrap());
-        state.set_state(ReadingState::Ready);
     }
 }
 
@@ -161,6 +213,7 @@ pub struct DistanceController {
     state: Arc<SharedState<ReadingState>>,
     reading: Arc<Mutex<DistanceReading>>,
     reading_mode: Arc<Mutex<ReadingMode>>,
+    waiters: Waiters,
     _thread_handle: Option<thread::JoinHandle<()>>,
 }
 
@@ -176,12 +229,16 @@ impl DistanceController {
         let reading_mode = Arc::new(Mutex::new(ReadingMode::Default));
         let reading_mode_clone = reading_mode.clone();
 
+        let waiters: Waiters = Default::default();
+        let waiters_clone = waiters.clone();
+
         let thread_handle = thread::spawn(move || {
             distance_sensor_control_loop(
                 distance_sensor,
                 state_clone,
                 reading_mode_clone,
                 reading_clone,
+                waiters_clone,
             )
         });
 
@@ -189,6 +246,7 @@ impl DistanceController {
             state,
             reading,
             reading_mode,
+            waiters,
             _thread_handle: Some(thread_handle),
         }
     }
@@ -219,14 +277,38 @@ impl DistanceController {
         *self.reading.lock().unwrap()
     }
 
+    /// Request a measurement and wait for it without blocking the thread, for async code.
+    /// Joins the measurement in progress if there is one, like [`Self::get_measurement`].
+    /// [`DistanceReading::NoReading`] if the sensor is dead.
+    pub async fn measure(&self) -> DistanceReading {
+        let (tx, rx) = oneshot::channel();
+        // Added before checking, so it's either answered or dropped once the sensor dies.
+        self.waiters.lock().unwrap().push(tx);
+        if self.state.get_state().is_dead() {
+            return DistanceReading::NoReading;
+        }
+        self.request_measurement();
+        rx.await.unwrap_or_default()
+    }
+
     /// Non-blocking get of last measurement.
     pub fn get_last_measurement(&self) -> DistanceReading {
         *self.reading.lock().unwrap()
     }
+
+    /// Turn the laser off and stop the controller, blocks untill it's done.
+    /// Measurements return last reading afterwards.
+    pub fn shutdown(&self) {
+        self.await_measurement();
+        self.state.set_state(ReadingState::Stopping);
+        self.state.await_state(ReadingState::Dead);
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
 pub enum ReadingMode {
+    #[default]
     Default,
     Fast,
     Slow,
@@ -240,6 +322,42 @@ impl ReadingMode {
             ReadingMode::Slow => b"M",
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadingMode::Default => "default",
+            ReadingMode::Fast => "fast",
+            ReadingMode::Slow => "slow",
+        }
+    }
+}
+
+/// Serial connection of the sensor and how it measures.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DistanceSensorConfig {
+    /// Serial port the sensor is connected to.
+    pub port: String,
+    pub baud_rate: u32,
+    pub mode: ReadingMode,
+}
+
+impl Default for DistanceSensorConfig {
+    fn default() -> Self {
+        DistanceSensorConfig {
+            port: "/dev/ttyS0".to_string(),
+            baud_rate: 19200,
+            mode: ReadingMode::Default,
+        }
+    }
+}
+
+impl DistanceSensorConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(!self.port.is_empty(), "port must be set");
+        ensure!(self.baud_rate > 0, "baud_rate must be positive");
+        Ok(())
+    }
 }
 
 pub use sensor::*;
@@ -249,6 +367,7 @@ pub use sensor::*;
 #[cfg(not(feature = "mock_hardware"))]
 mod sensor {
     use super::*;
+    use tracing::warn;
 
     /// HI50 Distance sensor.
     pub struct DistanceSensor {
@@ -262,13 +381,18 @@ mod sensor {
     }
 
     impl DistanceSensor {
-        /// Create new HI50 Distance sensor with hardcoded values.
+        /// Create new HI50 Distance sensor on the default port.
         pub fn new() -> Self {
-            let tty_port = mio_serial::new("/dev/ttyS0", 19200)
+            Self::from_config(&DistanceSensorConfig::default())
+        }
+
+        /// Create new HI50 Distance sensor on the port in `config`.
+        pub fn from_config(config: &DistanceSensorConfig) -> Self {
+            let tty_port = mio_serial::new(&config.port, config.baud_rate)
                 .timeout(Duration::from_millis(3500))
                 .data_bits(DataBits::Eight)
                 .open()
-                .expect("Failed to open ttyS0 port.");
+                .unwrap_or_else(|e| panic!("Failed to open {} port: {e}", config.port));
             DistanceSensor { tty_port }
         }
 
@@ -322,9 +446,8 @@ mod sensor {
                             }
                         }
                     }
-                    Err(_err) => {
-                        //error!(IO distance sensor error)
-                        eprintln!("Oopsy io error, {_err:?}");
+                    Err(error) => {
+                        warn!(%error, "Distance sensor IO error");
                         return DistanceReading::Err {
                             error: DistanceReadingError::UnknownError,
                             measuring_time: start.elapsed(),
@@ -343,14 +466,12 @@ mod sensor {
                 let range = [&buf[2..=3], &buf[5..=7]].concat();
                 let string = String::from_utf8(range);
                 if string.is_err() {
-                    eprintln!("Oopsy, bad string, {string:?}");
-                    eprintln!("Sensor message: {buf:?}");
+                    warn!(reply = ?buf, "Distance sensor sent a bad distance string");
                     return unkown_error;
                 }
                 let number = string.unwrap().trim().parse();
                 if number.is_err() {
-                    eprintln!("Oopsy, bad number, {number:?}");
-                    eprintln!("Sensor message: {buf:?}");
+                    warn!(reply = ?buf, "Distance sensor sent a bad distance number");
                     return unkown_error;
                 }
                 number.unwrap()
@@ -360,14 +481,12 @@ mod sensor {
                 let q_range = &buf[10..=13];
                 let string = String::from_utf8(q_range.to_vec());
                 if string.is_err() {
-                    eprintln!("Oopsy, bad quality string, {string:?}");
-                    eprintln!("Sensor message: {buf:?}");
+                    warn!(reply = ?buf, "Distance sensor sent a bad quality string");
                     return unkown_error;
                 }
                 let q_number = string.unwrap().parse();
                 if q_number.is_err() {
-                    eprintln!("Oopsy, bad quality number, {number:?}");
-                    eprintln!("Sensor message: {buf:?}");
+                    warn!(reply = ?buf, "Distance sensor sent a bad quality number");
                     return unkown_error;
                 }
                 q_number.unwrap()
@@ -397,11 +516,16 @@ mod sensor {
 
         /// Close laser. Sends `b"C"` on serial.
         pub fn stop(&mut self) -> Result<()> {
-            self.tty_port.write_all(b"C").expect("enabled laser");
-            self.tty_port.flush().expect("enabled laser");
+            self.tty_port.write_all(b"C")?;
+            self.tty_port.flush()?;
             let mut buf: Vec<u8> = vec![0; 7];
-            self.tty_port.read_exact(&mut buf).unwrap();
-            assert_eq!(buf, b"C,OK!\r\n");
+            self.tty_port.read_exact(&mut buf)?;
+            if buf != b"C,OK!\r\n" {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unexpected reply {buf:?}"),
+                ));
+            }
             Ok(())
         }
     }
@@ -426,6 +550,11 @@ mod sensor {
             DistanceSensor {}
         }
 
+        /// Same as [`Self::new`], there's no port to open.
+        pub fn from_config(_config: &DistanceSensorConfig) -> Self {
+            Self::new()
+        }
+
         /// Enable laser. Sends `b"O"` on serial.
         pub fn start(&mut self) -> Result<()> {
             Ok(())
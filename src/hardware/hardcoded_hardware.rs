@@ -3,36 +3,81 @@ use super::distance::*;
 use super::mcp23s17::*;
 use super::motor::*;
 use super::mpu::{Mpu, MpuConfig, OrientationController};
+use crate::config::{self, Config};
+use crate::kinematics::HeadKinematics;
+use crate::shutdown::{on_shutdown, ShutdownStage};
 use std::sync::Mutex;
 
 use lazy_static::lazy_static;
+use tracing::warn;
 
 const DEFAULT_MOTOR_DELAY_MS: u32 = 7;
-const PITCH_PINS: [u8; 4] = [3, 2, 1, 0];
-const YAW_PINS: [u8; 4] = [4, 5, 6, 7];
+/// Pitch motor pins on the first MCP23S17 chip, unless configured otherwise.
+pub const PITCH_PINS: [u8; 4] = [3, 2, 1, 0];
+/// Yaw motor pins on the first MCP23S17 chip, unless configured otherwise.
+pub const YAW_PINS: [u8; 4] = [4, 5, 6, 7];
 
 lazy_static! {
-    pub static ref MCP23S17: Mcp23s17Controller = Mcp23s17Controller::new();
+    pub static ref MCP23S17: Mcp23s17Controller = {
+        let config = config::current()
+            .mcp23s17
+            .unwrap_or_default();
+        let controller =
+            Mcp23s17Controller::from_config(&config).expect("Failed to create MCP23S17");
+        let handle = controller.shutdown_handle();
+        on_shutdown(
+            ShutdownStage::PowerDown,
+            "MCP23S17 outputs low",
+            move || handle.shutdown(),
+        );
+        controller
+    };
+}
+
+lazy_static! {
+    pub static ref KINEMATICS: HeadKinematics = config::current()
+        .kinematics
+        .unwrap_or_default();
 }
 
 lazy_static! {
     pub static ref YAW_CONTROLLER: StepMotorController = {
-        let pins = MCP23S17.step_motor_pins(YAW_PINS);
-        StepMotorController::from_pins(pins, DEFAULT_MOTOR_DELAY_MS)
+        let config = config::current();
+        let pins = MCP23S17.step_motor_pins_at(config.yaw_pins());
+        on_shutdown(ShutdownStage::StopMotion, "stop yaw", || {
+            YAW_CONTROLLER.stop()
+        });
+        let controller = StepMotorController::from_pins("yaw", pins, DEFAULT_MOTOR_DELAY_MS);
+        controller.set_config(config.yaw_motor.unwrap_or_default());
+        controller
     };
 }
 
 lazy_static! {
     pub static ref PITCH_CONTROLLER: StepMotorController = {
-        let pins = MCP23S17.step_motor_pins(PITCH_PINS);
-        StepMotorController::from_pins(pins, DEFAULT_MOTOR_DELAY_MS)
+        let config = config::current();
+        let pins = MCP23S17.step_motor_pins_at(config.pitch_pins());
+        on_shutdown(ShutdownStage::StopMotion, "stop pitch", || {
+            PITCH_CONTROLLER.stop()
+        });
+        let controller = StepMotorController::from_pins("pitch", pins, DEFAULT_MOTOR_DELAY_MS);
+        controller.set_config(config.pitch_motor.unwrap_or_default());
+        controller
     };
 }
 
 lazy_static! {
     pub static ref DISTANCE_CONTROLLER: DistanceController = {
-        let distance_sensor = DistanceSensor::new();
-        DistanceController::new(distance_sensor)
+        let config = config::current()
+            .distance_sensor
+            .unwrap_or_default();
+        let distance_sensor = DistanceSensor::from_config(&config);
+        on_shutdown(ShutdownStage::LaserOff, "laser off", || {
+            DISTANCE_CONTROLLER.shutdown()
+        });
+        let controller = DistanceController::new(distance_sensor);
+        controller.set_mode(config.mode);
+        controller
     };
 }
 
@@ -41,5 +86,40 @@ lazy_static! {
 }
 
 lazy_static! {
-    pub static ref MPU_CONTROLLER: Mutex<Mpu> = Mutex::new(Mpu::new(MpuConfig::default()));
+    pub static ref MPU_CONTROLLER: Mutex<Mpu> = {
+        let imu = config::current()
+            .imu
+            .unwrap_or_default();
+        Mutex::new(Mpu::new(MpuConfig::default(), imu))
+    };
+}
+
+/// Apply tuning values of a reloaded `config` to running hardware: motor speeds, hold,
+/// backlash and acceleration, distance reading mode and orientation filter gain.
+/// Pins, ports, IMU rate and kinematics only change on restart.
+pub fn apply_tuning(config: &Config) {
+    let motors = [
+        ("yaw", &*YAW_CONTROLLER, config.yaw_motor),
+        ("pitch", &*PITCH_CONTROLLER, config.pitch_motor),
+    ];
+    for (axis, controller, motor_config) in motors {
+        let motor_config = motor_config.unwrap_or_default();
+        let pins = controller.get_config().pins;
+        if motor_config.pins != pins {
+            warn!(axis, "Motor pins changed, restart to use them");
+        }
+        controller.set_config(MotorConfig {
+            pins,
+            step_delay_ms: Some(motor_config.step_delay_ms.unwrap_or(DEFAULT_MOTOR_DELAY_MS)),
+            ..motor_config
+        });
+    }
+
+    let distance_sensor = config.distance_sensor.clone().unwrap_or_default();
+    DISTANCE_CONTROLLER.set_mode(distance_sensor.mode);
+
+    let imu = config.imu.clone().unwrap_or_default();
+    if let Some(orientation) = ORIENTATION_CONTROLLER.lock().unwrap().as_ref() {
+        orientation.set_filter_gain(imu.filter_gain);
+    }
 }
@@ -0,0 +1,256 @@
+//! Recording raw MPU9250 samples to a file and replaying them back.
+//!
+//! Recording is a plain text file, one sample per line:
+//! `t ax ay az gx gy gz mx my mz temp`, where `t` is seconds since recording start.
+//! Lines starting with `#` are ignored.
+//!
+//! Gyro-only dumps from `misc/mpu_readings` (`gx gy gz dt`, where `dt` is seconds since
+//! previous sample) are loaded too, with zero accel and mag.
+//!
+//! # Example
+//! ```ignore
+//! let mut mpu = Mpu::from_config(&config::current());
+//! mpu.start_recording("session.imu")?;
+//! // ... use mpu ...
+//! mpu.stop_recording();
+//!
+//! let replay = ImuReplay::from_file("session.imu", 4.0)?;
+//! let controller = OrientationController::new(Mpu::replay(mpu.config, replay));
+//! ```
+
+use anyhow::{ensure, format_err, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "# t ax ay az gx gy gz mx my mz temp";
+
+/// Single raw MPU9250 reading.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImuSample {
+    /// Accelerometer, m/s².
+    pub accel: [f32; 3],
+    /// Gyroscope, rad/s.
+    pub gyro: [f32; 3],
+    /// Magnetometer, μT.
+    pub mag: [f32; 3],
+    /// Temperature, °C.
+    pub temp: f32,
+}
+
+/// [`ImuSample`] with time since recording start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimedImuSample {
+    pub timestamp: Duration,
+    pub sample: ImuSample,
+}
+
+/// Writes [`ImuSample`]s into a file.
+pub struct ImuRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl ImuRecorder {
+    /// Create a recording file at `path`, overwriting it if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{HEADER}")?;
+        Ok(ImuRecorder {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Write `sample` timestamped with current time.
+    pub fn record(&mut self, sample: &ImuSample) -> Result<()> {
+        let t = self.start.elapsed().as_secs_f64();
+        let [ax, ay, az] = sample.accel;
+        let [gx, gy, gz] = sample.gyro;
+        let [mx, my, mz] = sample.mag;
+        let temp = sample.temp;
+        writeln!(
+            self.writer,
+            "{t:.6} {ax} {ay} {az} {gx} {gy} {gz} {mx} {my} {mz} {temp}"
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `seconds` read from a recording, which might be negative, NaN or too large.
+fn parse_duration(seconds: f32) -> Result<Duration> {
+    Duration::try_from_secs_f32(seconds)
+        .map_err(|_| format_err!("time must be 0 or more seconds, got {seconds}"))
+}
+
+fn parse_line(line: &str, prev_timestamp: Duration) -> Result<TimedImuSample> {
+    let values = line
+        .split_whitespace()
+        .map(|v| v.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+    match values[..] {
+        [t, ax, ay, az, gx, gy, gz, mx, my, mz, temp] => Ok(TimedImuSample {
+            timestamp: parse_duration(t)?,
+            sample: ImuSample {
+                accel: [ax, ay, az],
+                gyro: [gx, gy, gz],
+                mag: [mx, my, mz],
+                temp,
+            },
+        }),
+        // `misc/mpu_readings` gyro dumps
+        [gx, gy, gz, dt] => Ok(TimedImuSample {
+            timestamp: prev_timestamp
+                .checked_add(parse_duration(dt)?)
+                .ok_or_else(|| format_err!("time overflows"))?,
+            sample: ImuSample {
+                gyro: [gx, gy, gz],
+                ..Default::default()
+            },
+        }),
+        _ => Err(format_err!("expected 11 or 4 values, got {}", values.len())),
+    }
+}
+
+/// Load all samples from a recording.
+pub fn load_recording<P: AsRef<Path>>(path: P) -> Result<Vec<TimedImuSample>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut samples: Vec<TimedImuSample> = Vec::new();
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let prev_timestamp = samples.last().map_or(Duration::ZERO, |s| s.timestamp);
+        let sample = parse_line(line, prev_timestamp)
+            .and_then(|sample| {
+                ensure!(
+                    sample.timestamp >= prev_timestamp,
+                    "time goes back from the previous sample"
+                );
+                Ok(sample)
+            })
+            .map_err(|e| format_err!("line {}: {e}", line_num + 1))?;
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+/// Source of recorded samples for [`super::mpu::Mpu::replay`].
+pub struct ImuReplay {
+    samples: Vec<TimedImuSample>,
+    next: usize,
+    /// Playback speed, 1.0 is real time.
+    speed: f32,
+    start: Option<Instant>,
+}
+
+impl ImuReplay {
+    /// Replay `samples`, in time order, at `speed` times real time.
+    pub fn new(samples: Vec<TimedImuSample>, speed: f32) -> Result<Self> {
+        ensure!(
+            speed.is_finite() && speed > 0.0,
+            "replay speed must be positive, got {speed}"
+        );
+        ensure!(
+            samples
+                .windows(2)
+                .all(|pair| pair[0].timestamp <= pair[1].timestamp),
+            "samples aren't in time order"
+        );
+        Ok(ImuReplay {
+            samples,
+            next: 0,
+            speed,
+            start: None,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P, speed: f32) -> Result<Self> {
+        Self::new(load_recording(path)?, speed)
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.samples.len()
+    }
+
+    /// Recording time of the sample last returned by [`ImuReplay::next_sample`].
+    pub fn last_timestamp(&self) -> Option<Duration> {
+        let last = self.next.checked_sub(1)?;
+        self.samples.get(last).map(|timed| timed.timestamp)
+    }
+
+    /// Start replaying from the beginning.
+    pub fn rewind(&mut self) {
+        self.next = 0;
+        self.start = None;
+    }
+
+    /// Returns next sample, blocks untill it's due according to playback speed.
+    pub fn next_sample(&mut self) -> Result<ImuSample> {
+        let timed = self
+            .samples
+            .get(self.next)
+            .ok_or_else(|| format_err!("IMU replay finished"))?;
+        let first_timestamp = self.samples[0].timestamp;
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        let due = (timed.timestamp - first_timestamp).div_f32(self.speed);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            spin_sleep::sleep(wait);
+        }
+        self.next += 1;
+        Ok(timed.sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_both_formats() {
+        let full = parse_line("0.5 0 0 9.81 0.1 0 0 20 0 40 25", Duration::ZERO).unwrap();
+        assert_eq!(full.timestamp, Duration::from_millis(500));
+        assert_eq!(full.sample.accel, [0.0, 0.0, 9.81]);
+        assert_eq!(full.sample.mag, [20.0, 0.0, 40.0]);
+
+        let gyro = parse_line("0.1 0.2 0.3 0.25", Duration::from_secs(1)).unwrap();
+        assert_eq!(gyro.timestamp, Duration::from_millis(1250));
+        assert_eq!(gyro.sample.gyro, [0.1, 0.2, 0.3]);
+        assert_eq!(gyro.sample.accel, [0.0; 3]);
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        for line in [
+            "-1 0 0 9.81 0 0 0 0 0 0 25",
+            "NaN 0 0 9.81 0 0 0 0 0 0 25",
+            "1e30 0 0 9.81 0 0 0 0 0 0 25",
+            "0 0 0 -0.001",
+            "0 0 0 inf",
+        ] {
+            assert!(parse_line(line, Duration::ZERO).is_err(), "{}", line);
+        }
+        assert!(parse_line("0 0 0 1", Duration::MAX).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_speed() {
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(ImuReplay::new(Vec::new(), speed).is_err(), "{}", speed);
+        }
+        assert!(ImuReplay::new(Vec::new(), 4.0).is_ok());
+    }
+}
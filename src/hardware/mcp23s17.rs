@@ -1,91 +1,755 @@
 //! MCP23S17 pin multiplexer.
+//!
+//! Several chips can share one chip select line, each with it's own hardware address
+//! (0 to 7, set by A0-A2 pins). Every chip has 16 pins: 0 to 7 are GPIOA, 8 to 15 are GPIOB.
 
-#[cfg(feature = "mock_hardware")]
+#[cfg(any(test, feature = "mock_hardware"))]
 use super::mcp23s17_mock::{pin, Mcp23s17};
-#[cfg(not(feature = "mock_hardware"))]
+#[cfg(not(any(test, feature = "mock_hardware")))]
 use rppal_mcp23s17::{pin, Mcp23s17};
 
+use crate::shared::{IsDead, SharedState};
+use anyhow::{format_err, Result};
 use rppal::gpio::Level;
-use rppal_mcp23s17::{ChipSelect, HardwareAddress, Port, SpiBus, SpiMode};
+pub use rppal_mcp23s17::Port;
+use rppal_mcp23s17::{ChipSelect, HardwareAddress, RegisterAddress, SpiBus, SpiMode, IOCON};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Amount of pins on a single chip.
+pub const PINS_PER_CHIP: u8 = 16;
+/// SPI transfers retried before reopening the chip.
+const SPI_RETRIES: u32 = 2;
+/// How long [`ShutdownHandle::shutdown`] waits for outputs to be driven low.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const THREAD_NAME: &str = "mcp23s17";
+
+/// Error of a [`VirtualPin`], [`VirtualInputPin`] or [`VirtualPort`] operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinError {
+    /// Controller thread has exited, pin can't be used anymore.
+    ControllerStopped,
+    /// SPI transfer to chip `chip` failed even after retries and reopening the chip.
+    Spi { chip: u8 },
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PinError::ControllerStopped => write!(f, "MCP23S17 controller stopped"),
+            PinError::Spi { chip } => write!(f, "SPI transfer to MCP23S17 chip {chip} failed"),
+        }
+    }
+}
+
+impl std::error::Error for PinError {}
+
+/// State of the MCP23S17 controller thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerHealth {
+    Ok,
+    /// Last SPI transfer failed, pins may be out of sync untill the next successful one.
+    Failed,
+    /// Controller thread exited with all the outputs driven low.
+    Stopped,
+}
+
+impl IsDead for ControllerHealth {
+    fn is_dead(&self) -> bool {
+        *self == ControllerHealth::Stopped
+    }
+}
+
+/// Health of the controller thread, shared with [`Mcp23s17Controller`].
+struct ControllerStatus {
+    health: SharedState<ControllerHealth>,
+    spi_errors: AtomicU32,
+}
 
 pub trait OutputPin {
-    fn write<T: Into<Level>>(&mut self, level: T);
-    fn set_low(&mut self);
-    fn set_high(&mut self);
+    fn write<T: Into<Level>>(&mut self, level: T) -> Result<(), PinError>;
+
+    fn set_low(&mut self) -> Result<(), PinError> {
+        self.write(Level::Low)
+    }
+
+    fn set_high(&mut self) -> Result<(), PinError> {
+        self.write(Level::High)
+    }
+
+    /// Set `pins` to `levels`. Pin types which support it do this in a single transaction.
+    fn write_all(pins: &mut [Self], levels: &[bool]) -> Result<(), PinError>
+    where
+        Self: Sized,
+    {
+        for (pin, level) in pins.iter_mut().zip(levels) {
+            pin.write(*level)?;
+        }
+        Ok(())
+    }
 }
 
 impl OutputPin for rppal::gpio::OutputPin {
-    fn write<T: Into<Level>>(&mut self, level: T) {
-        rppal::gpio::OutputPin::write(self, level.into())
+    fn write<T: Into<Level>>(&mut self, level: T) -> Result<(), PinError> {
+        rppal::gpio::OutputPin::write(self, level.into());
+        Ok(())
     }
-    fn set_low(&mut self) {
-        rppal::gpio::OutputPin::set_low(self)
+}
+
+impl OutputPin for VirtualPin {
+    /// Blocks untill the controller thread has written the pin.
+    fn write<T: Into<Level>>(&mut self, level: T) -> Result<(), PinError> {
+        let high = level.into() == Level::High;
+        send_writes(&self.pin_req_tx, vec![PortWrite::pin(self.pin, high)])
     }
-    fn set_high(&mut self) {
-        rppal::gpio::OutputPin::set_high(self)
+
+    /// Pins on the same port are changed at once, in a single port write.
+    fn write_all(pins: &mut [Self], levels: &[bool]) -> Result<(), PinError> {
+        let mut writes: Vec<PortWrite> = Vec::new();
+        for (pin, high) in pins.iter().zip(levels) {
+            let write = PortWrite::pin(pin.pin, *high);
+            match writes
+                .iter_mut()
+                .find(|w| w.chip == write.chip && w.port == write.port)
+            {
+                Some(w) => w.merge(&write),
+                None => writes.push(write),
+            }
+        }
+        match pins.first() {
+            Some(pin) => send_writes(&pin.pin_req_tx, writes),
+            None => Ok(()),
+        }
     }
 }
 
-impl OutputPin for VirtualPin {
-    fn set_high(&mut self) {
-        self.write(Level::High);
+/// Send `writes` to the controller thread and wait for the result.
+fn send_writes(pin_req_tx: &Sender<PinRequest>, writes: Vec<PortWrite>) -> Result<(), PinError> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    pin_req_tx
+        .send(PinRequest::Write {
+            writes,
+            reply: reply_tx,
+        })
+        .map_err(|_e| PinError::ControllerStopped)?;
+    reply_rx.recv().map_err(|_e| PinError::ControllerStopped)?
+}
+
+/// Thread-safe handle for changing several pins of a single port at once.
+#[derive(Debug, Clone)]
+pub struct VirtualPort {
+    chip: u8,
+    port: Port,
+    /// Output pins of this port.
+    outputs: u8,
+    pin_req_tx: Sender<PinRequest>,
+}
+
+impl VirtualPort {
+    /// Set pins selected by `mask` (bit 0 is pin 0 of the port) to bits of `values`,
+    /// leaving the other pins untouched.
+    pub fn write(&self, mask: u8, values: u8) -> Result<(), PinError> {
+        assert_eq!(
+            mask & !self.outputs,
+            0,
+            "mask {mask:#010b} has non-output pins"
+        );
+        let write = PortWrite {
+            chip: self.chip,
+            port: self.port,
+            mask,
+            values: values & mask,
+        };
+        send_writes(&self.pin_req_tx, vec![write])
     }
+}
 
-    fn set_low(&mut self) {
-        self.write(Level::Low);
+/// Pin `pin` (0 to 15) of a chip with hardware address `chip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinAddress {
+    pub chip: u8,
+    pub pin: u8,
+}
+
+impl PinAddress {
+    pub fn new(chip: u8, pin: u8) -> Self {
+        PinAddress { chip, pin }
     }
 
-    fn write<T: Into<Level>>(&mut self, level: T) {
-        self.pin_req_tx
-            .send(PinChangeRequest {
-                pin_num: self.pin_num,
-                high: (level.into() == Level::High),
-            })
-            .expect("controller alive");
+    fn port(&self) -> Port {
+        if self.pin < 8 {
+            Port::GpioA
+        } else {
+            Port::GpioB
+        }
+    }
+
+    /// Pin number within it's port.
+    fn port_pin(&self) -> u8 {
+        self.pin % 8
     }
 }
 
+/// Single MCP23S17 chip on the shared chip select line.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChipConfig {
+    /// Hardware address, 0 to 7.
+    pub address: u8,
+    /// Pins (0 to 15) used as inputs with pull-up, all the others are outputs.
+    #[serde(default)]
+    pub input_pins: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mcp23s17Config {
+    /// SPI bus number, 0 to 6.
+    pub spi_bus: u8,
+    /// Chip select line, 0 to 15.
+    pub chip_select: u8,
+    pub clock_hz: u32,
+    pub chips: Vec<ChipConfig>,
+}
+
+impl Default for Mcp23s17Config {
+    fn default() -> Self {
+        Mcp23s17Config {
+            spi_bus: 0,
+            chip_select: 0,
+            clock_hz: 100_000,
+            chips: vec![ChipConfig {
+                address: 0,
+                input_pins: Vec::new(),
+            }],
+        }
+    }
+}
+
+impl Mcp23s17Config {
+    /// Check for bad addresses, pin numbers and duplicates.
+    pub fn validate(&self) -> Result<()> {
+        spi_bus(self.spi_bus)?;
+        chip_select(self.chip_select)?;
+        if self.chips.is_empty() {
+            return Err(format_err!("no MCP23S17 chips configured"));
+        }
+        for (i, chip) in self.chips.iter().enumerate() {
+            HardwareAddress::new(chip.address)
+                .map_err(|_e| format_err!("invalid MCP23S17 address {}", chip.address))?;
+            if self.chips[..i].iter().any(|c| c.address == chip.address) {
+                return Err(format_err!("duplicate MCP23S17 address {}", chip.address));
+            }
+            if let Some(pin) = chip.input_pins.iter().find(|p| **p >= PINS_PER_CHIP) {
+                return Err(format_err!("invalid pin {pin} on chip {}", chip.address));
+            }
+        }
+        Ok(())
+    }
+
+    fn chip(&self, address: u8) -> Option<&ChipConfig> {
+        self.chips.iter().find(|c| c.address == address)
+    }
+
+    /// `true` for configured inputs, `false` for outputs, `None` if the pin doesn't exist.
+    pub fn is_input(&self, pin: PinAddress) -> Option<bool> {
+        self.chip(pin.chip)
+            .filter(|_| pin.pin < PINS_PER_CHIP)
+            .map(|chip| chip.input_pins.contains(&pin.pin))
+    }
+}
+
+fn spi_bus(bus: u8) -> Result<SpiBus> {
+    match bus {
+        0 => Ok(SpiBus::Spi0),
+        1 => Ok(SpiBus::Spi1),
+        2 => Ok(SpiBus::Spi2),
+        3 => Ok(SpiBus::Spi3),
+        4 => Ok(SpiBus::Spi4),
+        5 => Ok(SpiBus::Spi5),
+        6 => Ok(SpiBus::Spi6),
+        _ => Err(format_err!("invalid SPI bus {bus}")),
+    }
+}
+
+fn chip_select(cs: u8) -> Result<ChipSelect> {
+    use ChipSelect::*;
+    const ALL: [ChipSelect; 16] = [
+        Cs0, Cs1, Cs2, Cs3, Cs4, Cs5, Cs6, Cs7, Cs8, Cs9, Cs10, Cs11, Cs12, Cs13, Cs14, Cs15,
+    ];
+    ALL.get(cs as usize)
+        .copied()
+        .ok_or_else(|| format_err!("invalid chip select {cs}"))
+}
+
 #[derive(Debug, Clone)]
-/// Thread-safe MCP23S17 pin.
+/// Thread-safe MCP23S17 output pin.
 pub struct VirtualPin {
-    pin_num: u8,
-    pin_req_tx: Sender<PinChangeRequest>,
+    pin: PinAddress,
+    pin_req_tx: Sender<PinRequest>,
+}
+
+#[derive(Debug, Clone)]
+/// Thread-safe MCP23S17 input pin.
+pub struct VirtualInputPin {
+    pin: PinAddress,
+    pin_req_tx: Sender<PinRequest>,
+}
+
+impl VirtualInputPin {
+    /// Read pin level, blocks untill controller thread answers.
+    pub fn read(&self) -> Result<Level, PinError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.pin_req_tx
+            .send(PinRequest::Read {
+                pin: self.pin,
+                reply: reply_tx,
+            })
+            .map_err(|_e| PinError::ControllerStopped)?;
+        let high = reply_rx
+            .recv()
+            .map_err(|_e| PinError::ControllerStopped)??;
+        Ok(high.into())
+    }
+
+    pub fn is_high(&self) -> Result<bool, PinError> {
+        Ok(self.read()? == Level::High)
+    }
+
+    pub fn is_low(&self) -> Result<bool, PinError> {
+        Ok(self.read()? == Level::Low)
+    }
 }
 
 /// MCP23S17 controller with ability to get thread-safe [`VirtualPin`].
+///
+/// Controller thread exits, driving all the outputs low, once the controller and all
+/// the pins it gave out are dropped, or on [`Mcp23s17Controller::shutdown`].
 pub struct Mcp23s17Controller {
-    pin_req_tx: Mutex<Sender<PinChangeRequest>>,
+    config: Mcp23s17Config,
+    pin_req_tx: Mutex<Sender<PinRequest>>,
+    status: Arc<ControllerStatus>,
 }
 
 #[derive(Debug, Clone, Copy)]
-/// Request to set pin `pin_number` to state `high`.
-struct PinChangeRequest {
-    pin_num: u8,
-    high: bool,
+/// Request to set pins selected by `mask` on a single port to bits of `values`.
+struct PortWrite {
+    chip: u8,
+    port: Port,
+    mask: u8,
+    values: u8,
+}
+
+impl PortWrite {
+    fn pin(pin: PinAddress, high: bool) -> Self {
+        let mask = 1 << pin.port_pin();
+        PortWrite {
+            chip: pin.chip,
+            port: pin.port(),
+            mask,
+            values: if high { mask } else { 0 },
+        }
+    }
+
+    /// Apply `other` on top of this write, both must be for the same port.
+    fn merge(&mut self, other: &PortWrite) {
+        self.values = (self.values & !other.mask) | other.values;
+        self.mask |= other.mask;
+    }
+}
+
+#[derive(Debug)]
+enum PinRequest {
+    /// Port writes, each one is done in a single transaction. Result is sent to `reply`.
+    Write {
+        writes: Vec<PortWrite>,
+        reply: Sender<Result<(), PinError>>,
+    },
+    /// Read input pin `pin`, level is sent to `reply`.
+    Read {
+        pin: PinAddress,
+        reply: Sender<Result<bool, PinError>>,
+    },
+    /// Drive all the outputs low and exit, `reply` is dropped once done.
+    Shutdown { reply: Sender<()> },
+}
+
+fn port_index(port: Port) -> usize {
+    match port {
+        Port::GpioA => 0,
+        Port::GpioB => 1,
+    }
+}
+
+fn olat_register(port: Port) -> RegisterAddress {
+    match port {
+        Port::GpioA => RegisterAddress::OLATA,
+        Port::GpioB => RegisterAddress::OLATB,
+    }
+}
+
+/// Shadow copy of the OLATA and OLATB registers of a chip.
+///
+/// Outputs are changed by writing a whole OLAT register, so the requested value is kept here
+/// instead of reading OLAT back before every write. A read-back would double the SPI
+/// transfers of a motor step, and would return the reset value after the chip is power
+/// cycled, losing the outputs which should be restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Latches {
+    /// Requested OLATA and OLATB values.
+    latch: [u8; 2],
+    /// OLATA and OLATB values known to be on the chip, `None` after a failed write.
+    written: [Option<u8>; 2],
+}
+
+impl Latches {
+    /// All outputs low, as set by [`ChipPins::new`].
+    fn new() -> Self {
+        Latches {
+            latch: [0; 2],
+            written: [Some(0); 2],
+        }
+    }
+
+    /// Set pins selected by `write.mask` to bits of `write.values`.
+    fn apply(&mut self, write: &PortWrite) {
+        let latch = &mut self.latch[port_index(write.port)];
+        *latch = (*latch & !write.mask) | (write.values & write.mask);
+    }
+
+    /// Forget what's on the chip, so every port is written on the next sync.
+    fn invalidate(&mut self) {
+        self.written = [None; 2];
+    }
+
+    /// Write latch values which aren't known to be on the chip with `write_olat`.
+    fn sync<F>(&mut self, mut write_olat: F) -> Result<()>
+    where
+        F: FnMut(Port, u8) -> Result<()>,
+    {
+        for port in [Port::GpioA, Port::GpioB].iter().copied() {
+            let i = port_index(port);
+            if self.written[i] != Some(self.latch[i]) {
+                self.written[i] = None;
+                write_olat(port, self.latch[i])?;
+                self.written[i] = Some(self.latch[i]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pins of a single chip, indexed by pin number.
+struct ChipPins {
+    address: u8,
+    /// Output pins are written through OLAT registers, pins only keep direction set.
+    _outputs: Vec<Option<pin::OutputPin>>,
+    inputs: Vec<Option<pin::InputPin>>,
+    latches: Latches,
+    mcp23s17: Mcp23s17,
+}
+
+impl ChipPins {
+    fn new(config: &Mcp23s17Config, chip: &ChipConfig) -> Result<Self> {
+        let mcp23s17 = Mcp23s17::new(
+            HardwareAddress::new(chip.address).map_err(|e| format_err!("{e:?}"))?,
+            spi_bus(config.spi_bus)?,
+            chip_select(config.chip_select)?,
+            config.clock_hz,
+            SpiMode::Mode0,
+        )
+        .map_err(|e| format_err!("Failed to create MCP23S17: {e:?}"))?;
+
+        let mut outputs = Vec::new();
+        let mut inputs = Vec::new();
+        for pin_num in 0..PINS_PER_CHIP {
+            let address = PinAddress::new(chip.address, pin_num);
+            let pin = mcp23s17
+                .get(address.port(), address.port_pin())
+                .map_err(|e| format_err!("{e:?}"))?;
+            if chip.input_pins.contains(&pin_num) {
+                inputs.push(Some(
+                    pin.into_pullup_input_pin()
+                        .map_err(|e| format_err!("{e:?}"))?,
+                ));
+                outputs.push(None);
+            } else {
+                outputs.push(Some(
+                    pin.into_output_pin_low()
+                        .map_err(|e| format_err!("{e:?}"))?,
+                ));
+                inputs.push(None);
+            }
+        }
+
+        Ok(ChipPins {
+            address: chip.address,
+            _outputs: outputs,
+            inputs,
+            latches: Latches::new(),
+            mcp23s17,
+        })
+    }
+
+    /// Write latch values which aren't known to be on the chip.
+    fn sync(&mut self) -> Result<()> {
+        let mcp23s17 = &self.mcp23s17;
+        self.latches.sync(|port, value| {
+            mcp23s17
+                .write(olat_register(port), value)
+                .map_err(|e| format_err!("{e:?}"))
+        })
+    }
+
+    fn read_input(&self, pin: u8) -> Result<bool> {
+        let input = self.inputs[pin as usize].as_ref().expect("input pin");
+        input.is_high().map_err(|e| format_err!("{e:?}"))
+    }
+}
+
+/// Enable hardware addressing on every chip on the chip select line.
+fn enable_hardware_addressing(config: &Mcp23s17Config) -> Result<()> {
+    // Until HAEN is set every chip on the line answers to any address,
+    // so this enables it on all of them at once.
+    let mcp23s17 = Mcp23s17::new(
+        HardwareAddress::new(0).map_err(|e| format_err!("{e:?}"))?,
+        spi_bus(config.spi_bus)?,
+        chip_select(config.chip_select)?,
+        config.clock_hz,
+        SpiMode::Mode0,
+    )
+    .map_err(|e| format_err!("Failed to create MCP23S17: {e:?}"))?;
+    mcp23s17
+        .set_bits(RegisterAddress::IOCON, IOCON::HAEN_ON.bits())
+        .map_err(|e| format_err!("Failed to enable hardware addressing: {e:?}"))
+}
+
+/// All the configured chips, owned by the controller thread.
+///
+/// Outputs are driven low when dropped, including on controller thread panic.
+struct ChipSet {
+    config: Mcp23s17Config,
+    chips: Vec<ChipPins>,
+    status: Arc<ControllerStatus>,
+}
+
+impl ChipSet {
+    /// Open all the chips from `config`, enabling hardware addressing if needed.
+    fn open(config: &Mcp23s17Config, status: Arc<ControllerStatus>) -> Result<Self> {
+        if config.chips.iter().any(|c| c.address != 0) {
+            enable_hardware_addressing(config)?;
+        }
+        let chips = config
+            .chips
+            .iter()
+            .map(|chip| ChipPins::new(config, chip))
+            .collect::<Result<_>>()?;
+        Ok(ChipSet {
+            config: config.clone(),
+            chips,
+            status,
+        })
+    }
+
+    fn chip_index(&self, address: u8) -> usize {
+        self.chips
+            .iter()
+            .position(|c| c.address == address)
+            .expect("pin on configured chip")
+    }
+
+    /// Reopen chip at `index` in case it was power cycled and lost it's configuration.
+    /// Latch values are written again on the next sync.
+    fn reopen(&mut self, index: usize) -> Result<()> {
+        let address = self.chips[index].address;
+        if address != 0 {
+            enable_hardware_addressing(&self.config)?;
+        }
+        let chip_config = self.config.chip(address).expect("configured chip");
+        let mut chip = ChipPins::new(&self.config, chip_config)?;
+        chip.latches = self.chips[index].latches;
+        chip.latches.invalidate();
+        self.chips[index] = chip;
+        Ok(())
+    }
+
+    /// Run `op` on chip `address`, retrying and then reopening the chip on SPI errors.
+    fn with_retries<T, F>(&mut self, address: u8, mut op: F) -> Result<T, PinError>
+    where
+        F: FnMut(&mut ChipPins) -> Result<T>,
+    {
+        let index = self.chip_index(address);
+        for attempt in 0..=SPI_RETRIES {
+            if attempt == SPI_RETRIES {
+                warn!(chip = address, "Reopening MCP23S17 chip after SPI errors");
+                if let Err(e) = self.reopen(index) {
+                    self.spi_error(address, e);
+                    break;
+                }
+            }
+            match op(&mut self.chips[index]) {
+                Ok(value) => {
+                    if self.status.health.get_state() == ControllerHealth::Failed {
+                        info!(chip = address, "MCP23S17 chip recovered");
+                        self.status.health.set_state(ControllerHealth::Ok);
+                    }
+                    return Ok(value);
+                }
+                Err(e) => self.spi_error(address, e),
+            }
+        }
+        error!(
+            chip = address,
+            "MCP23S17 chip failed, giving up on the transfer"
+        );
+        self.status.health.set_state(ControllerHealth::Failed);
+        Err(PinError::Spi { chip: address })
+    }
+
+    fn spi_error(&self, address: u8, error: anyhow::Error) {
+        self.status.spi_errors.fetch_add(1, Ordering::Relaxed);
+        warn!(chip = address, %error, "MCP23S17 SPI transfer failed");
+    }
+
+    fn write_port(&mut self, write: &PortWrite) -> Result<(), PinError> {
+        let index = self.chip_index(write.chip);
+        self.chips[index].latches.apply(write);
+        self.with_retries(write.chip, ChipPins::sync)
+    }
+
+    fn read_pin(&mut self, pin: PinAddress) -> Result<bool, PinError> {
+        self.with_retries(pin.chip, |chip| chip.read_input(pin.pin))
+    }
+
+    /// Drive all the outputs low, without retries.
+    fn all_low(&mut self) {
+        for chip in &mut self.chips {
+            chip.latches.latch = [0; 2];
+            if let Err(e) = chip.sync() {
+                error!(chip = chip.address, error = %e, "Failed to drive MCP23S17 outputs low");
+            }
+        }
+        self.status.health.set_state(ControllerHealth::Stopped);
+    }
+}
+
+impl Drop for ChipSet {
+    fn drop(&mut self) {
+        self.all_low();
+    }
+}
+
+/// Port writes waiting to be sent, at most one per port.
+#[derive(Default)]
+struct PendingWrites {
+    writes: Vec<PortWrite>,
+    /// Senders of the writes, answered by [`PendingWrites::finish`].
+    replies: Vec<Sender<Result<(), PinError>>>,
+    /// First error since the last [`PendingWrites::finish`].
+    error: Option<PinError>,
+}
+
+impl PendingWrites {
+    /// Returns `false` if `write` touches pins which already have a pending change,
+    /// merging them would skip the earlier state (e.g. a motor phase).
+    fn try_merge(&mut self, write: &PortWrite) -> bool {
+        match self
+            .writes
+            .iter_mut()
+            .find(|w| w.chip == write.chip && w.port == write.port)
+        {
+            Some(pending) if pending.mask & write.mask != 0 => false,
+            Some(pending) => {
+                pending.merge(write);
+                true
+            }
+            None => {
+                self.writes.push(*write);
+                true
+            }
+        }
+    }
+
+    fn flush(&mut self, chips: &mut ChipSet) {
+        for write in self.writes.drain(..) {
+            if let Err(e) = chips.write_port(&write) {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    /// Flush and answer all the senders. Writes are coalesced, so an error is reported
+    /// to everyone who's write was sent together with the failed one.
+    fn finish(&mut self, chips: &mut ChipSet) {
+        self.flush(chips);
+        let result = self.error.take().map_or(Ok(()), Err);
+        for reply in self.replies.drain(..) {
+            let _ = reply.send(result);
+        }
+    }
 }
 
 /// Main thread for controlling MCP23S17.
-fn controller_thread(rx: Receiver<PinChangeRequest>, mcp23s17: Mcp23s17) {
-    let pins: [pin::OutputPin; 8] = core::array::from_fn(|i| {
-        mcp23s17
-            .get(Port::GpioA, i as u8)
-            .unwrap()
-            .into_output_pin_low()
-            .unwrap()
-    });
-
-    // TODO clean loop exit
-    loop {
-        let msg = rx.recv().unwrap();
-        let pin_num = msg.pin_num as usize;
-
-        if msg.high {
-            pins[pin_num].set_high().unwrap();
-        } else {
-            pins[pin_num].set_low().unwrap();
+///
+/// Writes which arrive while the previous ones are processed are coalesced,
+/// so pins of different users sharing a port are changed in one port write.
+/// Exits once every sender is dropped or on [`PinRequest::Shutdown`].
+fn controller_thread(rx: Receiver<PinRequest>, mut chips: ChipSet) {
+    let mut pending = PendingWrites::default();
+
+    while let Ok(request) = rx.recv() {
+        let mut msg = Some(request);
+        while let Some(request) = msg {
+            match request {
+                PinRequest::Write { writes, reply } => {
+                    for write in writes {
+                        if !pending.try_merge(&write) {
+                            pending.flush(&mut chips);
+                            pending.try_merge(&write);
+                        }
+                    }
+                    pending.replies.push(reply);
+                }
+                PinRequest::Read { pin, reply } => {
+                    pending.finish(&mut chips);
+                    let _ = reply.send(chips.read_pin(pin));
+                }
+                PinRequest::Shutdown { reply } => {
+                    pending.finish(&mut chips);
+                    chips.all_low();
+                    drop(reply);
+                    return;
+                }
+            }
+            msg = rx.try_recv().ok();
+        }
+        pending.finish(&mut chips);
+    }
+}
+
+/// Handle for stopping the controller thread from e.g. a panic hook, see
+/// [`Mcp23s17Controller::shutdown_handle`].
+pub struct ShutdownHandle {
+    pin_req_tx: Mutex<Sender<PinRequest>>,
+}
+
+impl ShutdownHandle {
+    /// Drive all the outputs low and stop the controller thread. Blocks for at most
+    /// [`SHUTDOWN_TIMEOUT`], does nothing if the thread has already exited.
+    pub fn shutdown(&self) {
+        let (reply_tx, reply_rx) = mpsc::channel::<()>();
+        let sent = self
+            .pin_req_tx
+            .lock()
+            .map(|tx| tx.send(PinRequest::Shutdown { reply: reply_tx }).is_ok())
+            .unwrap_or(false);
+        if sent {
+            // Reply sender is dropped once outputs are low.
+            let _ = reply_rx.recv_timeout(SHUTDOWN_TIMEOUT);
         }
     }
 }
@@ -97,36 +761,116 @@ impl Default for Mcp23s17Controller {
 }
 
 impl Mcp23s17Controller {
-    /// Create a new MCP23S17 controller instance.
+    /// Create a new MCP23S17 controller instance for a single chip on SPI0 CS0.
     pub fn new() -> Self {
-        let (pin_req_tx, rx) = mpsc::channel();
+        Self::from_config(&Mcp23s17Config::default()).expect("Failed to create MCP23S17")
+    }
 
-        let _thread_handle = thread::spawn(move || {
-            // Hardcoded values
-            let mcp23s17 = Mcp23s17::new(
-                HardwareAddress::new(0).expect("Invalid hardware address"),
-                SpiBus::Spi0,
-                ChipSelect::Cs0,
-                100_000,
-                SpiMode::Mode0,
-            )
-            .expect("Failed to create MCP23S17");
-
-            controller_thread(rx, mcp23s17);
+    /// Create a new MCP23S17 controller instance for chips described in `config`.
+    pub fn from_config(config: &Mcp23s17Config) -> Result<Self> {
+        config.validate()?;
+        let (pin_req_tx, rx) = mpsc::channel();
+        let (init_tx, init_rx) = mpsc::channel();
+        let status = Arc::new(ControllerStatus {
+            health: SharedState::new(ControllerHealth::Ok),
+            spi_errors: AtomicU32::new(0),
         });
 
-        Self {
+        let thread_config = config.clone();
+        let thread_status = status.clone();
+        thread::Builder::new()
+            .name(THREAD_NAME.to_string())
+            .spawn(move || {
+                // Chips aren't `Send`, so they are opened right in the controller thread.
+                match ChipSet::open(&thread_config, thread_status) {
+                    Ok(chips) => {
+                        let _ = init_tx.send(Ok(()));
+                        controller_thread(rx, chips);
+                    }
+                    Err(e) => {
+                        let _ = init_tx.send(Err(e));
+                    }
+                }
+            })?;
+        init_rx
+            .recv()
+            .map_err(|_e| format_err!("MCP23S17 thread died"))??;
+
+        Ok(Self {
+            config: config.clone(),
             pin_req_tx: pin_req_tx.into(),
+            status,
+        })
+    }
+
+    pub fn health(&self) -> ControllerHealth {
+        self.status.health.get_state()
+    }
+
+    /// Amount of failed SPI transfers, including retried ones.
+    pub fn spi_error_count(&self) -> u32 {
+        self.status.spi_errors.load(Ordering::Relaxed)
+    }
+
+    /// Drive all the outputs low and stop the controller thread,
+    /// every pin operation fails with [`PinError::ControllerStopped`] afterwards.
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    /// Returns [`ShutdownHandle`], which keeps the controller thread alive like a pin does.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            pin_req_tx: Mutex::new(self.pin_req_tx.lock().unwrap().clone()),
         }
     }
 
-    /// Returns [`VirtualPin`] for MPC23S7.
-    /// * `pin_num` - pin number (0 to 7) (on GPIOA)
-    /// TODO: add support for pins from 0 to 16
+    /// Returns [`VirtualPin`] on the first configured chip.
+    /// * `pin_num` - pin number (0 to 7 on GPIOA, 8 to 15 on GPIOB)
     pub fn output_pin(&self, pin_num: u8) -> VirtualPin {
-        assert!((0..8).contains(&pin_num));
+        self.output_pin_at(PinAddress::new(self.config.chips[0].address, pin_num))
+    }
+
+    /// Returns [`VirtualPin`] for pin `address`, which must be configured as an output.
+    pub fn output_pin_at(&self, address: PinAddress) -> VirtualPin {
+        assert_eq!(
+            self.config.is_input(address),
+            Some(false),
+            "{address:?} is not an output pin"
+        );
         VirtualPin {
-            pin_num,
+            pin: address,
+            pin_req_tx: self.pin_req_tx.lock().unwrap().clone(),
+        }
+    }
+
+    /// Returns [`VirtualInputPin`] for pin `address`, which must be configured as an input.
+    pub fn input_pin_at(&self, address: PinAddress) -> VirtualInputPin {
+        assert_eq!(
+            self.config.is_input(address),
+            Some(true),
+            "{address:?} is not an input pin"
+        );
+        VirtualInputPin {
+            pin: address,
+            pin_req_tx: self.pin_req_tx.lock().unwrap().clone(),
+        }
+    }
+
+    /// Returns [`VirtualPort`] for `port` of a chip with hardware address `chip`.
+    pub fn port(&self, chip: u8, port: Port) -> VirtualPort {
+        let chip_config = self.config.chip(chip).expect("configured chip");
+        let offset = match port {
+            Port::GpioA => 0,
+            Port::GpioB => 8,
+        };
+        let outputs = (0..8)
+            .filter(|bit| !chip_config.input_pins.contains(&(bit + offset)))
+            .fold(0u8, |mask, bit| mask | (1 << bit));
+        VirtualPort {
+            chip,
+            port,
+            outputs,
             pin_req_tx: self.pin_req_tx.lock().unwrap().clone(),
         }
     }
@@ -134,10 +878,261 @@ impl Mcp23s17Controller {
     pub fn step_motor_pins(&self, pin_numbers: [u8; 4]) -> [VirtualPin; 4] {
         core::array::from_fn(|i| self.output_pin(pin_numbers[i]))
     }
+
+    pub fn step_motor_pins_at(&self, pins: [PinAddress; 4]) -> [VirtualPin; 4] {
+        core::array::from_fn(|i| self.output_pin_at(pins[i]))
+    }
 }
 
-impl Drop for Mcp23s17Controller {
-    fn drop(&mut self) {
-        //TODO
+#[cfg(test)]
+mod tests {
+    use super::super::mcp23s17_mock::{self, MockBus};
+    use super::*;
+    use std::time::Instant;
+
+    fn write(chip: u8, port: Port, mask: u8, values: u8) -> PortWrite {
+        PortWrite {
+            chip,
+            port,
+            mask,
+            values,
+        }
+    }
+
+    fn pending(writes: &PendingWrites) -> Vec<(u8, Port, u8, u8)> {
+        writes
+            .writes
+            .iter()
+            .map(|w| (w.chip, w.port, w.mask, w.values))
+            .collect()
+    }
+
+    #[test]
+    fn masks_merge_per_port() {
+        let mut writes = PendingWrites::default();
+        assert!(writes.try_merge(&PortWrite::pin(PinAddress::new(0, 0), true)));
+        assert!(writes.try_merge(&PortWrite::pin(PinAddress::new(0, 3), false)));
+        assert!(writes.try_merge(&PortWrite::pin(PinAddress::new(0, 9), true)));
+        assert!(writes.try_merge(&PortWrite::pin(PinAddress::new(1, 0), true)));
+        assert!(writes.try_merge(&write(0, Port::GpioA, 0b1010_0000, 0b1000_0000)));
+        assert_eq!(
+            pending(&writes),
+            vec![
+                (0, Port::GpioA, 0b1010_1001, 0b1000_0001),
+                (0, Port::GpioB, 0b0000_0010, 0b0000_0010),
+                (1, Port::GpioA, 0b0000_0001, 0b0000_0001),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_pending_write_is_not_merged() {
+        let mut writes = PendingWrites::default();
+        assert!(writes.try_merge(&write(0, Port::GpioA, 0b0011, 0b0001)));
+        assert!(!writes.try_merge(&write(0, Port::GpioA, 0b0110, 0b0110)));
+        assert_eq!(pending(&writes), vec![(0, Port::GpioA, 0b0011, 0b0001)]);
+    }
+
+    #[test]
+    fn last_write_wins_per_bit() {
+        let mut merged = write(0, Port::GpioA, 0b0011, 0b0001);
+        merged.merge(&write(0, Port::GpioA, 0b0110, 0b0010));
+        assert_eq!((merged.mask, merged.values), (0b0111, 0b0011));
+
+        let mut latches = Latches::new();
+        latches.apply(&write(0, Port::GpioA, 0b1111, 0b0101));
+        latches.apply(&write(0, Port::GpioA, 0b0011, 0b0010));
+        latches.apply(&write(0, Port::GpioB, 0b1000_0000, 0b1111_1111));
+        assert_eq!(latches.latch, [0b0110, 0b1000_0000]);
+    }
+
+    #[test]
+    fn only_changed_ports_are_written() {
+        let mut latches = Latches::new();
+        let mut olat = Vec::new();
+        latches.apply(&write(0, Port::GpioB, 0b0001, 0b0001));
+        latches
+            .sync(|port, value| {
+                olat.push((port, value));
+                Ok(())
+            })
+            .unwrap();
+        latches
+            .sync(|port, value| {
+                olat.push((port, value));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(olat, vec![(Port::GpioB, 0b0001)]);
+        assert_eq!(latches.written, [Some(0), Some(0b0001)]);
+    }
+
+    #[test]
+    fn failed_write_is_written_again_on_next_sync() {
+        let mut latches = Latches::new();
+        latches.apply(&write(0, Port::GpioA, 0b0001, 0b0001));
+        assert!(latches
+            .sync(|_port, _value| Err(format_err!("SPI failed")))
+            .is_err());
+        assert_eq!(latches.written, [None, Some(0)]);
+
+        // Setting the pin back to what the chip had before still has to be written,
+        // the failed transfer may have changed it.
+        latches.apply(&write(0, Port::GpioA, 0b0001, 0));
+        let mut olat = Vec::new();
+        latches
+            .sync(|port, value| {
+                olat.push((port, value));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(olat, vec![(Port::GpioA, 0)]);
+        assert_eq!(latches.written, [Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn invalidated_latches_are_all_written() {
+        let mut latches = Latches::new();
+        latches.apply(&write(0, Port::GpioA, 0b0100, 0b0100));
+        latches.invalidate();
+        let mut olat = Vec::new();
+        latches
+            .sync(|port, value| {
+                olat.push((port, value));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(olat, vec![(Port::GpioA, 0b0100), (Port::GpioB, 0)]);
+    }
+
+    /// Single chip on chip select line `cs`, which no other test uses.
+    fn config_on(cs: u8) -> Mcp23s17Config {
+        Mcp23s17Config {
+            chip_select: cs,
+            ..Default::default()
+        }
+    }
+
+    fn mock_bus(config: &Mcp23s17Config) -> Arc<Mutex<MockBus>> {
+        mcp23s17_mock::bus(
+            spi_bus(config.spi_bus).unwrap(),
+            chip_select(config.chip_select).unwrap(),
+        )
+    }
+
+    fn olat(bus: &Mutex<MockBus>, port: Port) -> u8 {
+        bus.lock().unwrap().registers[0][olat_register(port) as usize]
+    }
+
+    fn open(config: &Mcp23s17Config) -> ChipSet {
+        let status = Arc::new(ControllerStatus {
+            health: SharedState::new(ControllerHealth::Ok),
+            spi_errors: AtomicU32::new(0),
+        });
+        ChipSet::open(config, status).unwrap()
+    }
+
+    fn pin_write(pin: u8, high: bool) -> PortWrite {
+        PortWrite::pin(PinAddress::new(0, pin), high)
+    }
+
+    #[test]
+    fn failed_transfer_is_retried() {
+        let config = config_on(1);
+        let bus = mock_bus(&config);
+        let mut chips = open(&config);
+        bus.lock().unwrap().failing_transfers = 1;
+
+        assert_eq!(chips.write_port(&pin_write(0, true)), Ok(()));
+        assert_eq!(olat(&bus, Port::GpioA), 0b0001);
+        assert_eq!(bus.lock().unwrap().opened, 1);
+        assert_eq!(chips.status.spi_errors.load(Ordering::Relaxed), 1);
+        assert_eq!(chips.status.health.get_state(), ControllerHealth::Ok);
+    }
+
+    #[test]
+    fn chip_is_reopened_and_rewritten_after_retries() {
+        let config = config_on(2);
+        let bus = mock_bus(&config);
+        let mut chips = open(&config);
+        assert_eq!(chips.write_port(&pin_write(9, true)), Ok(()));
+
+        // Power cycled chip fails until it's set up again.
+        {
+            let mut bus = bus.lock().unwrap();
+            bus.registers[0] = [0; 0x16];
+            bus.failing_transfers = SPI_RETRIES;
+        }
+        assert_eq!(chips.write_port(&pin_write(0, true)), Ok(()));
+        assert_eq!(bus.lock().unwrap().opened, 2);
+        assert_eq!(olat(&bus, Port::GpioA), 0b0001);
+        assert_eq!(olat(&bus, Port::GpioB), 0b0010);
+        assert_eq!(chips.status.spi_errors.load(Ordering::Relaxed), SPI_RETRIES);
+        assert_eq!(chips.status.health.get_state(), ControllerHealth::Ok);
+    }
+
+    #[test]
+    fn health_is_failed_until_a_transfer_succeeds() {
+        let config = config_on(3);
+        let bus = mock_bus(&config);
+        let mut chips = open(&config);
+        bus.lock().unwrap().failing_transfers = SPI_RETRIES + 1;
+
+        assert_eq!(
+            chips.write_port(&pin_write(0, true)),
+            Err(PinError::Spi { chip: 0 })
+        );
+        assert_eq!(chips.status.health.get_state(), ControllerHealth::Failed);
+        assert_eq!(
+            chips.status.spi_errors.load(Ordering::Relaxed),
+            SPI_RETRIES + 1
+        );
+
+        assert_eq!(chips.write_port(&pin_write(1, true)), Ok(()));
+        assert_eq!(chips.status.health.get_state(), ControllerHealth::Ok);
+        assert_eq!(olat(&bus, Port::GpioA), 0b0011);
+    }
+
+    fn await_stopped(status: &ControllerStatus) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while status.health.get_state() != ControllerHealth::Stopped {
+            assert!(Instant::now() < deadline, "controller thread didn't stop");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn controller_exits_when_last_pin_is_dropped() {
+        let config = config_on(4);
+        let bus = mock_bus(&config);
+        let controller = Mcp23s17Controller::from_config(&config).unwrap();
+        let status = controller.status.clone();
+        let mut pin = controller.output_pin(0);
+        drop(controller);
+
+        assert_eq!(pin.set_high(), Ok(()));
+        assert_eq!(olat(&bus, Port::GpioA), 0b0001);
+        assert_eq!(status.health.get_state(), ControllerHealth::Ok);
+
+        drop(pin);
+        await_stopped(&status);
+        assert_eq!(olat(&bus, Port::GpioA), 0);
+    }
+
+    #[test]
+    fn shutdown_drives_outputs_low() {
+        let config = config_on(5);
+        let bus = mock_bus(&config);
+        let controller = Mcp23s17Controller::from_config(&config).unwrap();
+        let mut pin = controller.output_pin(0);
+        assert_eq!(pin.set_high(), Ok(()));
+        assert_eq!(controller.port(0, Port::GpioB).write(0xff, 0xa5), Ok(()));
+        assert_eq!(olat(&bus, Port::GpioB), 0xa5);
+
+        controller.shutdown();
+        assert_eq!(controller.health(), ControllerHealth::Stopped);
+        assert_eq!(olat(&bus, Port::GpioA), 0);
+        assert_eq!(olat(&bus, Port::GpioB), 0);
+        assert_eq!(pin.set_high(), Err(PinError::ControllerStopped));
     }
 }
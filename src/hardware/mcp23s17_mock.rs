@@ -1,35 +1,112 @@
-use rppal_mcp23s17::{ChipSelect, HardwareAddress, Port, SpiBus, SpiMode};
+use lazy_static::lazy_static;
+use rppal_mcp23s17::{ChipSelect, HardwareAddress, Port, RegisterAddress, SpiBus, SpiMode};
+
+use std::sync::{Arc, Mutex};
 
 pub type Mcp23s17 = Mcp23s17Mock;
-pub struct Mcp23s17Mock {}
+
+/// Chips sharing a chip select line, kept across [`Mcp23s17Mock::new`] like real hardware.
+#[derive(Default)]
+pub struct MockBus {
+    /// Registers of every chip, indexed by hardware address.
+    pub registers: [[u8; 0x16]; 8],
+    /// Amount of the next transfers which fail.
+    pub failing_transfers: u32,
+    /// Times a chip on the line was opened.
+    pub opened: u32,
+}
+
+impl MockBus {
+    /// Take one of `failing_transfers`, if any are left.
+    fn transfer_fails(&mut self) -> bool {
+        let fails = self.failing_transfers > 0;
+        self.failing_transfers = self.failing_transfers.saturating_sub(1);
+        fails
+    }
+}
+
+type Line = (SpiBus, ChipSelect);
+
+lazy_static! {
+    static ref BUSES: Mutex<Vec<(Line, Arc<Mutex<MockBus>>)>> = Mutex::new(Vec::new());
+}
+
+/// Chips on `chip_select` of `spi_bus`.
+pub fn bus(spi_bus: SpiBus, chip_select: ChipSelect) -> Arc<Mutex<MockBus>> {
+    let mut buses = BUSES.lock().unwrap();
+    let line = (spi_bus, chip_select);
+    match buses.iter().find(|(l, _)| *l == line) {
+        Some((_, bus)) => bus.clone(),
+        None => {
+            let bus = Arc::new(Mutex::new(MockBus::default()));
+            buses.push((line, bus.clone()));
+            bus
+        }
+    }
+}
+
+pub struct Mcp23s17Mock {
+    address: usize,
+    bus: Arc<Mutex<MockBus>>,
+}
 
 impl Mcp23s17Mock {
     pub fn new(
-        _: HardwareAddress,
-        _: SpiBus,
-        _: ChipSelect,
+        address: HardwareAddress,
+        spi_bus: SpiBus,
+        chip_select: ChipSelect,
         _: u32,
         _: SpiMode,
     ) -> Result<Self, ()> {
-        Ok(Mcp23s17Mock {})
+        let bus = bus(spi_bus, chip_select);
+        bus.lock().unwrap().opened += 1;
+        Ok(Mcp23s17Mock {
+            address: u8::from(address) as usize,
+            bus,
+        })
     }
 
-    pub fn get(&self, _: Port, _: u8) -> Result<pin::OutputPin, ()> {
-        Ok(pin::OutputPin {})
+    pub fn get(&self, _: Port, _: u8) -> Result<pin::Pin, ()> {
+        Ok(pin::Pin {})
+    }
+
+    pub fn write(&self, register: RegisterAddress, data: u8) -> Result<(), ()> {
+        let mut bus = self.bus.lock().unwrap();
+        if bus.transfer_fails() {
+            return Err(());
+        }
+        bus.registers[self.address][register as usize] = data;
+        Ok(())
+    }
+
+    pub fn set_bits(&self, register: RegisterAddress, data: u8) -> Result<(), ()> {
+        let mut bus = self.bus.lock().unwrap();
+        if bus.transfer_fails() {
+            return Err(());
+        }
+        bus.registers[self.address][register as usize] |= data;
+        Ok(())
     }
 }
 
 pub mod pin {
-    pub struct OutputPin {}
-    impl OutputPin {
-        pub fn set_low(&self) -> Result<(), ()> {
-            Ok(())
+    pub struct Pin {}
+    impl Pin {
+        pub fn into_output_pin_low(self) -> Result<OutputPin, ()> {
+            Ok(OutputPin {})
         }
-        pub fn set_high(&self) -> Result<(), ()> {
-            Ok(())
+        pub fn into_pullup_input_pin(self) -> Result<InputPin, ()> {
+            Ok(InputPin {})
         }
-        pub fn into_output_pin_low(self) -> Result<Self, ()> {
-            Ok(self)
+    }
+
+    pub struct OutputPin {}
+
+    /// Pulled-up input with nothing connected, always high.
+    pub struct InputPin {}
+    impl InputPin {
+        pub fn is_high(&self) -> Result<bool, ()> {
+            Ok(true)
         }
     }
 }
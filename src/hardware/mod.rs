@@ -6,11 +6,16 @@ pub mod distance;
 //pub use distance_mock as distance;
 
 pub mod mcp23s17;
-#[cfg(feature = "mock_hardware")]
+#[cfg(any(test, feature = "mock_hardware"))]
 mod mcp23s17_mock;
 
+pub mod accel_calibration;
+pub mod backlash_calibration;
+pub mod imu_recording;
 pub mod motor;
 pub mod mpu;
+pub mod mpu_mock;
+pub mod stall_detection;
 
 mod hardcoded_hardware;
 pub use hardcoded_hardware::*;
@@ -1,19 +1,23 @@
 //! Managing 4-phase unipolar stepper motor.
 //! # Example
-//! ```
+//! ```ignore
 //! let motor = StepMotor::new(pins);
 //! let step_delay_ms: u32 = 5;
-//! let controller = StepMotorController::new(motor, step_delay_ms);
+//! let controller = StepMotorController::new("yaw", motor, step_delay_ms);
 //!
 //! controller.set_pos(100);
 //! ```
 
-use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::mcp23s17::*;
+use crate::metrics::Histogram;
+use anyhow::{ensure, format_err};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info_span, warn};
 
 /// Current phase of a stepper motor.
 #[derive(Clone, Copy, Debug)]
@@ -101,7 +105,7 @@ pub struct StepMotor<T: OutputPin> {
 }
 
 /// Motor phase shift direction.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StepDirection {
     Forward = 1,
     Nothing = 0,
@@ -119,14 +123,13 @@ impl<T: OutputPin> StepMotor<T> {
         }
     }
 
-    fn set_pins(&mut self, levels: &[bool; 4]) {
-        for (level, pin) in levels.iter().zip(self.pins.iter_mut()) {
-            pin.write(*level);
-        }
+    /// Set all the coils at once, so motor doesn't pass through intermediate states.
+    fn set_pins(&mut self, levels: &[bool; 4]) -> Result<(), PinError> {
+        T::write_all(&mut self.pins, levels)
     }
 
     /// Make stepper motor go a single full-step phase in chosen direction.
-    pub fn full_step(&mut self, dir: StepDirection) {
+    pub fn full_step(&mut self, dir: StepDirection) -> Result<(), PinError> {
         match dir {
             StepDirection::Forward => {
                 self.state.next_fullstep();
@@ -138,12 +141,13 @@ impl<T: OutputPin> StepMotor<T> {
                 self.state.init_phase();
             }
         }
-        self.set_pins(self.state.fullstep_pins());
+        self.set_pins(self.state.fullstep_pins())?;
         self.coils_powered = true;
+        Ok(())
     }
 
     /// Make stepper motor go a single half-step phase in chosen direction.
-    pub fn half_step(&mut self, dir: StepDirection) {
+    pub fn half_step(&mut self, dir: StepDirection) -> Result<(), PinError> {
         match dir {
             StepDirection::Forward => {
                 self.state.next_halfstep();
@@ -155,32 +159,241 @@ impl<T: OutputPin> StepMotor<T> {
                 self.state.init_phase();
             }
         }
-        self.set_pins(self.state.halfstep_pins());
+        self.set_pins(self.state.halfstep_pins())?;
         self.coils_powered = true;
+        Ok(())
     }
 
     /// Disables all the coils on the motor
-    pub fn disable_power(&mut self) {
+    pub fn disable_power(&mut self) -> Result<(), PinError> {
         self.coils_powered = false;
         self.set_pins(&[false, false, false, false])
     }
 
     /// Enables coils according to last used [`MotorPhase`]
-    pub fn enable_power(&mut self) {
+    pub fn enable_power(&mut self) -> Result<(), PinError> {
         self.coils_powered = true;
-        self.set_pins(self.state.fullstep_pins());
+        self.set_pins(self.state.fullstep_pins())
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.coils_powered
     }
 }
 
 impl<T: OutputPin> Drop for StepMotor<T> {
     fn drop(&mut self) {
-        for i in 0..4 {
-            self.pins[i].set_low();
+        // Pins controller may be stopped already, with all the outputs low.
+        let _ = self.disable_power();
+    }
+}
+
+/// What to do with motor coils once target position is reached.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum HoldPolicy {
+    /// Power coils down right away, motor can be moved by external forces.
+    #[default]
+    Release,
+    /// Keep coils powered untill the next move.
+    Hold,
+    /// Keep coils powered for `ms` milliseconds, then release.
+    HoldFor { ms: u64 },
+    /// Keep coils powered only `duty` (0 to 1) part of every `period_ms`,
+    /// for lower holding torque and heating.
+    Pwm { duty: f32, period_ms: u32 },
+}
+
+/// Limit on how long coils may be energised, as average over time.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ThermalBudget {
+    /// Highest allowed average part of time (0 to 1) coils are energised.
+    pub max_duty: f32,
+    /// Time constant of the average in seconds, roughly how long it takes the motor to cool down.
+    pub time_constant_s: f32,
+}
+
+impl Default for ThermalBudget {
+    fn default() -> Self {
+        ThermalBudget {
+            max_duty: 0.5,
+            time_constant_s: 120.0,
+        }
+    }
+}
+
+/// How gearbox backlash is compensated, see [`MotorConfig::backlash_steps`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BacklashMode {
+    /// Make extra steps on direction reversal, without changing the position.
+    #[default]
+    TakeUp,
+    /// Always reach the target moving forward, overshooting by backlash when coming from above.
+    ApproachForward,
+    /// Always reach the target moving backward, overshooting by backlash when coming from below.
+    ApproachBackward,
+}
+
+/// Per-axis motor settings.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct MotorConfig {
+    /// MCP23S17 outputs driving the coils, in phase order. Axis default if missing.
+    pub pins: Option<[PinAddress; 4]>,
+    /// Delay between steps at full speed, axis default if missing.
+    pub step_delay_ms: Option<u32>,
+    pub hold: HoldPolicy,
+    /// Holding is not started, or stopped, once this is exceeded. Moves are never limited.
+    pub thermal_budget: ThermalBudget,
+    /// Steps the motor turns after direction reversal before the axis starts moving.
+    pub backlash_steps: u32,
+    pub backlash_mode: BacklashMode,
+    /// Speed ramp at the start and end of every move, steps/s². 0 moves at full speed
+    /// right away, otherwise at least [`MIN_ACCELERATION`].
+    pub acceleration: f32,
+}
+
+/// Slowest speed ramp, steps/s². Anything slower takes minutes per step and hours to stop.
+pub const MIN_ACCELERATION: f32 = 1.0;
+
+impl MotorConfig {
+    /// Check for out of range tuning values, pins are checked by [`crate::config::Config`].
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.step_delay_ms != Some(0),
+            "step_delay_ms must be at least 1"
+        );
+        ensure!(
+            self.acceleration == 0.0
+                || (self.acceleration.is_finite() && self.acceleration >= MIN_ACCELERATION),
+            "acceleration must be 0 or at least {MIN_ACCELERATION} steps/s²"
+        );
+        let budget = &self.thermal_budget;
+        ensure!(
+            (0.0..=1.0).contains(&budget.max_duty),
+            "thermal_budget.max_duty must be 0 to 1"
+        );
+        ensure!(
+            budget.time_constant_s > 0.0,
+            "thermal_budget.time_constant_s must be positive"
+        );
+        if let HoldPolicy::Pwm { duty, period_ms } = self.hold {
+            ensure!((0.0..=1.0).contains(&duty), "hold.duty must be 0 to 1");
+            ensure!(period_ms > 0, "hold.period_ms must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Gearbox backlash state of a motor, see [`BacklashMode`].
+#[derive(Default)]
+struct Backlash {
+    /// Steps turned backward since the gear was last pushed forward, 0 to backlash.
+    /// `None` untill the first step, as it's unknown after start.
+    slack: Option<u32>,
+    /// Target and the overshoot position it's approached from.
+    approach: Option<(i32, i32)>,
+}
+
+impl Backlash {
+    /// Position to move to for reaching `target` from `current`.
+    fn goal(&mut self, current: i32, target: i32, config: &MotorConfig) -> i32 {
+        if let Some((approach_target, overshoot)) = self.approach {
+            if approach_target == target && current != overshoot {
+                return overshoot;
+            }
+            self.approach = None;
+        }
+
+        let backlash = config.backlash_steps as i32;
+        let overshoot = match config.backlash_mode {
+            BacklashMode::ApproachForward if current > target => target - backlash,
+            BacklashMode::ApproachBackward if current < target => target + backlash,
+            _ => return target,
+        };
+        if backlash > 0 {
+            self.approach = Some((target, overshoot));
+            overshoot
+        } else {
+            target
+        }
+    }
+
+    /// Account for a motor step in `dir`, returns `false` if it only took up the slack.
+    fn step(&mut self, dir: StepDirection, config: &MotorConfig) -> bool {
+        if config.backlash_mode != BacklashMode::TakeUp || config.backlash_steps == 0 {
+            self.slack = None;
+            return true;
+        }
+        let backlash = config.backlash_steps;
+        let slack = self.slack.get_or_insert(match dir {
+            StepDirection::Backward => backlash,
+            _ => 0,
+        });
+        *slack = (*slack).min(backlash);
+        match dir {
+            StepDirection::Forward if *slack > 0 => {
+                *slack -= 1;
+                false
+            }
+            StepDirection::Backward if *slack < backlash => {
+                *slack += 1;
+                false
+            }
+            _ => true,
         }
     }
 }
 
+/// Estimate of motor heating, exponential average of energised part of time.
+struct CoilHeat {
+    /// Average part of time coils were energised.
+    duty: f32,
+    /// Part of time coils are energised since `last_update`.
+    level: f32,
+    last_update: Instant,
+    /// `duty` was over budget on the last [`CoilHeat::check_budget`].
+    over_budget: bool,
+}
+
+impl CoilHeat {
+    fn new() -> Self {
+        CoilHeat {
+            duty: 0.0,
+            level: 0.0,
+            last_update: Instant::now(),
+            over_budget: false,
+        }
+    }
+
+    fn update(&mut self, budget: &ThermalBudget) {
+        let now = Instant::now();
+        self.advance((now - self.last_update).as_secs_f32(), budget);
+        self.last_update = now;
+    }
+
+    /// Average in `dt` seconds more at the current level.
+    fn advance(&mut self, dt: f32, budget: &ThermalBudget) {
+        let k = 1.0 - (-dt / budget.time_constant_s.max(f32::EPSILON)).exp();
+        self.duty += (self.level - self.duty) * k;
+    }
+
+    /// Compare `duty` with the budget, returns `true` only when an overrun has just started.
+    fn check_budget(&mut self, budget: &ThermalBudget) -> bool {
+        let was_over = self.over_budget;
+        self.over_budget = self.duty > budget.max_duty;
+        self.over_budget && !was_over
+    }
+
+    fn set_level(&mut self, level: f32, budget: &ThermalBudget) {
+        self.update(budget);
+        self.level = level;
+    }
+}
+
 use std::sync::{Condvar, Mutex};
+use tokio::sync::Notify;
 
 #[derive(Default, Clone)]
 struct ControllerSharedData {
@@ -188,7 +401,19 @@ struct ControllerSharedData {
     target_pos: Arc<AtomicI32>,
     step_delay_ms: Arc<AtomicU32>,
     update_status: Arc<(Mutex<bool>, Condvar)>,
+    /// Notified along with the condvar once the target is reached, for async waiters.
+    settled: Arc<Notify>,
     kill_switch: Arc<AtomicBool>,
+    /// Error of the last pin write, `None` if it succeeded.
+    pin_error: Arc<Mutex<Option<PinError>>>,
+    config: Arc<Mutex<MotorConfig>>,
+    /// [`CoilHeat::duty`] as `f32` bits.
+    coil_duty: Arc<AtomicU32>,
+    over_thermal_budget: Arc<AtomicBool>,
+    /// Steps made, including the ones taking up backlash.
+    steps: Arc<AtomicU64>,
+    /// Time from leaving the target till reaching it again, seconds.
+    move_durations: Arc<Histogram>,
 }
 
 impl ControllerSharedData {
@@ -230,15 +455,24 @@ impl ControllerSharedData {
         let mut update = lock.lock().unwrap();
         *update = false;
         cvar.notify_all();
+        self.settled.notify_waiters();
+    }
+
+    fn is_updating(&self) -> bool {
+        *self.update_status.0.lock().unwrap()
     }
 
-    fn await_update(&self) {
+    /// Wait for an update, giving up after `timeout`. Returns `true` if there was an update.
+    ///
+    /// Update isn't cleared untill the target is reached and [`Self::notify_noupdate`] is called,
+    /// otherwise [`Self::await_noupdate`] could return before the move even started.
+    fn await_update_timeout(&self, timeout: Duration) -> bool {
         let (lock, cvar) = &*self.update_status;
-        let mut update = lock.lock().unwrap();
-        while !*update {
-            update = cvar.wait(update).unwrap();
-        }
-        *update = false;
+        let update = lock.lock().unwrap();
+        let (update, _) = cvar
+            .wait_timeout_while(update, timeout, |update| !*update)
+            .unwrap();
+        *update
     }
 
     fn await_noupdate(&self) {
@@ -258,15 +492,39 @@ impl ControllerSharedData {
         self.notify_update();
     }
 
-    fn wait_step_delay(&self) {
-        thread::sleep(Duration::from_millis(
-            self.step_delay_ms.load(Ordering::Relaxed) as u64,
-        ));
+    /// Delay after a step, longer than the step delay while speeding up `run` steps into a
+    /// move and slowing down for the `remaining` ones.
+    fn step_delay(&self, config: &MotorConfig, run: u32, remaining: u32) -> Duration {
+        let delay = Duration::from_millis(self.get_step_delay_ms() as u64);
+        if config.acceleration <= 0.0 {
+            return delay;
+        }
+        // v² = 2as, from standstill and to standstill.
+        let speed = (2.0 * config.acceleration * run.min(remaining + 1) as f32).sqrt();
+        delay.max(Duration::from_secs_f32(1.0 / speed))
     }
 
     fn is_killed(&self) -> bool {
         self.kill_switch.load(Ordering::Relaxed)
     }
+
+    fn set_pin_error(&self, pin_error: Option<PinError>) {
+        *self.pin_error.lock().unwrap() = pin_error;
+    }
+
+    fn get_pin_error(&self) -> Option<PinError> {
+        *self.pin_error.lock().unwrap()
+    }
+
+    fn get_config(&self) -> MotorConfig {
+        *self.config.lock().unwrap()
+    }
+
+    fn set_coil_heat(&self, heat: &CoilHeat) {
+        self.coil_duty.store(heat.duty.to_bits(), Ordering::Relaxed);
+        self.over_thermal_budget
+            .store(heat.over_budget, Ordering::Relaxed);
+    }
 }
 
 /// Controller for managing stepper motor asynchronously in a separate thread.
@@ -282,51 +540,179 @@ pub struct StepMotorController {
 }
 
 /// Thread for managing a stepper motor.
+///
+/// Motor is stopped at current position if a step fails, see
+/// [`StepMotorController::get_pin_error`].
 fn control_loop<T: OutputPin>(mut motor: StepMotor<T>, shared: ControllerSharedData) {
+    let mut heat = CoilHeat::new();
+    let mut backlash = Backlash::default();
+    // Steps made since the motor stood still or turned around.
+    let mut run = 0;
+    let mut last_dir = StepDirection::Nothing;
+    let mut move_start: Option<Instant> = None;
     loop {
         if shared.is_killed() {
             break;
         }
 
-        let diff = shared.get_target_pos() - shared.get_current_pos();
-        match diff.cmp(&0) {
+        let config = shared.get_config();
+        let current = shared.get_current_pos();
+        let diff = backlash.goal(current, shared.get_target_pos(), &config) - current;
+        let dir = match diff.cmp(&0) {
             // Positive integer, step forward
-            std::cmp::Ordering::Greater => {
-                motor.full_step(StepDirection::Forward);
-                shared.inc_current_pos(1);
-                shared.wait_step_delay();
-            }
+            std::cmp::Ordering::Greater => StepDirection::Forward,
             // Negative integer, step backward
-            std::cmp::Ordering::Less => {
-                motor.full_step(StepDirection::Backward);
-                shared.inc_current_pos(-1);
-                shared.wait_step_delay();
-            }
-            // Zero, do nothing
+            std::cmp::Ordering::Less => StepDirection::Backward,
+            // Zero, hold position
             std::cmp::Ordering::Equal => {
-                motor.disable_power();
+                if let Some(start) = move_start.take() {
+                    shared.move_durations.observe(start.elapsed().as_secs_f64());
+                }
+                run = 0;
+                last_dir = StepDirection::Nothing;
                 shared.notify_noupdate();
-                shared.await_update()
+                hold(&mut motor, &shared, &mut heat);
+                continue;
+            }
+        };
+
+        move_start.get_or_insert_with(Instant::now);
+        if dir != last_dir {
+            run = 0;
+            last_dir = dir;
+        }
+
+        heat.set_level(1.0, &config.thermal_budget);
+        shared.set_coil_heat(&heat);
+        match motor.full_step(dir) {
+            Ok(()) => {
+                shared.set_pin_error(None);
+                shared.steps.fetch_add(1, Ordering::Relaxed);
+                if backlash.step(dir, &config) {
+                    shared.inc_current_pos(dir as i32);
+                }
             }
+            Err(e) => step_failed(&shared, e),
         }
+        run += 1;
+        thread::sleep(shared.step_delay(&config, run, diff.unsigned_abs() - 1));
     }
 }
 
+/// Hold position according to [`HoldPolicy`] untill there is an update.
+fn hold<T: OutputPin>(
+    motor: &mut StepMotor<T>,
+    shared: &ControllerSharedData,
+    heat: &mut CoilHeat,
+) {
+    /// How often thermal budget is checked while holding.
+    const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+    /// How often heat estimate is updated while released.
+    const COOLING_INTERVAL: Duration = Duration::from_secs(1);
+
+    let start = Instant::now();
+    loop {
+        let config = shared.get_config();
+        let budget = &config.thermal_budget;
+        heat.update(budget);
+        if heat.check_budget(budget) {
+            warn!(
+                duty = heat.duty,
+                max_duty = budget.max_duty,
+                "Step motor coils energised {:.0}% of time, over {:.0}% budget, releasing",
+                heat.duty * 100.0,
+                budget.max_duty * 100.0
+            );
+        }
+        shared.set_coil_heat(heat);
+
+        let hold_time = match config.hold {
+            _ if heat.over_budget => Duration::ZERO,
+            HoldPolicy::Release => Duration::ZERO,
+            HoldPolicy::HoldFor { ms } => Duration::from_millis(ms),
+            HoldPolicy::Hold | HoldPolicy::Pwm { .. } => Duration::MAX,
+        };
+        let remaining = match hold_time.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                heat.set_level(0.0, budget);
+                if motor.is_powered() {
+                    shared.set_pin_error(motor.disable_power().err());
+                }
+                // Released untill the next move, only keeping the estimate up to date.
+                while !shared.await_update_timeout(COOLING_INTERVAL) {
+                    heat.update(budget);
+                    // Only cooling here, so this can end an overrun but not start one.
+                    heat.check_budget(budget);
+                    shared.set_coil_heat(heat);
+                }
+                return;
+            }
+        };
+
+        let updated = match config.hold {
+            HoldPolicy::Pwm { duty, period_ms } => {
+                let duty = duty.clamp(0.0, 1.0);
+                let period = Duration::from_millis(period_ms.max(1) as u64);
+                heat.set_level(duty, budget);
+                shared.set_pin_error(motor.enable_power().err());
+                if shared.await_update_timeout(period.mul_f32(duty)) {
+                    true
+                } else {
+                    shared.set_pin_error(motor.disable_power().err());
+                    shared.await_update_timeout(period.mul_f32(1.0 - duty))
+                }
+            }
+            _ => {
+                heat.set_level(1.0, budget);
+                if !motor.is_powered() {
+                    shared.set_pin_error(motor.enable_power().err());
+                }
+                shared.await_update_timeout(remaining.min(CHECK_INTERVAL))
+            }
+        };
+        if updated {
+            return;
+        }
+    }
+}
+
+fn step_failed(shared: &ControllerSharedData, error: PinError) {
+    error!(%error, pos = shared.get_current_pos(), "Step motor stopped");
+    shared.set_pin_error(Some(error));
+    shared.set_target_pos(shared.get_current_pos());
+}
+
 impl StepMotorController {
     /// Creates a new [`StepMotorController`].
+    /// * `name`: axis the motor turns, in logs of the control thread
     /// * `motor`: motor to controll
     /// * `step_delay_ms`: delay in millisecond between each step
-    pub fn from_pins<T: OutputPin + Send + 'static>(pins: [T; 4], step_delay_ms: u32) -> Self {
+    pub fn from_pins<T: OutputPin + Send + 'static>(
+        name: &'static str,
+        pins: [T; 4],
+        step_delay_ms: u32,
+    ) -> Self {
         let motor = StepMotor::new(pins);
-        Self::new(motor, step_delay_ms)
+        Self::new(name, motor, step_delay_ms)
     }
 
-    pub fn new<T: OutputPin + Send + 'static>(motor: StepMotor<T>, step_delay_ms: u32) -> Self {
+    pub fn new<T: OutputPin + Send + 'static>(
+        name: &'static str,
+        motor: StepMotor<T>,
+        step_delay_ms: u32,
+    ) -> Self {
         let shared_data: ControllerSharedData = Default::default();
         shared_data.set_step_delay_ms(step_delay_ms);
 
         let shared_clone = shared_data.clone();
-        let thread_handle = thread::spawn(move || control_loop(motor, shared_clone));
+        let thread_handle = thread::Builder::new()
+            .name(format!("{name} motor"))
+            .spawn(move || {
+                let _span = info_span!("motor", axis = name).entered();
+                control_loop(motor, shared_clone)
+            })
+            .expect("Failed to spawn motor control thread");
         let thread_handle = Some(thread_handle);
 
         StepMotorController {
@@ -363,6 +749,9 @@ impl StepMotorController {
     }
 
     /// Checks if motor is running or not.
+    ///
+    /// Might be `true` for a moment while passing the target to approach it from
+    /// the other side, see [`BacklashMode`].
     pub fn is_stopped(&self) -> bool {
         self.shared.get_current_pos() == self.shared.get_target_pos()
     }
@@ -372,6 +761,20 @@ impl StepMotorController {
         self.shared.await_noupdate();
     }
 
+    /// Same as [`Self::wait_stop`] without blocking the thread, for async code.
+    pub async fn stopped(&self) {
+        loop {
+            let notified = self.shared.settled.notified();
+            tokio::pin!(notified);
+            // Registered before checking, so a notification in between isn't missed.
+            notified.as_mut().enable();
+            if !self.shared.is_updating() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
     pub fn get_target_pos(&self) -> i32 {
         self.shared.get_target_pos()
     }
@@ -388,9 +791,56 @@ impl StepMotorController {
         self.shared.set_current_pos(current_pos);
     }
 
+    pub fn get_config(&self) -> MotorConfig {
+        MotorConfig {
+            step_delay_ms: Some(self.get_step_delay_ms()),
+            ..self.shared.get_config()
+        }
+    }
+
+    /// Change hold policy, thermal budget, backlash compensation and speed,
+    /// hold settings are applied from the next hold check. Pins can't be changed.
+    pub fn set_config(&self, config: MotorConfig) {
+        if let Some(step_delay_ms) = config.step_delay_ms {
+            self.set_step_delay_ms(step_delay_ms);
+        }
+        *self.shared.config.lock().unwrap() = config;
+        self.shared.notify_update();
+    }
+
+    /// Estimated average part of time (0 to 1) coils are energised, see [`ThermalBudget`].
+    pub fn get_coil_duty(&self) -> f32 {
+        f32::from_bits(self.shared.coil_duty.load(Ordering::Relaxed))
+    }
+
+    /// `true` if motor was released because it's over [`ThermalBudget`].
+    pub fn is_over_thermal_budget(&self) -> bool {
+        self.shared.over_thermal_budget.load(Ordering::Relaxed)
+    }
+
+    /// Error of the last motor pin write, `None` if it succeeded.
+    pub fn get_pin_error(&self) -> Option<PinError> {
+        self.shared.get_pin_error()
+    }
+
     /// Change target position on `delta_pos` step.
-    pub fn move_on(&self, delta_pos: i32) {
-        self.set_target_pos(self.get_target_pos() + delta_pos);
+    pub fn move_on(&self, delta_pos: i32) -> anyhow::Result<()> {
+        let target_pos = self
+            .get_target_pos()
+            .checked_add(delta_pos)
+            .ok_or_else(|| format_err!("target position out of range"))?;
+        self.set_target_pos(target_pos);
+        Ok(())
+    }
+
+    /// Steps made since start, including the ones taking up backlash.
+    pub fn get_step_count(&self) -> u64 {
+        self.shared.steps.load(Ordering::Relaxed)
+    }
+
+    /// Durations of finished moves, from standstill to standstill.
+    pub fn move_durations(&self) -> &Histogram {
+        &self.shared.move_durations
     }
 }
 
@@ -471,10 +921,232 @@ impl ControllerMock {
         }
     }
 
-    pub fn move_on(&self, delta_pos: i32) {
-        self.tgt_pos.store(
-            self.cur_pos.load(Ordering::Relaxed) + delta_pos,
-            Ordering::Relaxed,
+    pub fn move_on(&self, delta_pos: i32) -> anyhow::Result<()> {
+        let target_pos = self
+            .cur_pos
+            .load(Ordering::Relaxed)
+            .checked_add(delta_pos)
+            .ok_or_else(|| format_err!("target position out of range"))?;
+        self.tgt_pos.store(target_pos, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGET: ThermalBudget = ThermalBudget {
+        max_duty: 0.5,
+        time_constant_s: 120.0,
+    };
+    /// Seconds between budget checks.
+    const TICK: f32 = 0.1;
+
+    /// Keep coils at `level` for `seconds`, returns times (from the start) overruns started.
+    fn energise(heat: &mut CoilHeat, level: f32, seconds: f32) -> Vec<f32> {
+        heat.level = level;
+        let ticks = (seconds / TICK).round() as u32;
+        (1..=ticks)
+            .filter_map(|tick| {
+                heat.advance(TICK, &BUDGET);
+                heat.check_budget(&BUDGET).then_some(tick as f32 * TICK)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn budget_is_exhausted_at_expected_time() {
+        let mut heat = CoilHeat::new();
+        let overruns = energise(&mut heat, 1.0, 200.0);
+        // Duty of a cold motor is 1 - e^(-t / time constant).
+        let expected = -BUDGET.time_constant_s * (1.0 - BUDGET.max_duty).ln();
+        assert_eq!(overruns.len(), 1, "{:?}", overruns);
+        assert!((overruns[0] - expected).abs() <= TICK, "{}", overruns[0]);
+    }
+
+    #[test]
+    fn holding_at_budget_never_overruns() {
+        let mut heat = CoilHeat::new();
+        assert!(energise(&mut heat, BUDGET.max_duty, 1000.0).is_empty());
+        assert!(heat.duty <= BUDGET.max_duty);
+    }
+
+    #[test]
+    fn cools_down_below_budget() {
+        let mut heat = CoilHeat::new();
+        energise(&mut heat, 1.0, 100.0);
+        assert!(heat.over_budget);
+        let hot = heat.duty;
+
+        heat.level = 0.0;
+        let mut cooled = None;
+        for tick in 1..=600 {
+            heat.advance(TICK, &BUDGET);
+            heat.check_budget(&BUDGET);
+            if !heat.over_budget {
+                cooled = Some(tick as f32 * TICK);
+                break;
+            }
+        }
+        let expected = BUDGET.time_constant_s * (hot / BUDGET.max_duty).ln();
+        let cooled = cooled.expect("cooled down");
+        assert!((cooled - expected).abs() <= TICK, "{}", cooled);
+    }
+
+    #[test]
+    fn warns_once_per_overrun() {
+        let mut heat = CoilHeat::new();
+        assert_eq!(energise(&mut heat, 1.0, 100.0).len(), 1);
+        assert!(energise(&mut heat, 1.0, 100.0).is_empty());
+        assert!(energise(&mut heat, 0.0, 100.0).is_empty());
+        assert!(!heat.over_budget);
+        assert_eq!(energise(&mut heat, 1.0, 100.0).len(), 1);
+    }
+
+    fn backlash_config(mode: BacklashMode) -> MotorConfig {
+        MotorConfig {
+            backlash_steps: 5,
+            backlash_mode: mode,
+            ..Default::default()
+        }
+    }
+
+    /// Step like [`control_loop`] does from `*pos` to `target`, returns the position after
+    /// every motor step.
+    fn move_to(
+        backlash: &mut Backlash,
+        config: &MotorConfig,
+        pos: &mut i32,
+        target: i32,
+    ) -> Vec<i32> {
+        let mut positions = Vec::new();
+        loop {
+            let dir = match (backlash.goal(*pos, target, config) - *pos).cmp(&0) {
+                std::cmp::Ordering::Greater => StepDirection::Forward,
+                std::cmp::Ordering::Less => StepDirection::Backward,
+                std::cmp::Ordering::Equal => return positions,
+            };
+            if backlash.step(dir, config) {
+                *pos += dir as i32;
+            }
+            positions.push(*pos);
+            assert!(positions.len() < 1000, "never reached {}", target);
+        }
+    }
+
+    #[test]
+    fn take_up_only_on_reversal() {
+        let config = backlash_config(BacklashMode::TakeUp);
+        let mut backlash = Backlash::default();
+        let mut pos = 0;
+
+        assert_eq!(
+            move_to(&mut backlash, &config, &mut pos, 10),
+            (1..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 13).len(), 3);
+
+        // Position stays put while the slack is taken up.
+        let positions = move_to(&mut backlash, &config, &mut pos, 10);
+        assert_eq!(positions, vec![13, 13, 13, 13, 13, 12, 11, 10]);
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 7).len(), 3);
+
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 9).len(), 5 + 2);
+        assert_eq!(pos, 9);
+    }
+
+    #[test]
+    fn first_move_takes_no_slack_up() {
+        let config = backlash_config(BacklashMode::TakeUp);
+        for target in [4, -4] {
+            let mut pos = 0;
+            let positions = move_to(&mut Backlash::default(), &config, &mut pos, target);
+            assert_eq!(positions.len(), 4);
+            assert_eq!(pos, target);
+        }
+    }
+
+    #[test]
+    fn every_step_counts_without_backlash() {
+        let config = MotorConfig::default();
+        let mut backlash = Backlash::default();
+        let mut pos = 0;
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 10).len(), 10);
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 5).len(), 5);
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 6).len(), 1);
+    }
+
+    #[test]
+    fn approach_forward_overshoots_from_above_only() {
+        let config = backlash_config(BacklashMode::ApproachForward);
+        let mut backlash = Backlash::default();
+        let mut pos = 0;
+
+        assert_eq!(
+            move_to(&mut backlash, &config, &mut pos, 10),
+            (1..=10).collect::<Vec<_>>()
+        );
+
+        let positions = move_to(&mut backlash, &config, &mut pos, 8);
+        let expected: Vec<_> = (3..=9).rev().chain(4..=8).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(pos, 8);
+
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 9), vec![9]);
+    }
+
+    #[test]
+    fn approach_backward_overshoots_from_below_only() {
+        let config = backlash_config(BacklashMode::ApproachBackward);
+        let mut backlash = Backlash::default();
+        let mut pos = 0;
+
+        let positions = move_to(&mut backlash, &config, &mut pos, 2);
+        let expected: Vec<_> = (1..=7).chain((2..=6).rev()).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(move_to(&mut backlash, &config, &mut pos, 1), vec![1]);
+        assert_eq!(pos, 1);
+    }
+
+    /// Pin of a motor with nothing connected.
+    struct NullPin;
+
+    impl OutputPin for NullPin {
+        fn write<T: Into<rppal::gpio::Level>>(&mut self, _level: T) -> Result<(), PinError> {
+            Ok(())
+        }
+    }
+
+    fn await_stopped(motor: &StepMotorController) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !motor.is_stopped() {
+            assert!(Instant::now() < deadline, "motor didn't stop");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn controller_position_excludes_take_up() {
+        let motor = StepMotorController::from_pins("test", [NullPin, NullPin, NullPin, NullPin], 1);
+        motor.set_config(backlash_config(BacklashMode::TakeUp));
+
+        motor.set_target_pos(10);
+        await_stopped(&motor);
+        assert_eq!((motor.get_current_pos(), motor.get_step_count()), (10, 10));
+
+        motor.set_target_pos(6);
+        await_stopped(&motor);
+        assert_eq!(
+            (motor.get_current_pos(), motor.get_step_count()),
+            (6, 10 + 5 + 4)
+        );
+
+        motor.set_target_pos(3);
+        await_stopped(&motor);
+        assert_eq!(
+            (motor.get_current_pos(), motor.get_step_count()),
+            (3, 19 + 3)
         );
     }
 }
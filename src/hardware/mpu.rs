@@ -1,21 +1,36 @@
 //! MPU9250 with rotation tracking.
 
-use anyhow::Result;
+use crate::metrics::MPU_LOOP_RATE;
+use anyhow::{ensure, Result};
 use linux_embedded_hal::{Delay, I2cdev};
 use mpu9250::*;
 use nalgebra::UnitQuaternion;
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use super::mpu_mock::{MockImu, MockImuConfig};
+use crate::config::Config;
+use super::imu_recording::{ImuRecorder, ImuReplay, ImuSample, TimedImuSample};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use tokio::sync::watch;
+use tracing::warn;
 
-const I2C_ADDR: &str = "/dev/i2c-1";
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+fn identity() -> [[f32; 3]; 3] {
+    IDENTITY
+}
 
 #[derive(Deserialize, Serialize, Clone, Copy)]
 pub struct MpuConfig {
     pub gyro_bias: [f32; 3],
     pub accel_bias: [f32; 3],
     pub accel_scale: [f32; 3],
+    /// Cross-axis correction applied after bias and scale.
+    #[serde(default = "identity")]
+    pub accel_misalignment: [[f32; 3]; 3],
 }
 
 impl Default for MpuConfig {
@@ -24,43 +39,229 @@ impl Default for MpuConfig {
             gyro_bias: [0.0; 3],
             accel_bias: [0.0; 3],
             accel_scale: [1.0; 3],
+            accel_misalignment: IDENTITY,
+        }
+    }
+}
+
+impl MpuConfig {
+    /// Apply calibration to raw accelerometer and gyroscope readings.
+    pub fn apply(&self, accel: [f32; 3], gyro: [f32; 3]) -> ([f32; 3], [f32; 3]) {
+        let mut gyro = gyro;
+        for (gyro, bias) in gyro.iter_mut().zip(self.gyro_bias) {
+            *gyro -= bias;
+        }
+        let mut accel = accel;
+        for ((accel, bias), scale) in accel
+            .iter_mut()
+            .zip(self.accel_bias)
+            .zip(self.accel_scale)
+        {
+            *accel -= bias;
+            *accel *= scale;
+        }
+        let m = self.accel_misalignment;
+        let accel: [f32; 3] =
+            core::array::from_fn(|i| m[i][0] * accel[0] + m[i][1] * accel[1] + m[i][2] * accel[2]);
+        (accel, gyro)
+    }
+}
+
+/// MPU9250 bus and orientation filter settings, calibration is in [`MpuConfig`].
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct ImuConfig {
+    /// I2C bus device the MPU9250 is on.
+    pub i2c_path: String,
+    /// Orientation filter updates per second.
+    pub rate_hz: u32,
+    /// Madgwick filter gain, 0 trusts only the gyroscope, 1 only the accelerometer.
+    pub filter_gain: f32,
+}
+
+impl Default for ImuConfig {
+    fn default() -> Self {
+        ImuConfig {
+            i2c_path: "/dev/i2c-1".to_string(),
+            rate_hz: 500,
+            filter_gain: 0.1,
         }
     }
 }
 
+impl ImuConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.i2c_path.is_empty(), "i2c_path must be set");
+        ensure!(
+            (1..=2000).contains(&self.rate_hz),
+            "rate_hz must be 1 to 2000"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.filter_gain),
+            "filter_gain must be 0 to 1"
+        );
+        Ok(())
+    }
+}
+
+/// Where [`Mpu`] gets its readings from.
+enum MpuBackend {
+    Hardware(Mpu9250<I2cDevice<I2cdev>, mpu9250::Marg>),
+    Replay(ImuReplay),
+    Mock(MockImu),
+}
+
 pub struct Mpu {
-    pub mpu9250: Mpu9250<I2cDevice<I2cdev>, mpu9250::Marg>,
+    backend: MpuBackend,
+    recorder: Option<ImuRecorder>,
     pub config: MpuConfig,
+    /// Settings of the [`OrientationController`] running on this MPU.
+    pub imu: ImuConfig,
 }
 
 impl Mpu {
-    /// Create new MPU9250.
-    pub fn new(config: MpuConfig) -> Self {
-        let i2c = I2cdev::new(I2C_ADDR).unwrap();
+    /// Create new MPU9250 on the I2C bus in `imu`. Simulated with `mock_hardware` feature.
+    pub fn new(config: MpuConfig, imu: ImuConfig) -> Self {
+        if cfg!(feature = "mock_hardware") {
+            return Mpu {
+                imu,
+                ..Self::mock(config, MockImuConfig::default())
+            };
+        }
+        let i2c = I2cdev::new(&imu.i2c_path)
+            .unwrap_or_else(|e| panic!("Failed to open {}: {e}", imu.i2c_path));
         let mpu9250 = Mpu9250::marg_default(i2c, &mut Delay).expect("unable to make MPU9250");
-        Mpu { mpu9250, config }
+        Mpu {
+            backend: MpuBackend::Hardware(mpu9250),
+            recorder: None,
+            config,
+            imu,
+        }
+    }
+
+    /// Create [`Mpu`] described by `config`. Uses [`MockImu`] with `mock_hardware` feature.
+    pub fn from_config(config: &Config) -> Self {
+        let mpu_config = config.imu_calibration.unwrap_or_default();
+        let imu = config.imu.clone().unwrap_or_default();
+        if cfg!(feature = "mock_hardware") {
+            Mpu {
+                imu,
+                ..Self::mock(mpu_config, config.mock_imu.clone().unwrap_or_default())
+            }
+        } else {
+            Self::new(mpu_config, imu)
+        }
+    }
+
+    /// Create [`Mpu`] with simulated readings.
+    pub fn mock(config: MpuConfig, mock_config: MockImuConfig) -> Self {
+        Mpu {
+            backend: MpuBackend::Mock(MockImu::new(mock_config)),
+            recorder: None,
+            config,
+            imu: ImuConfig::default(),
+        }
+    }
+
+    /// Create [`Mpu`] which reads samples from a recording instead of hardware.
+    pub fn replay(config: MpuConfig, replay: ImuReplay) -> Self {
+        Mpu {
+            backend: MpuBackend::Replay(replay),
+            recorder: None,
+            config,
+            imu: ImuConfig::default(),
+        }
+    }
+
+    /// How much faster than real time samples are produced.
+    pub fn time_scale(&self) -> f32 {
+        match &self.backend {
+            MpuBackend::Hardware(_) | MpuBackend::Mock(_) => 1.0,
+            MpuBackend::Replay(replay) => replay.speed(),
+        }
+    }
+
+    fn is_replay(&self) -> bool {
+        matches!(self.backend, MpuBackend::Replay(_))
+    }
+
+    /// Recording time of the sample last read from a replay, `None` for other backends.
+    pub fn replay_timestamp(&self) -> Option<Duration> {
+        match &self.backend {
+            MpuBackend::Replay(replay) => replay.last_timestamp(),
+            MpuBackend::Hardware(_) | MpuBackend::Mock(_) => None,
+        }
+    }
+
+    /// Record every sample read by [`Mpu::read_sample`] into `path`.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.recorder = Some(ImuRecorder::create(path)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<()> {
+        if let Some(mut recorder) = self.recorder.take() {
+            recorder.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Read raw accelerometer, gyroscope, magnetometer and temperature.
+    pub fn read_sample(&mut self) -> Result<ImuSample> {
+        let sample = match &mut self.backend {
+            MpuBackend::Hardware(mpu9250) => {
+                let measurements: MargMeasurements<[f32; 3]> = mpu9250
+                    .all()
+                    .map_err(|_e| anyhow::format_err!("I2C is ded"))?;
+                ImuSample {
+                    accel: measurements.accel,
+                    gyro: measurements.gyro,
+                    mag: measurements.mag,
+                    temp: measurements.temp,
+                }
+            }
+            MpuBackend::Replay(replay) => replay.next_sample()?,
+            MpuBackend::Mock(mock) => mock.read_sample(),
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&sample)?;
+        }
+        Ok(sample)
     }
 
     pub fn get_accel_gyro(&mut self) -> Result<([f32; 3], [f32; 3])> {
-        let measurements = self
-            .mpu9250
-            .all()
-            .map_err(|_e| anyhow::format_err!("I2C is ded"))?;
+        let sample = self.read_sample()?;
+        Ok(self.config.apply(sample.accel, sample.gyro))
+    }
 
-        let mut gyro: [f32; 3] = measurements.gyro;
-        for (gyro, bias) in gyro.iter_mut().zip(self.config.gyro_bias) {
-            *gyro -= bias;
+    /// Accelerometer reading without calibration applied.
+    pub fn get_raw_accel(&mut self) -> Result<[f32; 3]> {
+        match &mut self.backend {
+            MpuBackend::Hardware(mpu9250) => mpu9250
+                .accel()
+                .map_err(|_e| anyhow::format_err!("I2C is ded")),
+            MpuBackend::Replay(_) | MpuBackend::Mock(_) => Ok(self.read_sample()?.accel),
         }
-        let mut accel: [f32; 3] = measurements.accel;
-        for ((accel, bias), scale) in accel
-            .iter_mut()
-            .zip(self.config.accel_bias)
-            .zip(self.config.accel_scale)
-        {
-            *accel -= bias;
-            *accel *= scale;
+    }
+
+    /// Gyroscope reading without calibration applied.
+    pub fn get_raw_gyro(&mut self) -> Result<[f32; 3]> {
+        match &mut self.backend {
+            MpuBackend::Hardware(mpu9250) => mpu9250
+                .gyro()
+                .map_err(|_e| anyhow::format_err!("I2C is ded")),
+            MpuBackend::Replay(_) | MpuBackend::Mock(_) => Ok(self.read_sample()?.gyro),
+        }
+    }
+
+    /// Magnetometer reading.
+    pub fn get_raw_mag(&mut self) -> Result<[f32; 3]> {
+        match &mut self.backend {
+            MpuBackend::Hardware(mpu9250) => mpu9250
+                .mag()
+                .map_err(|_e| anyhow::format_err!("I2C is ded")),
+            MpuBackend::Replay(_) | MpuBackend::Mock(_) => Ok(self.read_sample()?.mag),
         }
-        Ok((accel, gyro))
     }
 }
 
@@ -69,7 +270,7 @@ pub fn get_magnetometer_data(mpu: &mut Mpu, duration: &Duration) -> Vec<[f32; 3]
     let start_time = Instant::now();
     let mut data = Vec::new();
     while start_time.elapsed() < *duration {
-        let reading = mpu.mpu9250.mag();
+        let reading = mpu.get_raw_mag();
         if let Ok(reading) = reading {
             match data.last() {
                 None => data.push(reading),
@@ -80,7 +281,7 @@ pub fn get_magnetometer_data(mpu: &mut Mpu, duration: &Duration) -> Vec<[f32; 3]
                 }
             }
         } else {
-            eprintln!("Error reading from mag: {reading:?}");
+            warn!(result = ?reading, "Failed reading magnetometer");
         }
     }
     data
@@ -92,7 +293,7 @@ pub fn calculate_gyro_bias(mpu: &mut Mpu, duration: &Duration) -> [f32; 3] {
     let mut gyro_biases: [f32; 3] = [0.0, 0.0, 0.0];
     let mut amount_of_readings = 0;
     while start_time.elapsed() < *duration {
-        let reading: [f32; 3] = mpu.mpu9250.gyro().expect("unable to make gyro reading");
+        let reading: [f32; 3] = mpu.get_raw_gyro().expect("unable to make gyro reading");
         for (bias, reading) in gyro_biases.iter_mut().zip(reading) {
             *bias += reading;
         }
@@ -101,113 +302,241 @@ pub fn calculate_gyro_bias(mpu: &mut Mpu, duration: &Duration) -> [f32; 3] {
     gyro_biases.map(|b| b / amount_of_readings as f32)
 }
 
-#[must_use]
-pub fn calculate_accel_bias_and_scale(mpu: &mut Mpu) -> ([f32; 3], [f32; 3]) {
-    eprintln!("Put mpu into 6 different positions, every time pointing some axis to ground.");
-    let stdin = std::io::stdin();
-    let mut zero_values: [Vec<f32>; 3] = Default::default();
-    let mut axis_ranges: [Vec<f32>; 3] = Default::default();
-
-    for i in 0..6 {
-        eprintln!("Put mpu into position {i} and press <Enter>.");
-        let mut input = String::new();
-        let _ = stdin.read_line(&mut input);
-
-        let accel: [f32; 3] = mpu.mpu9250.accel().expect("unable to make accel reading");
-        eprintln!("got reading: {accel:?}");
-
-        for (i, reading) in accel.iter().enumerate() {
-            if reading.abs() > 6.0 {
-                eprintln!("that was {i} axis");
-                axis_ranges[i].push(*reading);
-            } else {
-                zero_values[i].push(*reading);
-            }
-        }
-    }
+use spin_sleep::LoopHelper;
 
-    for zero_array in zero_values.iter() {
-        assert!(zero_array.len() == 4);
-    }
+/// Madgwick filter over raw samples, rebuilt when its gain or sample period change, as
+/// ahrs keeps them private.
+struct OrientationFilter {
+    ahrs: ahrs::Madgwick<f32>,
+    gain: f32,
+    dt: f32,
+}
 
-    for axis_array in axis_ranges.iter() {
-        assert!(axis_array.len() == 2);
+impl OrientationFilter {
+    fn new(gain: f32, dt: f32) -> Self {
+        OrientationFilter {
+            ahrs: ahrs::Madgwick::new(dt / 2.0, gain),
+            gain,
+            dt,
+        }
     }
 
-    use std::convert::TryInto;
-    let accel_bias: [f32; 3] = zero_values
-        .iter()
-        .map(|v| v.iter().sum::<f32>() / 4.0)
-        .collect::<Vec<f32>>()
-        .try_into()
-        .unwrap();
-
-    let accel_scale: [f32; 3] = axis_ranges
-        .iter()
-        .map(|v| (2.0 * mpu9250::G) / (v[0].abs() + v[1].abs()))
-        .collect::<Vec<f32>>()
-        .try_into()
-        .unwrap();
+    /// Filter step over raw `sample` with `config` calibration applied, `dt` seconds after the
+    /// previous one. Done twice per sample, each over half of `dt`. Samples without
+    /// accelerometer reading, as in gyro-only recordings, are integrated from the gyroscope.
+    fn update(
+        &mut self,
+        config: &MpuConfig,
+        sample: &ImuSample,
+        dt: f32,
+        gain: f32,
+    ) -> Option<UnitQuaternion<f32>> {
+        use ahrs::Ahrs;
+        use nalgebra::Vector3;
 
-    (accel_bias, accel_scale)
+        if gain != self.gain || dt != self.dt {
+            self.ahrs = ahrs::Madgwick::new_with_quat(dt / 2.0, gain, self.ahrs.quat);
+            self.gain = gain;
+            self.dt = dt;
+        }
+        let (accel, gyro) = config.apply(sample.accel, sample.gyro);
+        let gyro = Vector3::new(gyro[0], gyro[1], gyro[2]);
+        if sample.accel == [0.0; 3] {
+            // Madgwick refuses zero accel
+            self.ahrs.quat *= UnitQuaternion::from_scaled_axis(gyro * dt);
+            return Some(self.ahrs.quat);
+        }
+        let accel = Vector3::new(accel[0], accel[1], accel[2]);
+        let _ = self.ahrs.update_imu(&gyro, &accel);
+        self.ahrs.update_imu(&gyro, &accel).ok().copied()
+    }
 }
 
-use spin_sleep::LoopHelper;
-
-fn control_loop(mut mpu: Mpu, quaternion: Arc<Mutex<UnitQuaternion<f32>>>) {
-    let rate_hz = 500;
-    let sample_period = Duration::from_secs(1) / (rate_hz * 2);
-    let filter_gain = 0.1; // 0.0 -> Fully trust gyro, 1.0 -> fully trust accel
+fn control_loop(
+    mut mpu: Mpu,
+    quaternion: watch::Sender<UnitQuaternion<f32>>,
+    filter_gain: Arc<AtomicU32>,
+) {
+    let rate_hz = mpu.imu.rate_hz;
+    let sample_dt = 1.0 / rate_hz as f32;
+    let gain = || f32::from_bits(filter_gain.load(Ordering::Relaxed)); // 0.0 -> Fully trust gyro, 1.0 -> fully trust accel
 
     let mut loop_helper = LoopHelper::builder()
         .report_interval_s(5.0)
-        .build_with_target_rate(rate_hz);
+        .build_with_target_rate(rate_hz as f32 * mpu.time_scale());
 
-    let mut ahrs = ahrs::Madgwick::new(sample_period.as_secs_f32(), filter_gain);
+    let mut filter = OrientationFilter::new(gain(), sample_dt);
+    let mut prev_timestamp = None;
 
     loop {
         loop_helper.loop_start();
-        use ahrs::Ahrs;
-        use nalgebra::Vector3;
 
-        /*if let Some(fps) = loop_helper.report_rate() {
-            eprintln!("MPU_UPDATE_LOOP UPS: {fps}");
-        }*/
+        if let Some(rate) = loop_helper.report_rate() {
+            MPU_LOOP_RATE.set(rate);
+        }
 
-        let measurement = mpu.get_accel_gyro();
-        if let Ok((accel, gyro)) = measurement {
-            let _ = ahrs.update_imu(
-                &Vector3::new(gyro[0], gyro[1], gyro[2]),
-                &Vector3::new(accel[0], accel[1], accel[2]),
-            );
-            let quat = ahrs
-                .update_imu(
-                    &Vector3::new(gyro[0], gyro[1], gyro[2]),
-                    &Vector3::new(accel[0], accel[1], accel[2]),
-                )
-                .unwrap();
-            let mut lock = quaternion.lock().unwrap();
-            *lock = *quat;
+        let measurement = mpu.read_sample();
+        if let Ok(sample) = &measurement {
+            // Replays go by their own timestamps, whatever rate they were recorded at.
+            let timestamp = mpu.replay_timestamp();
+            let dt = match (prev_timestamp, timestamp) {
+                (Some(prev), Some(timestamp)) => timestamp
+                    .checked_sub(prev)
+                    .map_or(0.0, |dt| dt.as_secs_f32()),
+                (None, Some(_)) => 0.0,
+                (_, None) => sample_dt,
+            };
+            prev_timestamp = timestamp;
+            if let Some(quat) = filter.update(&mpu.config, sample, dt, gain()) {
+                quaternion.send_replace(quat);
+            }
+        }
+        // Replays wait for their next sample to be due by themselves.
+        if !mpu.is_replay() || measurement.is_err() {
+            loop_helper.loop_sleep();
         }
-        loop_helper.loop_sleep();
     }
 }
 
+/// Run orientation filter over a recording as fast as possible, for testing filter changes.
+/// Returns orientation after every sample.
+pub fn replay_orientation(
+    config: &MpuConfig,
+    samples: &[TimedImuSample],
+    filter_gain: f32,
+) -> Vec<UnitQuaternion<f32>> {
+    let duration = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) if samples.len() > 1 => last.timestamp - first.timestamp,
+        _ => return Vec::new(),
+    };
+    let mean_dt = duration.as_secs_f32() / (samples.len() - 1) as f32;
+    let mut filter = OrientationFilter::new(filter_gain, mean_dt);
+
+    let mut prev_timestamp = samples[0].timestamp;
+    samples
+        .iter()
+        .map(|timed| {
+            let dt = (timed.timestamp - prev_timestamp).as_secs_f32();
+            let _ = filter.update(config, &timed.sample, dt, filter_gain);
+            prev_timestamp = timed.timestamp;
+            filter.ahrs.quat
+        })
+        .collect()
+}
+
 pub struct OrientationController {
-    quat: Arc<Mutex<UnitQuaternion<f32>>>,
+    quat: watch::Receiver<UnitQuaternion<f32>>,
+    /// Madgwick filter gain as `f32` bits, read by the loop every update.
+    filter_gain: Arc<AtomicU32>,
 }
 
 impl OrientationController {
     pub fn new(mpu: Mpu) -> Self {
-        let quat = Arc::new(Mutex::new(UnitQuaternion::default()));
-        let quat_clone = quat.clone();
+        let (tx, quat) = watch::channel(UnitQuaternion::default());
+        let filter_gain = Arc::new(AtomicU32::new(mpu.imu.filter_gain.to_bits()));
+        let loop_filter_gain = filter_gain.clone();
         std::thread::spawn(|| {
-            control_loop(mpu, quat_clone);
+            control_loop(mpu, tx, loop_filter_gain);
         });
-        OrientationController { quat }
+        OrientationController { quat, filter_gain }
+    }
+
+    /// Change filter gain of the running loop, see [`ImuConfig::filter_gain`].
+    pub fn set_filter_gain(&self, filter_gain: f32) {
+        self.filter_gain
+            .store(filter_gain.to_bits(), Ordering::Relaxed);
     }
 
     pub fn get_quat(&self) -> UnitQuaternion<f32> {
-        *self.quat.lock().unwrap()
+        *self.quat.borrow()
+    }
+
+    /// Orientation updates for async code, without going through the controller's lock.
+    pub fn subscribe(&self) -> watch::Receiver<UnitQuaternion<f32>> {
+        self.quat.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::imu_recording::load_recording;
+    use std::f32::consts::FRAC_PI_2;
+
+    /// Replay recording `text` written to a temporary file.
+    fn replay(name: &str, text: &str, config: &MpuConfig) -> UnitQuaternion<f32> {
+        let path = std::env::temp_dir().join(format!("lidarino_{name}_{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let samples = load_recording(&path);
+        std::fs::remove_file(&path).unwrap();
+        let orientation = replay_orientation(config, &samples.unwrap(), 0.1);
+        *orientation.last().unwrap()
+    }
+
+    #[test]
+    fn replays_gyro_only_recording() {
+        // 1 s turning at 90°/s about z, in `misc/mpu_readings` format.
+        let text = format!("0 0 {FRAC_PI_2} 0.01\n").repeat(101);
+        let (roll, pitch, yaw) = replay("gyro", &text, &MpuConfig::default()).euler_angles();
+        assert!(
+            roll.abs() < 1e-3 && pitch.abs() < 1e-3,
+            "{} {}",
+            roll,
+            pitch
+        );
+        assert!((yaw - FRAC_PI_2).abs() < 1e-3, "{}", yaw);
+
+        // Calibration doesn't turn missing accel readings into a tilt.
+        let config = MpuConfig {
+            gyro_bias: [0.0, 0.0, 0.1],
+            accel_bias: [0.2, -0.1, 0.3],
+            ..MpuConfig::default()
+        };
+        let (roll, pitch, yaw) = replay("gyro_calibrated", &text, &config).euler_angles();
+        assert!(
+            roll.abs() < 1e-3 && pitch.abs() < 1e-3,
+            "{} {}",
+            roll,
+            pitch
+        );
+        assert!((yaw - (FRAC_PI_2 - 0.1)).abs() < 1e-3, "{}", yaw);
+    }
+
+    #[test]
+    fn control_loop_uses_recorded_times() {
+        // 1 s turning at 90°/s about z recorded at 100 Hz, the loop runs at 500 Hz.
+        let samples = (0..=100)
+            .map(|i| TimedImuSample {
+                timestamp: Duration::from_millis(10 * i),
+                sample: ImuSample {
+                    gyro: [0.0, 0.0, FRAC_PI_2],
+                    ..ImuSample::default()
+                },
+            })
+            .collect();
+        let replay = ImuReplay::new(samples, 10.0).unwrap();
+        let controller = OrientationController::new(Mpu::replay(MpuConfig::default(), replay));
+        std::thread::sleep(Duration::from_millis(500));
+        let (_, _, yaw) = controller.get_quat().euler_angles();
+        assert!((yaw - FRAC_PI_2).abs() < 1e-3, "{}", yaw);
+    }
+
+    #[test]
+    fn replay_settles_on_gravity() {
+        // 20 s still, rolled 30°.
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        let text: String = (0..2000)
+            .map(|i| {
+                let t = i as f32 * 0.01;
+                format!("{t} 0 {} {} 0 0 0 20 0 40 25\n", 9.81 * sin, 9.81 * cos)
+            })
+            .collect();
+        let (roll, pitch, _) = replay("still", &text, &MpuConfig::default()).euler_angles();
+        assert!(
+            (roll.to_degrees() - 30.0).abs() < 0.5,
+            "{}",
+            roll.to_degrees()
+        );
+        assert!(pitch.to_degrees().abs() < 0.5, "{}", pitch.to_degrees());
     }
 }
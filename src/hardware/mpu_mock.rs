@@ -0,0 +1,168 @@
+//! Simulated MPU9250 for running without the board.
+//!
+//! Produces readings for a static or scripted attitude of the base, optionally rotated
+//! by simulated yaw and pitch motors, with gaussian noise added.
+
+use super::imu_recording::ImuSample;
+use super::{KINEMATICS, PITCH_CONTROLLER, YAW_CONTROLLER};
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::time::Instant;
+
+/// Earth magnetic field in base frame, μT.
+const EARTH_FIELD: [f32; 3] = [20.0, 0.0, -40.0];
+
+/// Base attitude at time `t` seconds since start.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct AttitudeKeyframe {
+    pub t: f32,
+    /// Roll, pitch and yaw in degrees.
+    pub attitude_deg: [f32; 3],
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct MockImuConfig {
+    /// Static base attitude as roll, pitch and yaw in degrees. Used if `script` is empty.
+    pub attitude_deg: [f32; 3],
+    /// Base attitude keyframes, interpolated between.
+    pub script: Vec<AttitudeKeyframe>,
+    /// Start script over after the last keyframe.
+    pub loop_script: bool,
+    /// Rotate the head with simulated yaw and pitch motor positions.
+    pub follow_motors: bool,
+    /// Standard deviation of accelerometer noise, m/s².
+    pub accel_noise: f32,
+    /// Standard deviation of gyroscope noise, rad/s.
+    pub gyro_noise: f32,
+    /// Standard deviation of magnetometer noise, μT.
+    pub mag_noise: f32,
+    /// Constant gyroscope offset, rad/s.
+    pub gyro_bias: [f32; 3],
+    pub seed: u64,
+}
+
+impl Default for MockImuConfig {
+    fn default() -> Self {
+        MockImuConfig {
+            attitude_deg: [0.0; 3],
+            script: Vec::new(),
+            loop_script: false,
+            follow_motors: true,
+            accel_noise: 0.02,
+            gyro_noise: 0.002,
+            mag_noise: 0.3,
+            gyro_bias: [0.0; 3],
+            seed: 42,
+        }
+    }
+}
+
+fn from_degrees(attitude_deg: [f32; 3]) -> UnitQuaternion<f32> {
+    let [roll, pitch, yaw] = attitude_deg.map(f32::to_radians);
+    UnitQuaternion::from_euler_angles(roll, pitch, yaw)
+}
+
+/// Simulated IMU, see [module documentation](self).
+pub struct MockImu {
+    config: MockImuConfig,
+    start: Instant,
+    /// Attitude and time of previous reading, for gyroscope.
+    prev: Option<(UnitQuaternion<f32>, Instant)>,
+    rng: u64,
+}
+
+impl MockImu {
+    pub fn new(config: MockImuConfig) -> Self {
+        let rng = config.seed.max(1);
+        MockImu {
+            config,
+            start: Instant::now(),
+            prev: None,
+            rng,
+        }
+    }
+
+    fn base_attitude(&self, t: f32) -> UnitQuaternion<f32> {
+        let script = &self.config.script;
+        let (first, last) = match (script.first(), script.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return from_degrees(self.config.attitude_deg),
+        };
+
+        let t = if self.config.loop_script && last.t > first.t {
+            first.t + (t - first.t).rem_euclid(last.t - first.t)
+        } else {
+            t
+        };
+        match script.windows(2).find(|w| (w[0].t..w[1].t).contains(&t)) {
+            Some([from, to]) => {
+                let fraction = (t - from.t) / (to.t - from.t);
+                from_degrees(from.attitude_deg).slerp(&from_degrees(to.attitude_deg), fraction)
+            }
+            _ if t < first.t => from_degrees(first.attitude_deg),
+            _ => from_degrees(last.attitude_deg),
+        }
+    }
+
+    /// Rotation of the head relative to the base, from simulated motor positions.
+    fn head_attitude(&self) -> UnitQuaternion<f32> {
+        if !self.config.follow_motors {
+            return UnitQuaternion::identity();
+        }
+        let yaw = KINEMATICS.yaw.to_radians(YAW_CONTROLLER.get_current_pos());
+        let pitch = KINEMATICS
+            .pitch
+            .to_radians(PITCH_CONTROLLER.get_current_pos());
+        UnitQuaternion::from_axis_angle(&Vector3::z_axis(), yaw)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch)
+    }
+
+    /// Gaussian noise with standard deviation `sigma` (xorshift + Box-Muller).
+    fn noise(&mut self, sigma: f32) -> f32 {
+        let mut uniform = || {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            ((self.rng >> 40) as f32 + 1.0) / (1u64 << 24) as f32
+        };
+        let (u1, u2) = (uniform(), uniform());
+        sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+
+    fn noisy(&mut self, v: Vector3<f32>, sigma: f32) -> [f32; 3] {
+        [
+            v.x + self.noise(sigma),
+            v.y + self.noise(sigma),
+            v.z + self.noise(sigma),
+        ]
+    }
+
+    pub fn read_sample(&mut self) -> ImuSample {
+        let now = Instant::now();
+        let t = now.duration_since(self.start).as_secs_f32();
+        let attitude = self.base_attitude(t) * self.head_attitude();
+        let to_body = attitude.inverse();
+
+        let angular_velocity = match self.prev {
+            Some((prev, prev_time)) if now > prev_time => {
+                let dt = now.duration_since(prev_time).as_secs_f32();
+                (prev.inverse() * attitude).scaled_axis() / dt
+            }
+            _ => Vector3::zeros(),
+        };
+        self.prev = Some((attitude, now));
+
+        let gravity = to_body * Vector3::new(0.0, 0.0, mpu9250::G);
+        let mag = to_body * Vector3::from(EARTH_FIELD);
+        let gyro = angular_velocity + Vector3::from(self.config.gyro_bias);
+
+        ImuSample {
+            accel: self.noisy(gravity, self.config.accel_noise),
+            gyro: self.noisy(gyro, self.config.gyro_noise),
+            mag: self.noisy(mag, self.config.mag_noise),
+            temp: 25.0 + self.noise(0.1),
+        }
+    }
+}
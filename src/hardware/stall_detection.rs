@@ -0,0 +1,262 @@
+//! Missed step detection with the IMU.
+//!
+//! Motors are driven open loop, so a stalled axis keeps counting steps it never made and
+//! everything scanned after it is misregistered. The MPU sits on the head and rotates with
+//! it, so every axis move is checked against the rotation measured by the orientation filter.
+//!
+//! Axes are moved one at a time, so measured rotation angle is compared with the commanded
+//! one directly and it doesn't matter how the MPU is mounted on the head.
+//!
+//! # Example
+//! ```ignore
+//! let config = StallDetectionConfig::default();
+//! let check = checked_move(&YAW_CONTROLLER, &KINEMATICS.yaw, 1000, &config, current_orientation);
+//! if check.is_stall(&config) {
+//!     println!("yaw moved {:?}° instead of {}°", check.measured_deg, check.commanded_deg);
+//! }
+//! ```
+
+use super::motor::StepMotorController;
+use super::ORIENTATION_CONTROLLER;
+use crate::kinematics::AxisKinematics;
+use anyhow::{ensure, Result};
+use nalgebra::UnitQuaternion;
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::Duration;
+
+/// Rotation angle is ambiguous close to half a turn, longer moves aren't checked.
+const MAX_CHECKED_DEG: f32 = 170.0;
+
+/// What the scan does once a move disagrees with the IMU.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StallAction {
+    /// Keep scanning, mark this and every following checkpoint as misregistered.
+    #[default]
+    Annotate,
+    /// Pause the scan before measuring the point. Points scanned after it's resumed are
+    /// misregistered till the axis is rehomed.
+    Pause,
+    /// Correct axis position by the measured rotation and repeat the move.
+    ///
+    /// There are no endstops, so the axis is assumed to have moved in the commanded
+    /// direction, only less than it should have.
+    Rehome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct StallDetectionConfig {
+    pub enabled: bool,
+    /// Allowed difference between commanded and measured rotation, degrees.
+    pub tolerance_deg: f32,
+    /// Part of the commanded rotation allowed on top of `tolerance_deg`. Yaw has nothing but
+    /// the gyroscope to go by, so it's measured a few percent off on long moves.
+    pub tolerance_ratio: f32,
+    /// Time given to the orientation filter to catch up after the move.
+    pub settle_ms: u64,
+    pub action: StallAction,
+    /// Moves repeated with [`StallAction::Rehome`] before giving up and pausing.
+    pub max_rehome_attempts: u32,
+}
+
+impl StallDetectionConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.tolerance_deg >= 0.0, "tolerance_deg must be 0 or more");
+        ensure!(
+            self.tolerance_ratio >= 0.0,
+            "tolerance_ratio must be 0 or more"
+        );
+        Ok(())
+    }
+}
+
+impl Default for StallDetectionConfig {
+    fn default() -> Self {
+        StallDetectionConfig {
+            enabled: true,
+            tolerance_deg: 1.0,
+            tolerance_ratio: 0.1,
+            settle_ms: 100,
+            action: StallAction::Annotate,
+            max_rehome_attempts: 2,
+        }
+    }
+}
+
+/// Commanded against measured rotation of a single axis move.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct MoveCheck {
+    pub start_pos: i32,
+    pub end_pos: i32,
+    /// Rotation the motor position says was made, degrees.
+    pub commanded_deg: f32,
+    /// Rotation measured by the IMU, `None` if orientation isn't available.
+    pub measured_deg: Option<f32>,
+}
+
+impl MoveCheck {
+    /// Measured minus commanded rotation, degrees.
+    pub fn error_deg(&self) -> Option<f32> {
+        self.measured_deg
+            .map(|measured| measured - self.commanded_deg)
+    }
+
+    /// `true` if the move disagrees with the IMU beyond tolerance.
+    pub fn is_stall(&self, config: &StallDetectionConfig) -> bool {
+        let tolerance = config.tolerance_deg + config.tolerance_ratio * self.commanded_deg;
+        self.error_deg()
+            .is_some_and(|error| error.abs() > tolerance)
+    }
+
+    /// Position the axis is at according to the IMU, assuming it
+    /// moved in the commanded direction.
+    pub fn measured_pos(&self, axis: &AxisKinematics) -> Option<i32> {
+        let direction = (self.end_pos - self.start_pos).signum();
+        self.measured_deg.map(|measured| {
+            let steps = measured.to_radians() * axis.steps_per_radian().abs();
+            self.start_pos + direction * steps.round() as i32
+        })
+    }
+}
+
+/// Head orientation from [`ORIENTATION_CONTROLLER`], `None` if it isn't initialized.
+pub fn current_orientation() -> Option<UnitQuaternion<f32>> {
+    ORIENTATION_CONTROLLER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|controller| controller.get_quat())
+}
+
+/// Move `motor` to `target` and wait for it to stop, measuring the rotation with `orientation`.
+///
+/// Other axes should stay still during the move, their rotation is counted too.
+/// Moves longer than 170° aren't measured.
+pub fn checked_move<F>(
+    motor: &StepMotorController,
+    axis: &AxisKinematics,
+    target: i32,
+    config: &StallDetectionConfig,
+    orientation: F,
+) -> MoveCheck
+where
+    F: Fn() -> Option<UnitQuaternion<f32>>,
+{
+    let start_pos = motor.get_current_pos();
+    let start = orientation();
+    motor.set_target_pos(target);
+    motor.wait_stop();
+    let end_pos = motor.get_current_pos();
+    let commanded_deg = (axis.to_degrees(end_pos) - axis.to_degrees(start_pos)).abs();
+
+    let measured_deg = match start {
+        Some(start) if config.enabled && commanded_deg > 0.0 && commanded_deg < MAX_CHECKED_DEG => {
+            thread::sleep(Duration::from_millis(config.settle_ms));
+            orientation().map(|end| (start.inverse() * end).angle().to_degrees())
+        }
+        _ => None,
+    };
+    MoveCheck {
+        start_pos,
+        end_pos,
+        commanded_deg,
+        measured_deg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(
+        start_pos: i32,
+        end_pos: i32,
+        commanded_deg: f32,
+        measured_deg: Option<f32>,
+    ) -> MoveCheck {
+        MoveCheck {
+            start_pos,
+            end_pos,
+            commanded_deg,
+            measured_deg,
+        }
+    }
+
+    fn axis(reversed: bool) -> AxisKinematics {
+        AxisKinematics {
+            steps_per_rev: 8000,
+            gear_ratio: 1.0,
+            reversed,
+            zero_offset_deg: 0.0,
+        }
+    }
+
+    #[test]
+    fn stall_beyond_tolerance() {
+        let config = StallDetectionConfig {
+            tolerance_deg: 1.0,
+            tolerance_ratio: 0.0,
+            ..Default::default()
+        };
+        assert!(!check(0, 100, 10.0, Some(10.0)).is_stall(&config));
+        assert!(!check(0, 100, 10.0, Some(9.2)).is_stall(&config));
+        assert!(!check(0, 100, 10.0, Some(10.8)).is_stall(&config));
+        assert!(check(0, 100, 10.0, Some(8.5)).is_stall(&config));
+        assert!(check(0, 100, 10.0, Some(11.5)).is_stall(&config));
+    }
+
+    #[test]
+    fn tolerance_grows_with_move() {
+        let config = StallDetectionConfig {
+            tolerance_deg: 1.0,
+            tolerance_ratio: 0.1,
+            ..Default::default()
+        };
+        assert!(!check(0, 2000, 90.0, Some(81.0)).is_stall(&config));
+        assert!(check(0, 2000, 90.0, Some(79.0)).is_stall(&config));
+        assert!(check(0, 100, 5.0, Some(3.0)).is_stall(&config));
+    }
+
+    #[test]
+    fn unmeasured_move_is_no_stall() {
+        let config = StallDetectionConfig {
+            tolerance_deg: 0.0,
+            tolerance_ratio: 0.0,
+            ..Default::default()
+        };
+        assert!(!check(0, 2000, 90.0, None).is_stall(&config));
+    }
+
+    #[test]
+    fn measured_pos_in_commanded_direction() {
+        let axis = axis(false);
+        assert_eq!(
+            check(0, 2000, 90.0, Some(45.0)).measured_pos(&axis),
+            Some(1000)
+        );
+        assert_eq!(
+            check(500, -1500, 90.0, Some(45.0)).measured_pos(&axis),
+            Some(-500)
+        );
+        assert_eq!(
+            check(0, 2000, 90.0, Some(90.0)).measured_pos(&axis),
+            Some(2000)
+        );
+        assert_eq!(check(0, 2000, 90.0, None).measured_pos(&axis), None);
+    }
+
+    #[test]
+    fn measured_pos_of_reversed_axis() {
+        let axis = axis(true);
+        assert_eq!(
+            check(0, 2000, 90.0, Some(45.0)).measured_pos(&axis),
+            Some(1000)
+        );
+        assert_eq!(
+            check(0, -2000, 90.0, Some(22.5)).measured_pos(&axis),
+            Some(-500)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Conversion between motor steps and axis angles.
+//!
+//! Every axis is described by [`AxisKinematics`] in the config, so a different
+//! motor or gearbox doesn't need code changes:
+//! ```toml
+//! [kinematics.yaw]
+//! steps_per_rev = 2048
+//! gear_ratio = 3.0
+//! reversed = true
+//! zero_offset_deg = 0.0
+//! ```
+
+use crate::sphere::{Direction, Point, Waypoint};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AxisKinematics {
+    /// Motor full steps per turn of the motor shaft, gearbox built into the motor included.
+    pub steps_per_rev: u32,
+    /// Motor shaft turns per axis turn.
+    pub gear_ratio: f32,
+    /// Positive steps turn the axis to negative angles.
+    pub reversed: bool,
+    /// Axis angle at motor position 0, degrees.
+    pub zero_offset_deg: f32,
+}
+
+impl AxisKinematics {
+    /// Steps per radian of axis rotation, negative if axis is reversed.
+    pub fn steps_per_radian(&self) -> f32 {
+        let steps_per_radian = self.steps_per_rev as f32 * self.gear_ratio / TAU;
+        if self.reversed {
+            -steps_per_radian
+        } else {
+            steps_per_radian
+        }
+    }
+
+    /// Axis angle at motor position `steps`, radians.
+    pub fn to_radians(&self, steps: i32) -> f32 {
+        steps as f32 / self.steps_per_radian() + self.zero_offset_deg.to_radians()
+    }
+
+    /// Motor position closest to axis `angle` in radians.
+    pub fn to_steps(&self, angle: f32) -> i32 {
+        self.to_steps_f32(angle).round() as i32
+    }
+
+    /// Motor position at axis `angle` in radians, unrounded so it can be range checked.
+    pub fn to_steps_f32(&self, angle: f32) -> f32 {
+        (angle - self.zero_offset_deg.to_radians()) * self.steps_per_radian()
+    }
+
+    /// Motor steps of a full turn of the axis.
+    pub fn steps_per_turn(&self) -> f32 {
+        TAU * self.steps_per_radian().abs()
+    }
+
+    pub fn to_degrees(&self, steps: i32) -> f32 {
+        self.to_radians(steps).to_degrees()
+    }
+
+    pub fn from_degrees(&self, angle: f32) -> i32 {
+        self.to_steps(angle.to_radians())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.steps_per_rev > 0, "steps_per_rev must be positive");
+        ensure!(
+            self.gear_ratio.is_finite() && self.gear_ratio > 0.0,
+            "gear_ratio must be positive"
+        );
+        ensure!(
+            self.zero_offset_deg.is_finite(),
+            "zero_offset_deg must be a number"
+        );
+        Ok(())
+    }
+}
+
+/// Kinematics of both axes of the head.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct HeadKinematics {
+    pub yaw: AxisKinematics,
+    pub pitch: AxisKinematics,
+}
+
+impl Default for HeadKinematics {
+    /// 4000 steps for half a turn on both axes, yaw turning clockwise.
+    fn default() -> Self {
+        let axis = AxisKinematics {
+            steps_per_rev: 8000,
+            gear_ratio: 1.0,
+            reversed: false,
+            zero_offset_deg: 0.0,
+        };
+        HeadKinematics {
+            yaw: AxisKinematics {
+                reversed: true,
+                ..axis
+            },
+            pitch: axis,
+        }
+    }
+}
+
+impl HeadKinematics {
+    pub fn validate(&self) -> Result<()> {
+        self.yaw.validate().context("yaw")?;
+        self.pitch.validate().context("pitch")
+    }
+
+    pub fn direction(&self, waypoint: Waypoint) -> Direction {
+        Direction {
+            yaw: self.yaw.to_radians(waypoint.yaw),
+            pitch: self.pitch.to_radians(waypoint.pitch),
+        }
+    }
+
+    pub fn waypoint(&self, direction: Direction) -> Waypoint {
+        Waypoint {
+            yaw: self.yaw.to_steps(direction.yaw),
+            pitch: self.pitch.to_steps(direction.pitch),
+        }
+    }
+
+    /// Point measured `distance` mm away with motors at `waypoint`, in meters.
+    pub fn point(&self, waypoint: Waypoint, distance: u32) -> Point {
+        self.direction(waypoint).to_point(distance as f32 / 1000.0)
+    }
+}
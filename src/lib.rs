@@ -1,5 +1,16 @@
 pub mod config;
+pub mod export;
 pub mod hardware;
+pub mod kinematics;
+pub mod logging;
+pub mod metrics;
+pub mod motion;
+pub mod pattern;
+pub mod planner;
+pub mod refine;
+pub mod region;
 pub mod scan;
+pub mod server;
 pub mod shared;
+pub mod shutdown;
 pub mod sphere;
@@ -0,0 +1,130 @@
+//! Log output of the binaries: level filter, text or JSON lines, and rotating log files.
+//!
+//! Library code logs with [`tracing`] macros, passing context such as `axis`, `point` or
+//! `error` as fields, so JSON logs can be filtered on them.
+//!
+//! # Example
+//! ```ignore
+//! let config = Config::from_args()?;
+//! lidarino::logging::init(&config.logging.unwrap_or_default()).expect("Failed to set up logging");
+//! tracing::info!(axis = "yaw", steps = 100, "Moving");
+//! ```
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use tracing::Subscriber;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line, fields included.
+    Json,
+}
+
+/// How often a new log file is started.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogFileConfig {
+    /// Directory of log files, named `lidarino.<date>.log`.
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Oldest files are deleted once there are more, all are kept if missing.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Filter in `RUST_LOG` syntax, e.g. `info,lidarino::scan=debug`. `RUST_LOG`
+    /// environment variable takes precedence.
+    pub level: String,
+    /// Format of both console and file output.
+    pub format: LogFormat,
+    /// Also write logs to rotating files, console only if missing.
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+        }
+    }
+}
+
+fn format_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+fn file_appender(config: &LogFileConfig) -> Result<RollingFileAppender> {
+    let rotation = match config.rotation {
+        Rotation::Hourly => rolling::Rotation::HOURLY,
+        Rotation::Daily => rolling::Rotation::DAILY,
+        Rotation::Never => rolling::Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("lidarino")
+        .filename_suffix("log");
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    std::fs::create_dir_all(&config.dir)?;
+    Ok(builder.build(&config.dir)?)
+}
+
+/// Send logs, `log` crate records of dependencies included, to stderr and the log file.
+///
+/// File is written without buffering, so nothing is lost when the process exits
+/// through [`crate::shutdown`].
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(filter) => EnvFilter::try_new(filter)?,
+        Err(_) => EnvFilter::try_new(&config.level)?,
+    };
+    let ansi = std::io::stderr().is_terminal();
+    let console = format_layer(config.format, std::io::stderr, ansi);
+    let file = match &config.file {
+        Some(file) => Some(format_layer(config.format, file_appender(file)?, false)),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(console)
+        .with(file)
+        .try_init()?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+//! Scanner health and throughput in the Prometheus text format, see [`render`].
+//!
+//! Hardware loops record into the metrics here or keep their own counters, which are
+//! collected when the metrics are rendered.
+
+use crate::hardware::{MCP23S17, PITCH_CONTROLLER, YAW_CONTROLLER};
+use crate::scan::{ScanState, ScanStatus};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Upper bounds of duration histogram buckets, seconds.
+pub const DURATION_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Last value set, as `f64` bits.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Default, Clone)]
+struct HistogramData {
+    /// Observations in every bucket, not cumulative, the last one is above every bound.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// Observations counted in [`DURATION_BUCKETS`].
+#[derive(Default)]
+pub struct Histogram {
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: f64) {
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        let mut data = self.data.lock().unwrap();
+        data.counts.resize(DURATION_BUCKETS.len() + 1, 0);
+        data.counts[bucket] += 1;
+        data.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.data.lock().unwrap().counts.iter().sum()
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Metrics of one kind told apart by their labels.
+pub struct Family<M> {
+    metrics: Mutex<BTreeMap<Labels, Arc<M>>>,
+}
+
+impl<M: Default> Family<M> {
+    pub fn new() -> Self {
+        Family {
+            metrics: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Metric with `labels`, created on first use.
+    pub fn with(&self, labels: &[(&'static str, &str)]) -> Arc<M> {
+        let labels = labels
+            .iter()
+            .map(|&(name, value)| (name, value.to_string()))
+            .collect();
+        self.metrics
+            .lock()
+            .unwrap()
+            .entry(labels)
+            .or_default()
+            .clone()
+    }
+
+    fn snapshot(&self) -> Vec<(Labels, Arc<M>)> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(labels, metric)| (labels.clone(), metric.clone()))
+            .collect()
+    }
+}
+
+impl<M: Default> Default for Family<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static! {
+    /// Distance measurements by `result` and `error`.
+    pub static ref MEASUREMENTS: Family<Counter> = Family::new();
+    /// Distance measurement durations by `mode`, seconds.
+    pub static ref MEASUREMENT_DURATION: Family<Histogram> = Family::new();
+    /// Orientation filter updates per second, as last reported by its loop.
+    pub static ref MPU_LOOP_RATE: Gauge = Gauge::default();
+}
+
+/// Metrics text being written.
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").unwrap();
+        writeln!(self.0, "# TYPE {name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+            .collect();
+        if labels.is_empty() {
+            writeln!(self.0, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.0, "{name}{{{}}} {value}", labels.join(",")).unwrap();
+        }
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let data = histogram.data.lock().unwrap().clone();
+        let mut cumulative = 0;
+        let bounds = DURATION_BUCKETS.iter().map(|bound| bound.to_string());
+        for (i, le) in bounds.chain(Some("+Inf".to_string())).enumerate() {
+            cumulative += data.counts.get(i).copied().unwrap_or(0);
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&format!("{name}_bucket"), &bucket_labels, cumulative as f64);
+        }
+        self.sample(&format!("{name}_sum"), labels, data.sum);
+        self.sample(&format!("{name}_count"), labels, cumulative as f64);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn borrowed(labels: &Labels) -> Vec<(&str, &str)> {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect()
+}
+
+/// Every metric, with progress of `scan` if there is one.
+pub fn render(scan: Option<&ScanStatus>) -> String {
+    let mut out = Exposition(String::new());
+
+    out.header(
+        "lidarino_measurements_total",
+        "counter",
+        "Distance measurements by result and sensor error.",
+    );
+    for (labels, counter) in MEASUREMENTS.snapshot() {
+        out.sample(
+            "lidarino_measurements_total",
+            &borrowed(&labels),
+            counter.get() as f64,
+        );
+    }
+
+    out.header(
+        "lidarino_measurement_duration_seconds",
+        "histogram",
+        "Time taken by distance measurements by reading mode.",
+    );
+    for (labels, histogram) in MEASUREMENT_DURATION.snapshot() {
+        out.histogram(
+            "lidarino_measurement_duration_seconds",
+            &borrowed(&labels),
+            &histogram,
+        );
+    }
+
+    let axes = [("yaw", &*YAW_CONTROLLER), ("pitch", &*PITCH_CONTROLLER)];
+    out.header(
+        "lidarino_motor_steps_total",
+        "counter",
+        "Steps made by the motor, backlash take-up included.",
+    );
+    for (axis, controller) in axes {
+        let steps = controller.get_step_count() as f64;
+        out.sample("lidarino_motor_steps_total", &[("axis", axis)], steps);
+    }
+    out.header(
+        "lidarino_motor_moves_total",
+        "counter",
+        "Moves from standstill to standstill.",
+    );
+    for (axis, controller) in axes {
+        let moves = controller.move_durations().count() as f64;
+        out.sample("lidarino_motor_moves_total", &[("axis", axis)], moves);
+    }
+    out.header(
+        "lidarino_motor_move_duration_seconds",
+        "histogram",
+        "Time from the start of a move till the motor settled.",
+    );
+    for (axis, controller) in axes {
+        out.histogram(
+            "lidarino_motor_move_duration_seconds",
+            &[("axis", axis)],
+            controller.move_durations(),
+        );
+    }
+
+    out.header(
+        "lidarino_mpu_loop_rate_hz",
+        "gauge",
+        "Orientation filter updates per second.",
+    );
+    out.sample("lidarino_mpu_loop_rate_hz", &[], MPU_LOOP_RATE.get());
+
+    out.header(
+        "lidarino_spi_errors_total",
+        "counter",
+        "Failed SPI transfers to the MCP23S17 pin expanders, retried ones included.",
+    );
+    out.sample(
+        "lidarino_spi_errors_total",
+        &[],
+        MCP23S17.spi_error_count() as f64,
+    );
+
+    if let Some(scan) = scan {
+        out.header(
+            "lidarino_scan_state",
+            "gauge",
+            "State of the current scan, 1 for the state it's in.",
+        );
+        for state in ScanState::ALL {
+            let value = if state == scan.info.state { 1.0 } else { 0.0 };
+            let state = state.to_string();
+            out.sample("lidarino_scan_state", &[("state", &state)], value);
+        }
+        let gauges = [
+            (
+                "lidarino_scan_points_scanned",
+                "Points scanned in the current scan.",
+                scan.info.points_scanned as f64,
+            ),
+            (
+                "lidarino_scan_waypoints",
+                "Waypoints queued in the current scan.",
+                scan.waypoints as f64,
+            ),
+            (
+                "lidarino_scan_pass",
+                "Refinement pass of the current scan, 0 for the coarse one.",
+                scan.info.passes as f64,
+            ),
+            (
+                "lidarino_scan_planning",
+                "1 while the path of the current scan is being planned.",
+                scan.planning as u8 as f64,
+            ),
+            (
+                "lidarino_scan_elapsed_seconds",
+                "Time spent scanning points of the current scan.",
+                scan.elapsed_s as f64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            out.header(name, "gauge", help);
+            out.sample(name, &[], value);
+        }
+        if let Some(eta) = scan.eta_s {
+            out.header(
+                "lidarino_scan_eta_seconds",
+                "gauge",
+                "Time left to scan the queued waypoints of the current scan.",
+            );
+            out.sample("lidarino_scan_eta_seconds", &[], eta as f64);
+        }
+    }
+
+    out.0
+}
@@ -0,0 +1,319 @@
+//! Manual motion of the head: moves, jogging, stopping and motor speed settings.
+//!
+//! There are no endstops, so homing goes back to the position that was last zeroed.
+
+use crate::hardware::motor::{StepMotorController, MIN_ACCELERATION};
+use crate::hardware::{KINEMATICS, PITCH_CONTROLLER, YAW_CONTROLLER};
+use crate::kinematics::AxisKinematics;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Longest allowed jog timeout, a jog can't run away for longer than this.
+const MAX_JOG_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    Yaw,
+    Pitch,
+}
+
+impl Axis {
+    pub const ALL: [Axis; 2] = [Axis::Yaw, Axis::Pitch];
+
+    pub fn controller(self) -> &'static StepMotorController {
+        match self {
+            Axis::Yaw => &YAW_CONTROLLER,
+            Axis::Pitch => &PITCH_CONTROLLER,
+        }
+    }
+
+    pub fn kinematics(self) -> &'static AxisKinematics {
+        match self {
+            Axis::Yaw => &KINEMATICS.yaw,
+            Axis::Pitch => &KINEMATICS.pitch,
+        }
+    }
+}
+
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Axis::Yaw => write!(f, "yaw"),
+            Axis::Pitch => write!(f, "pitch"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Steps,
+    #[default]
+    Deg,
+}
+
+/// Move of both axes, axes left out keep going where they were going.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MoveCommand {
+    pub yaw: Option<f32>,
+    pub pitch: Option<f32>,
+    #[serde(default)]
+    pub unit: Unit,
+    /// Move by the given amount from the current target, instead of to it.
+    #[serde(default)]
+    pub relative: bool,
+    /// Reply once the motion has settled.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+impl MoveCommand {
+    fn get(&self, axis: Axis) -> Option<f32> {
+        match axis {
+            Axis::Yaw => self.yaw,
+            Axis::Pitch => self.pitch,
+        }
+    }
+}
+
+/// Continuous move, renewed by the client for as long as it should go on.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JogCommand {
+    /// Direction of the axis, -1 or 1, 0 stops it. Left out axes aren't touched.
+    pub yaw: Option<i32>,
+    pub pitch: Option<i32>,
+    /// Axes stop this long after the last jog command, so a lost client can't leave them
+    /// running. At most 5 s.
+    #[serde(default = "default_jog_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl JogCommand {
+    fn get(&self, axis: Axis) -> Option<i32> {
+        match axis {
+            Axis::Yaw => self.yaw,
+            Axis::Pitch => self.pitch,
+        }
+    }
+}
+
+fn default_jog_timeout_ms() -> u64 {
+    500
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct AxisSettings {
+    /// Delay between steps at full speed.
+    pub step_delay_ms: Option<u32>,
+    /// Speed ramp at the start and end of moves, steps/s², 0 for none.
+    pub acceleration: Option<f32>,
+}
+
+/// Motor speed settings, `None` leaves a setting as it is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct MotionSettings {
+    #[serde(default)]
+    pub yaw: AxisSettings,
+    #[serde(default)]
+    pub pitch: AxisSettings,
+}
+
+impl MotionSettings {
+    fn get(&self, axis: Axis) -> AxisSettings {
+        match axis {
+            Axis::Yaw => self.yaw,
+            Axis::Pitch => self.pitch,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct AxisStatus {
+    pub pos: i32,
+    pub target: i32,
+    pub deg: f32,
+    pub target_deg: f32,
+    pub moving: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct MotionStatus {
+    pub yaw: AxisStatus,
+    pub pitch: AxisStatus,
+}
+
+fn axis_status(axis: Axis) -> AxisStatus {
+    let controller = axis.controller();
+    let kinematics = axis.kinematics();
+    let pos = controller.get_current_pos();
+    let target = controller.get_target_pos();
+    AxisStatus {
+        pos,
+        target,
+        deg: kinematics.to_degrees(pos),
+        target_deg: kinematics.to_degrees(target),
+        moving: !controller.is_stopped(),
+    }
+}
+
+pub fn status() -> MotionStatus {
+    MotionStatus {
+        yaw: axis_status(Axis::Yaw),
+        pitch: axis_status(Axis::Pitch),
+    }
+}
+
+/// Start `cmd`, see [`settled`] for waiting till it's done. Nothing is moved if a value
+/// isn't a number or a target is more than a turn away from zero.
+pub fn move_axes(cmd: &MoveCommand) -> Result<()> {
+    let targets = check_move(cmd)?;
+    for (axis, steps) in targets {
+        let controller = axis.controller();
+        if cmd.relative {
+            controller.move_on(steps)?;
+        } else {
+            controller.set_target_pos(steps);
+        }
+    }
+    Ok(())
+}
+
+/// Steps `cmd` moves the axes to, or by if it's relative.
+pub fn check_move(cmd: &MoveCommand) -> Result<Vec<(Axis, i32)>> {
+    let mut targets = Vec::new();
+    for axis in Axis::ALL {
+        let value = match cmd.get(axis) {
+            Some(value) => value,
+            None => continue,
+        };
+        ensure!(value.is_finite(), "{axis} must be a number");
+        let controller = axis.controller();
+        let kinematics = axis.kinematics();
+        let steps = match cmd.unit {
+            Unit::Steps => value.round(),
+            Unit::Deg if cmd.relative => {
+                (value.to_radians() * kinematics.steps_per_radian()).round()
+            }
+            Unit::Deg => kinematics.to_steps_f32(value.to_radians()).round(),
+        };
+        let target = if cmd.relative {
+            controller.get_target_pos() as f32 + steps
+        } else {
+            steps
+        };
+        ensure!(
+            target.abs() <= kinematics.steps_per_turn(),
+            "{axis} target is more than a turn away from zero"
+        );
+        targets.push((axis, steps as i32));
+    }
+    Ok(targets)
+}
+
+/// Keep the jogged axes going for `timeout_ms` more.
+///
+/// Target is put just as far ahead as the axis gets in the timeout, plus the distance it
+/// needs to slow down, so it stops by itself once the client stops renewing the jog.
+pub fn jog(cmd: &JogCommand) {
+    let timeout_ms = cmd.timeout_ms.min(MAX_JOG_TIMEOUT_MS);
+    for axis in Axis::ALL {
+        let direction = match cmd.get(axis) {
+            Some(direction) => direction.signum(),
+            None => continue,
+        };
+        let controller = axis.controller();
+        if direction == 0 {
+            controller.stop();
+            continue;
+        }
+        let step_delay_ms = controller.get_step_delay_ms().max(1);
+        let acceleration = controller.get_config().acceleration;
+        let mut lead = timeout_ms / step_delay_ms as u64 + 1;
+        if acceleration > 0.0 {
+            let speed = 1000.0 / step_delay_ms as f32;
+            lead += (speed * speed / (2.0 * acceleration)).ceil() as u64;
+        }
+        // A slow ramp at high speed can ask for more than fits in a position, a turn of the
+        // axis is more than any jog gets through before it's renewed.
+        let turn = axis.kinematics().steps_per_turn().ceil() as u64;
+        let lead = lead.min(turn).min(i32::MAX as u64) as i32;
+        controller.set_target_pos(
+            controller
+                .get_current_pos()
+                .saturating_add(direction * lead),
+        );
+    }
+}
+
+/// Stop both axes where they are.
+pub fn stop() {
+    for axis in Axis::ALL {
+        axis.controller().stop();
+    }
+}
+
+/// Make the current position of `axes` their zero, stopping them.
+pub fn zero(axes: &[Axis]) {
+    for axis in axes {
+        axis.controller().reset();
+    }
+}
+
+/// Move `axes` back to zero, see [`settled`] for waiting till they're there.
+pub fn home(axes: &[Axis]) {
+    for axis in axes {
+        axis.controller().set_target_pos(0);
+    }
+}
+
+/// Wait till both axes have settled.
+pub async fn settled() {
+    tokio::join!(YAW_CONTROLLER.stopped(), PITCH_CONTROLLER.stopped());
+}
+
+pub fn settings() -> MotionSettings {
+    let axis_settings = |axis: Axis| AxisSettings {
+        step_delay_ms: Some(axis.controller().get_step_delay_ms()),
+        acceleration: Some(axis.controller().get_config().acceleration),
+    };
+    MotionSettings {
+        yaw: axis_settings(Axis::Yaw),
+        pitch: axis_settings(Axis::Pitch),
+    }
+}
+
+/// Change motor speed settings, till restart. Nothing is changed if any setting is invalid.
+pub fn set_settings(settings: &MotionSettings) -> Result<()> {
+    for axis in Axis::ALL {
+        let AxisSettings {
+            step_delay_ms,
+            acceleration,
+        } = settings.get(axis);
+        ensure!(step_delay_ms != Some(0), "step delay must be at least 1 ms");
+        if let Some(acceleration) = acceleration {
+            ensure!(
+                acceleration == 0.0
+                    || (acceleration.is_finite() && acceleration >= MIN_ACCELERATION),
+                "acceleration must be 0 or at least {MIN_ACCELERATION} steps/s²"
+            );
+        }
+    }
+    for axis in Axis::ALL {
+        let controller = axis.controller();
+        let AxisSettings {
+            step_delay_ms,
+            acceleration,
+        } = settings.get(axis);
+        if let Some(step_delay_ms) = step_delay_ms {
+            controller.set_step_delay_ms(step_delay_ms);
+        }
+        if let Some(acceleration) = acceleration {
+            let mut config = controller.get_config();
+            config.acceleration = acceleration;
+            controller.set_config(config);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,543 @@
+//! Scan patterns, generating directions to measure inside a [`ScanRegion`].
+//!
+//! # Example
+//! ```ignore
+//! let opts = ScanOptions {
+//!     amount_of_points: 500,
+//!     region: ScanRegion {
+//!         yaw: YawRange { from: -90.0, to: 90.0 },
+//!         pitch: PitchRange { from: 60.0, to: 120.0 },
+//!         exclude: Vec::new(),
+//!     },
+//!     refinement: None,
+//! };
+//! let waypoints = Serpentine.waypoints(&opts, &KINEMATICS);
+//! ```
+
+use crate::kinematics::HeadKinematics;
+use crate::region::ScanRegion;
+use crate::sphere::{Direction, ScanOptions, Waypoint};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{PI, TAU};
+
+pub trait ScanPattern {
+    /// About `n` directions inside yaw and pitch bounds of `region`, exclusions aren't applied.
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction>;
+
+    /// `true` if directions are already in scanning order, so path shouldn't be optimized.
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    /// About `opts.amount_of_points` directions inside `opts.region`. Bounds are covered
+    /// with more points to make up for the excluded ones, keeping the density even.
+    fn directions(&self, opts: &ScanOptions) -> Vec<Direction> {
+        let region = &opts.region;
+        let included = region.included_fraction();
+        if included == 0.0 {
+            return Vec::new();
+        }
+        let n = (opts.amount_of_points as f32 / included).round() as u32;
+        self.cover(region, n)
+            .into_iter()
+            .filter(|direction| region.contains(*direction))
+            .collect()
+    }
+
+    fn waypoints(&self, opts: &ScanOptions, kinematics: &HeadKinematics) -> Vec<Waypoint> {
+        self.directions(opts)
+            .into_iter()
+            .map(|direction| kinematics.waypoint(direction))
+            .collect()
+    }
+}
+
+/// `n` evenly spaced values from `start` to `end`, the middle one if `n` is 1.
+fn linspace(start: f32, end: f32, n: u32) -> impl Iterator<Item = f32> {
+    (0..n).map(move |i| match n {
+        1 => (start + end) / 2.0,
+        _ => start + (end - start) * i as f32 / (n - 1) as f32,
+    })
+}
+
+/// Yaw of `n` grid columns. End is left out of a full turn, it's the start.
+fn yaw_columns(region: &ScanRegion, n: u32) -> Vec<f32> {
+    let (start, span) = (region.yaw.from, region.yaw.span());
+    if span == 360.0 {
+        (0..n).map(|i| start + span * i as f32 / n as f32).collect()
+    } else {
+        linspace(start, start + span, n).collect()
+    }
+}
+
+fn direction(yaw: f32, pitch: f32) -> Direction {
+    Direction {
+        yaw: yaw.to_radians(),
+        pitch: pitch.to_radians(),
+    }
+}
+
+/// Rows and columns of a grid with about `n` cells, as square as the region allows.
+fn grid_size(region: &ScanRegion, n: u32) -> (u32, u32) {
+    let yaw_span = region.yaw.span();
+    let pitch_span = region.pitch.to - region.pitch.from;
+    let cell = (yaw_span * pitch_span / n as f32).sqrt();
+    let rows = ((pitch_span / cell).round() as u32).clamp(1, n.max(1));
+    let columns = ((n as f32 / rows as f32).round() as u32).max(1);
+    (rows, columns)
+}
+
+/// Fibonacci lattice, evenly spread points without rows.
+///
+/// Lattice covers the whole pitch band and only points inside yaw bounds are kept,
+/// so it isn't squeezed into narrow yaw ranges.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fibonacci;
+
+impl ScanPattern for Fibonacci {
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        let n = (n as f32 * 360.0 / region.yaw.span()).round() as u32;
+        let z_from = region.pitch.from.to_radians().cos();
+        let z_to = region.pitch.to.to_radians().cos();
+        let golden_angle: f32 = PI * (3.0 - 5f32.sqrt());
+
+        (1..=n)
+            .filter_map(|index| {
+                let index = index as f32;
+                let yaw = ((golden_angle * index) % TAU).to_degrees();
+                let offset = region.yaw.offset(yaw)?;
+                // Equal steps of z give equal areas of the sphere.
+                let z = z_from + (z_to - z_from) * (index - 0.5) / n as f32;
+                Some(Direction {
+                    yaw: (region.yaw.from + offset).to_radians(),
+                    pitch: z.clamp(-1.0, 1.0).acos(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Regular grid with equal yaw and pitch steps, denser towards the poles.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AngularGrid;
+
+impl ScanPattern for AngularGrid {
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        let (rows, columns) = grid_size(region, n);
+        let yaws = yaw_columns(region, columns);
+        linspace(region.pitch.from, region.pitch.to, rows)
+            .flat_map(|pitch| yaws.iter().map(move |yaw| direction(*yaw, pitch)))
+            .collect()
+    }
+}
+
+/// [`AngularGrid`] scanned row by row, every other row backwards, so the path
+/// doesn't need optimizing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Serpentine;
+
+impl ScanPattern for Serpentine {
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        let (rows, columns) = grid_size(region, n);
+        linspace(region.pitch.from, region.pitch.to, rows)
+            .enumerate()
+            .flat_map(|(row, pitch)| {
+                let mut yaws = yaw_columns(region, columns);
+                if row % 2 == 1 {
+                    yaws.reverse();
+                }
+                yaws.into_iter().map(move |yaw| direction(yaw, pitch))
+            })
+            .collect()
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// Rings of constant pitch split into cells of about the same area,
+/// with a point in the middle of every cell.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EqualAreaGrid;
+
+impl ScanPattern for EqualAreaGrid {
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        let pitch_from = region.pitch.from.to_radians();
+        let pitch_to = region.pitch.to.to_radians();
+        let yaw_span = region.yaw.span();
+        let cell_area = region.bounds_area() / n as f32;
+        let rings = (((pitch_to - pitch_from) / cell_area.sqrt()).round() as u32).max(1);
+        let ring_height = (pitch_to - pitch_from) / rings as f32;
+
+        let mut directions = Vec::new();
+        for ring in 0..rings {
+            let top = pitch_from + ring_height * ring as f32;
+            let bottom = top + ring_height;
+            let ring_area = yaw_span.to_radians() * (top.cos() - bottom.cos());
+            let cells = ((ring_area / cell_area).round() as u32).max(1);
+            // Pitch splitting the ring into halves of equal area.
+            let pitch = ((top.cos() + bottom.cos()) / 2.0).acos();
+            directions.extend((0..cells).map(|cell| {
+                let yaw = region.yaw.from + yaw_span * (cell as f32 + 0.5) / cells as f32;
+                Direction {
+                    yaw: yaw.to_radians(),
+                    pitch,
+                }
+            }));
+        }
+        directions
+    }
+}
+
+/// Profile in a single plane, like a 2D lidar.
+#[derive(Clone, Copy, Debug)]
+pub enum SinglePlane {
+    /// Pitch is swept at `yaw` degrees.
+    Vertical { yaw: f32 },
+    /// Yaw is swept at `pitch` degrees.
+    Horizontal { pitch: f32 },
+}
+
+impl ScanPattern for SinglePlane {
+    /// Empty if the plane is outside of `region` bounds.
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        match *self {
+            SinglePlane::Vertical { yaw } => match region.yaw.offset(yaw) {
+                Some(offset) => linspace(region.pitch.from, region.pitch.to, n)
+                    .map(|pitch| direction(region.yaw.from + offset, pitch))
+                    .collect(),
+                None => Vec::new(),
+            },
+            SinglePlane::Horizontal { pitch } => linspace(region.yaw.from, region.yaw.end(), n)
+                .map(|yaw| direction(yaw, pitch))
+                .collect(),
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// Directions given by the user, ones outside of the region are skipped.
+/// `amount_of_points` is ignored.
+///
+/// Loaded from JSON like `{"angles": [[0.0, 90.0], [10.0, 90.0]], "keep_order": true}`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AngleList {
+    /// Yaw and pitch in degrees.
+    pub angles: Vec<[f32; 2]>,
+    /// Scan in the given order instead of optimizing the path.
+    #[serde(default)]
+    pub keep_order: bool,
+}
+
+impl ScanPattern for AngleList {
+    fn cover(&self, region: &ScanRegion, _n: u32) -> Vec<Direction> {
+        self.angles
+            .iter()
+            .filter_map(|[yaw, pitch]| {
+                let offset = region.yaw.offset(*yaw)?;
+                Some(direction(region.yaw.from + offset, *pitch))
+            })
+            .collect()
+    }
+
+    fn is_ordered(&self) -> bool {
+        self.keep_order
+    }
+}
+
+/// Any of the patterns, picked by name in JSON like `{"type": "vertical", "yaw": 90.0}`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PatternSpec {
+    #[default]
+    Fibonacci,
+    Grid,
+    EqualArea,
+    Serpentine,
+    Vertical {
+        yaw: f32,
+    },
+    Horizontal {
+        pitch: f32,
+    },
+    List(AngleList),
+}
+
+impl ScanPattern for PatternSpec {
+    fn cover(&self, region: &ScanRegion, n: u32) -> Vec<Direction> {
+        match self {
+            PatternSpec::Fibonacci => Fibonacci.cover(region, n),
+            PatternSpec::Grid => AngularGrid.cover(region, n),
+            PatternSpec::EqualArea => EqualAreaGrid.cover(region, n),
+            PatternSpec::Serpentine => Serpentine.cover(region, n),
+            PatternSpec::Vertical { yaw } => SinglePlane::Vertical { yaw: *yaw }.cover(region, n),
+            PatternSpec::Horizontal { pitch } => {
+                SinglePlane::Horizontal { pitch: *pitch }.cover(region, n)
+            }
+            PatternSpec::List(list) => list.cover(region, n),
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        match self {
+            PatternSpec::Fibonacci | PatternSpec::Grid | PatternSpec::EqualArea => false,
+            PatternSpec::Serpentine
+            | PatternSpec::Vertical { .. }
+            | PatternSpec::Horizontal { .. } => true,
+            PatternSpec::List(list) => list.is_ordered(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::{Exclusion, PitchRange, YawRange};
+
+    /// Cells along each axis for density checks.
+    const CELLS: u32 = 6;
+
+    fn options(amount_of_points: u32, region: ScanRegion) -> ScanOptions {
+        ScanOptions {
+            amount_of_points,
+            region,
+            refinement: None,
+        }
+    }
+
+    fn wrapping_band() -> ScanRegion {
+        ScanRegion {
+            yaw: YawRange {
+                from: 300.0,
+                to: 60.0,
+            },
+            pitch: PitchRange {
+                from: 30.0,
+                to: 150.0,
+            },
+            exclude: Vec::new(),
+        }
+    }
+
+    fn with_tripod_and_pole() -> ScanRegion {
+        ScanRegion {
+            exclude: vec![
+                Exclusion::Rect {
+                    yaw: YawRange::FULL,
+                    pitch: PitchRange {
+                        from: 140.0,
+                        to: 180.0,
+                    },
+                },
+                Exclusion::Cone {
+                    yaw: 0.0,
+                    pitch: 0.0,
+                    radius: 20.0,
+                },
+            ],
+            ..ScanRegion::default()
+        }
+    }
+
+    /// Cell of `region` bounds `direction` falls in, cells are of equal area.
+    fn cell(region: &ScanRegion, direction: &Direction) -> (u32, u32) {
+        let z_from = region.pitch.from.to_radians().cos();
+        let z_to = region.pitch.to.to_radians().cos();
+        let z = direction.pitch.cos();
+        let row = ((z - z_from) / (z_to - z_from) * CELLS as f32) as u32;
+        let offset = region.yaw.offset(direction.yaw.to_degrees()).unwrap();
+        let column = (offset / region.yaw.span() * CELLS as f32) as u32;
+        (row.min(CELLS - 1), column.min(CELLS - 1))
+    }
+
+    /// `true` if the whole cell is inside the region, checked on a few samples.
+    fn cell_fully_included(region: &ScanRegion, (row, column): (u32, u32)) -> bool {
+        let z_from = region.pitch.from.to_radians().cos();
+        let z_to = region.pitch.to.to_radians().cos();
+        (0..=10).all(|i| {
+            (0..=10).all(|j| {
+                let z = z_from + (z_to - z_from) * (row as f32 + i as f32 / 10.0) / CELLS as f32;
+                let yaw = region.yaw.from
+                    + region.yaw.span() * (column as f32 + j as f32 / 10.0) / CELLS as f32;
+                region.contains(Direction {
+                    yaw: yaw.to_radians(),
+                    pitch: z.clamp(-1.0, 1.0).acos(),
+                })
+            })
+        })
+    }
+
+    /// Checks point count and that every fully included cell of equal area
+    /// holds about the same number of points.
+    fn assert_even_coverage(pattern: &dyn ScanPattern, opts: &ScanOptions, tolerance: f32) {
+        let region = &opts.region;
+        let directions = pattern.directions(opts);
+        let n = opts.amount_of_points as f32;
+        assert!(
+            (directions.len() as f32 - n).abs() / n < 0.05,
+            "{} points instead of {}",
+            directions.len(),
+            n
+        );
+        assert!(directions.iter().all(|d| region.contains(*d)));
+
+        let mut counts = vec![0u32; (CELLS * CELLS) as usize];
+        for direction in &directions {
+            let (row, column) = cell(region, direction);
+            counts[(row * CELLS + column) as usize] += 1;
+        }
+        let expected = n / region.included_fraction() / (CELLS * CELLS) as f32;
+        let mut checked = 0;
+        for row in 0..CELLS {
+            for column in 0..CELLS {
+                if !cell_fully_included(region, (row, column)) {
+                    continue;
+                }
+                let count = counts[(row * CELLS + column) as usize] as f32;
+                assert!(
+                    (count - expected).abs() / expected < tolerance,
+                    "cell {},{} has {} points, expected {}",
+                    row,
+                    column,
+                    count,
+                    expected
+                );
+                checked += 1;
+            }
+        }
+        assert!(
+            checked >= CELLS * CELLS / 2,
+            "only {} cells checked",
+            checked
+        );
+    }
+
+    #[test]
+    fn fibonacci_covers_evenly() {
+        for region in [
+            ScanRegion::default(),
+            wrapping_band(),
+            with_tripod_and_pole(),
+        ] {
+            assert_even_coverage(&Fibonacci, &options(3000, region), 0.15);
+        }
+    }
+
+    #[test]
+    fn equal_area_grid_covers_evenly() {
+        for region in [
+            ScanRegion::default(),
+            wrapping_band(),
+            with_tripod_and_pole(),
+        ] {
+            assert_even_coverage(&EqualAreaGrid, &options(3000, region), 0.2);
+        }
+    }
+
+    #[test]
+    fn wrapping_yaw_is_continuous() {
+        let opts = options(1000, wrapping_band());
+        for pattern in [
+            &Fibonacci as &dyn ScanPattern,
+            &AngularGrid,
+            &Serpentine,
+            &EqualAreaGrid,
+        ] {
+            let yaws: Vec<f32> = pattern
+                .directions(&opts)
+                .iter()
+                .map(|d| d.yaw.to_degrees())
+                .collect();
+            assert!(yaws.iter().all(|yaw| (299.99..=420.01).contains(yaw)));
+            // Both sides of 0° are covered.
+            assert!(yaws.iter().any(|yaw| *yaw < 330.0));
+            assert!(yaws.iter().any(|yaw| *yaw > 390.0));
+        }
+    }
+
+    #[test]
+    fn grid_is_square_and_includes_bounds() {
+        let region = ScanRegion {
+            yaw: YawRange {
+                from: -90.0,
+                to: 90.0,
+            },
+            pitch: PitchRange {
+                from: 60.0,
+                to: 120.0,
+            },
+            exclude: Vec::new(),
+        };
+        let directions = AngularGrid.directions(&options(300, region));
+        assert_eq!(directions.len(), 300);
+        let pitch_rows = directions
+            .windows(2)
+            .filter(|w| w[0].pitch != w[1].pitch)
+            .count()
+            + 1;
+        assert_eq!(pitch_rows, 10);
+        let first = directions.first().unwrap();
+        let last = directions.last().unwrap();
+        assert!((first.yaw.to_degrees() + 90.0).abs() < 1e-3);
+        assert!((first.pitch.to_degrees() - 60.0).abs() < 1e-3);
+        assert!((last.yaw.to_degrees() - 90.0).abs() < 1e-3);
+        assert!((last.pitch.to_degrees() - 120.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn serpentine_moves_to_neighbours() {
+        let opts = options(500, wrapping_band());
+        let directions = Serpentine.directions(&opts);
+        let (rows, columns) = grid_size(&opts.region, 500);
+        let yaw_step = opts.region.yaw.span() / (columns - 1) as f32;
+        let pitch_step = (opts.region.pitch.to - opts.region.pitch.from) / (rows - 1) as f32;
+        for w in directions.windows(2) {
+            let yaw = (w[1].yaw - w[0].yaw).to_degrees().abs();
+            let pitch = (w[1].pitch - w[0].pitch).to_degrees().abs();
+            assert!(
+                (yaw < yaw_step + 1e-3 && pitch < 1e-3)
+                    || (yaw < 1e-3 && pitch < pitch_step + 1e-3),
+                "jump of {}° yaw and {}° pitch",
+                yaw,
+                pitch
+            );
+        }
+    }
+
+    #[test]
+    fn exclusions_are_skipped() {
+        let opts = options(2000, with_tripod_and_pole());
+        for pattern in [
+            &Fibonacci as &dyn ScanPattern,
+            &AngularGrid,
+            &Serpentine,
+            &EqualAreaGrid,
+            &SinglePlane::Vertical { yaw: 10.0 },
+        ] {
+            let directions = pattern.directions(&opts);
+            assert!(!directions.is_empty());
+            for d in directions {
+                let pitch = d.pitch.to_degrees();
+                assert!((20.0..=140.0).contains(&pitch), "pitch {}", pitch);
+            }
+        }
+    }
+
+    #[test]
+    fn angle_list_keeps_points_inside() {
+        let list = AngleList {
+            angles: vec![[0.0, 90.0], [90.0, 90.0], [350.0, 90.0], [10.0, 170.0]],
+            keep_order: true,
+        };
+        let yaws: Vec<f32> = list
+            .directions(&options(1, wrapping_band()))
+            .iter()
+            .map(|d| d.yaw.to_degrees().round())
+            .collect();
+        assert_eq!(yaws, [360.0, 350.0]);
+    }
+}
@@ -0,0 +1,323 @@
+//! Ordering waypoints into a short path for the motors.
+//!
+//! The path is seeded with a nearest neighbour ordering, or a serpentine one for large point
+//! counts where nearest neighbour gets slow, and then improved with 2-opt until the time
+//! budget runs out, a sweep stops paying off or the planner is cancelled. The best path so far
+//! is reported on the way, so a scan can start before planning is done.
+
+use crate::sphere::Waypoint;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Time between progress reports.
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PlannerOptions {
+    /// Longest time spent improving the path, seconds.
+    pub max_duration_s: f32,
+    /// Planning stops once a sweep shortens the path by less than this part of its cost.
+    pub min_improvement: f32,
+    /// Paths with more points than this are seeded with a serpentine ordering.
+    pub serpentine_seed_above: usize,
+}
+
+impl PlannerOptions {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.max_duration_s >= 0.0,
+            "max_duration_s must be 0 or more"
+        );
+        ensure!(
+            (0.0..1.0).contains(&self.min_improvement),
+            "min_improvement must be 0 to 1"
+        );
+        Ok(())
+    }
+}
+
+impl Default for PlannerOptions {
+    fn default() -> Self {
+        PlannerOptions {
+            max_duration_s: 30.0,
+            min_improvement: 0.001,
+            serpentine_seed_above: 5000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct PlanProgress {
+    /// Cost of the seed ordering, motor steps.
+    pub initial_cost: u64,
+    /// Cost of the best path so far, motor steps.
+    pub cost: u64,
+    pub elapsed: Duration,
+    /// Planner is done, this is the final path.
+    pub finished: bool,
+}
+
+fn cost(a: &Waypoint, b: &Waypoint) -> i64 {
+    a.manhattan_distance(b) as i64
+}
+
+/// Total motor steps travelled along `path`.
+pub fn path_cost(path: &[Waypoint]) -> u64 {
+    path.windows(2).map(|w| cost(&w[0], &w[1]) as u64).sum()
+}
+
+fn nearest_neighbour_order(mut waypoints: Vec<Waypoint>) -> Vec<Waypoint> {
+    for i in 1..waypoints.len() {
+        let last = waypoints[i - 1];
+        let nearest = (i..waypoints.len())
+            .min_by_key(|&j| cost(&last, &waypoints[j]))
+            .unwrap();
+        waypoints.swap(i, nearest);
+    }
+    waypoints
+}
+
+/// Rows of pitch, every other one swept in reverse yaw, rows about as far apart as points in them.
+fn serpentine_order(mut waypoints: Vec<Waypoint>) -> Vec<Waypoint> {
+    let n = waypoints.len();
+    if n == 0 {
+        return waypoints;
+    }
+    let min_pitch = waypoints.iter().map(|w| w.pitch).min().unwrap();
+    let max_pitch = waypoints.iter().map(|w| w.pitch).max().unwrap();
+    let min_yaw = waypoints.iter().map(|w| w.yaw).min().unwrap();
+    let max_yaw = waypoints.iter().map(|w| w.yaw).max().unwrap();
+    let (pitch_extent, yaw_extent) = ((max_pitch - min_pitch) as f32, (max_yaw - min_yaw) as f32);
+
+    // Empty extents give NaN or infinity, which end up at either clamp bound.
+    let rows = ((n as f32 * pitch_extent / yaw_extent).sqrt() as usize).clamp(1, n);
+    let row_height = (pitch_extent + 1.0) / rows as f32;
+    waypoints.sort_by_key(|w| {
+        let row = ((w.pitch - min_pitch) as f32 / row_height) as i64;
+        let yaw = if row % 2 == 0 { w.yaw } else { -w.yaw };
+        (row, yaw as i64)
+    });
+    waypoints
+}
+
+/// Order `waypoints` into a short path, calling `progress` with the best path so far every
+/// half a second and once more when done.
+///
+/// Stops early once `cancelled` is set, returning the best path so far.
+pub fn plan_path<F>(
+    waypoints: Vec<Waypoint>,
+    opts: &PlannerOptions,
+    cancelled: &AtomicBool,
+    mut progress: F,
+) -> Vec<Waypoint>
+where
+    F: FnMut(&[Waypoint], PlanProgress),
+{
+    let start = Instant::now();
+    let budget = Duration::from_secs_f32(opts.max_duration_s.max(0.0));
+    let mut path = if waypoints.len() > opts.serpentine_seed_above {
+        serpentine_order(waypoints)
+    } else {
+        nearest_neighbour_order(waypoints)
+    };
+    let initial_cost = path_cost(&path) as i64;
+    let mut path_len = initial_cost;
+    let mut last_report = Instant::now();
+    let n = path.len();
+
+    'planning: loop {
+        let sweep_start_len = path_len;
+        for i in 0..n.saturating_sub(2) {
+            if cancelled.load(Ordering::Relaxed) || start.elapsed() >= budget {
+                break 'planning;
+            }
+            // Reversing path[i + 1..=j] replaces edges i → i + 1 and j → j + 1
+            // with i → j and i + 1 → j + 1. The path is open, the last point has no next one.
+            for j in i + 2..n {
+                let removed = cost(&path[i], &path[i + 1])
+                    + path.get(j + 1).map_or(0, |next| cost(&path[j], next));
+                let added = cost(&path[i], &path[j])
+                    + path.get(j + 1).map_or(0, |next| cost(&path[i + 1], next));
+                if added < removed {
+                    path[i + 1..=j].reverse();
+                    path_len += added - removed;
+                }
+            }
+            if last_report.elapsed() >= REPORT_INTERVAL {
+                progress(
+                    &path,
+                    PlanProgress {
+                        initial_cost: initial_cost as u64,
+                        cost: path_len as u64,
+                        elapsed: start.elapsed(),
+                        finished: false,
+                    },
+                );
+                last_report = Instant::now();
+            }
+        }
+        if ((sweep_start_len - path_len) as f32) <= opts.min_improvement * sweep_start_len as f32 {
+            break;
+        }
+    }
+
+    progress(
+        &path,
+        PlanProgress {
+            initial_cost: initial_cost as u64,
+            cost: path_len as u64,
+            elapsed: start.elapsed(),
+            finished: true,
+        },
+    );
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scattered waypoints, the same every run.
+    fn scattered(n: usize) -> Vec<Waypoint> {
+        let mut seed = 12345u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i32 % 1000
+        };
+        (0..n)
+            .map(|_| Waypoint {
+                pitch: next(),
+                yaw: next(),
+            })
+            .collect()
+    }
+
+    fn sorted(path: &[Waypoint]) -> Vec<(i32, i32)> {
+        let mut points: Vec<_> = path.iter().map(|w| (w.pitch, w.yaw)).collect();
+        points.sort_unstable();
+        points
+    }
+
+    fn plan(waypoints: Vec<Waypoint>, opts: &PlannerOptions) -> (Vec<Waypoint>, PlanProgress) {
+        let mut last = None;
+        let path = plan_path(waypoints, opts, &AtomicBool::new(false), |_, p| {
+            last = Some(p)
+        });
+        (path, last.unwrap())
+    }
+
+    #[test]
+    fn cancelled_returns_seed() {
+        let waypoints = scattered(200);
+        let seed = nearest_neighbour_order(waypoints.clone());
+        let mut reports = Vec::new();
+        let path = plan_path(
+            waypoints.clone(),
+            &PlannerOptions::default(),
+            &AtomicBool::new(true),
+            |_, p| reports.push(p),
+        );
+        assert_eq!(sorted(&path), sorted(&waypoints));
+        assert_eq!(path_cost(&path), path_cost(&seed));
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].finished);
+        assert_eq!(reports[0].cost, path_cost(&path));
+        assert_eq!(reports[0].initial_cost, reports[0].cost);
+    }
+
+    #[test]
+    fn cancelled_while_planning_returns_best_so_far() {
+        let waypoints = scattered(2000);
+        let seed = nearest_neighbour_order(waypoints.clone());
+        let cancelled = AtomicBool::new(false);
+        let mut last = None;
+        let path = std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                cancelled.store(true, Ordering::Relaxed);
+            });
+            plan_path(
+                waypoints.clone(),
+                &PlannerOptions::default(),
+                &cancelled,
+                |_, p| last = Some(p),
+            )
+        });
+        let last = last.unwrap();
+        assert!(last.finished);
+        assert_eq!(sorted(&path), sorted(&waypoints));
+        assert_eq!(last.cost, path_cost(&path));
+        assert!(last.cost <= path_cost(&seed));
+    }
+
+    #[test]
+    fn reported_cost_is_path_cost() {
+        for serpentine_seed_above in [0, usize::MAX] {
+            let opts = PlannerOptions {
+                serpentine_seed_above,
+                ..PlannerOptions::default()
+            };
+            let (path, progress) = plan(scattered(300), &opts);
+            assert!(progress.finished);
+            assert_eq!(progress.cost, path_cost(&path));
+        }
+    }
+
+    #[test]
+    fn never_longer_than_seed() {
+        let waypoints = scattered(300);
+        for (serpentine_seed_above, seed) in [
+            (0, serpentine_order(waypoints.clone())),
+            (usize::MAX, nearest_neighbour_order(waypoints.clone())),
+        ] {
+            let opts = PlannerOptions {
+                serpentine_seed_above,
+                ..PlannerOptions::default()
+            };
+            let (path, progress) = plan(waypoints.clone(), &opts);
+            assert_eq!(sorted(&path), sorted(&waypoints));
+            assert_eq!(progress.initial_cost, path_cost(&seed));
+            assert!(path_cost(&path) <= path_cost(&seed));
+        }
+    }
+
+    #[test]
+    fn empty_and_single_paths() {
+        for n in [0, 1] {
+            let (path, progress) = plan(scattered(n), &PlannerOptions::default());
+            assert_eq!(path.len(), n);
+            assert_eq!(progress.cost, 0);
+        }
+    }
+
+    #[test]
+    fn serpentine_of_single_row() {
+        let row: Vec<_> = [5, -3, 9, 0]
+            .iter()
+            .map(|&yaw| Waypoint { pitch: 7, yaw })
+            .collect();
+        let yaws: Vec<_> = serpentine_order(row).iter().map(|w| w.yaw).collect();
+        assert_eq!(yaws, [-3, 0, 5, 9]);
+    }
+
+    #[test]
+    fn serpentine_of_single_column() {
+        let column: Vec<_> = [5, -3, 9, 0]
+            .iter()
+            .map(|&pitch| Waypoint { pitch, yaw: 7 })
+            .collect();
+        let path = serpentine_order(column);
+        let pitches: Vec<_> = path.iter().map(|w| w.pitch).collect();
+        assert_eq!(pitches, [-3, 0, 5, 9]);
+    }
+
+    #[test]
+    fn serpentine_of_repeated_point() {
+        let path = serpentine_order(vec![Waypoint { pitch: 1, yaw: 2 }; 3]);
+        assert_eq!(path.len(), 3);
+        assert_eq!(path_cost(&path), 0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Adaptive refinement, picking extra directions to scan after a pass.
+//!
+//! Every scanned sample is compared with its nearest neighbours. Where the range jumps or
+//! the surface normal turns, the scene has an edge in between and a new direction halfway
+//! between the two samples is queued for the next pass. Blank walls get no new points.
+
+use crate::sphere::{Direction, Point};
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+/// When and how densely a scan is refined after the coarse pass.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RefinementOptions {
+    /// Passes after the coarse one.
+    pub max_passes: u32,
+    /// Scanned points after which no more passes are made, coarse pass included.
+    pub max_points: u32,
+    /// Scanning time after which no more passes are made, seconds.
+    pub max_duration_s: Option<f32>,
+    /// Neighbours every sample is compared with.
+    pub neighbours: usize,
+    /// Range difference between neighbours taken as an edge, part of the closer range.
+    pub range_jump: f32,
+    /// Angle between neighbouring surface normals taken as an edge, degrees.
+    pub normal_change_deg: f32,
+    /// Samples closer than this aren't split any further, degrees.
+    pub min_spacing_deg: f32,
+}
+
+impl Default for RefinementOptions {
+    fn default() -> Self {
+        RefinementOptions {
+            max_passes: 3,
+            max_points: 10000,
+            max_duration_s: None,
+            neighbours: 6,
+            range_jump: 0.1,
+            normal_change_deg: 30.0,
+            min_spacing_deg: 0.25,
+        }
+    }
+}
+
+/// Measured direction and its range, meters.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub direction: Direction,
+    pub distance: f32,
+}
+
+fn unit_vector(direction: Direction) -> Vector3<f32> {
+    let Point { x, y, z } = direction.to_point(1.0);
+    Vector3::new(x, y, z)
+}
+
+/// Indices of `k` samples closest to every sample.
+fn nearest_neighbours(units: &[Vector3<f32>], k: usize) -> Vec<Vec<usize>> {
+    units
+        .iter()
+        .enumerate()
+        .map(|(i, unit)| {
+            let mut others: Vec<(usize, f32)> = units
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(j, other)| (j, unit.dot(other)))
+                .collect();
+            let k = k.min(others.len());
+            if k > 0 && k < others.len() {
+                others.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+            }
+            others.truncate(k);
+            others.into_iter().map(|(j, _)| j).collect()
+        })
+        .collect()
+}
+
+/// Surface normal at `points[i]`, the direction its neighbourhood is flattest in.
+fn normal(points: &[Vector3<f32>], i: usize, neighbours: &[usize]) -> Option<Vector3<f32>> {
+    if neighbours.len() < 2 {
+        return None;
+    }
+    let n = (neighbours.len() + 1) as f32;
+    let centroid = neighbours.iter().fold(points[i], |sum, &j| sum + points[j]) / n;
+    let covariance = neighbours
+        .iter()
+        .map(|&j| points[j])
+        .chain(Some(points[i]))
+        .fold(Matrix3::zeros(), |sum, p| {
+            let d = p - centroid;
+            sum + d * d.transpose()
+        });
+    let eigen = covariance.symmetric_eigen();
+    let flattest = eigen.eigenvalues.imin();
+    Some(eigen.eigenvectors.column(flattest).into_owned())
+}
+
+/// How strongly the scene changes between samples `i` and `j`, edges are above 1.
+fn edge_score(
+    samples: &[Sample],
+    normals: &[Option<Vector3<f32>>],
+    opts: &RefinementOptions,
+    i: usize,
+    j: usize,
+) -> f32 {
+    let (a, b) = (samples[i].distance, samples[j].distance);
+    let jump = (a - b).abs() / a.min(b).max(f32::EPSILON) / opts.range_jump;
+    let turn = match (normals[i], normals[j]) {
+        // Normals have no side, so only the angle between their lines counts.
+        (Some(n), Some(m)) => n.dot(&m).abs().min(1.0).acos().to_degrees() / opts.normal_change_deg,
+        _ => 0.0,
+    };
+    jump.max(turn)
+}
+
+/// Directions to scan in the next pass, at most `limit`, strongest edges first.
+pub fn refine(samples: &[Sample], opts: &RefinementOptions, limit: usize) -> Vec<Direction> {
+    let units: Vec<_> = samples.iter().map(|s| unit_vector(s.direction)).collect();
+    let points: Vec<_> = samples
+        .iter()
+        .zip(&units)
+        .map(|(s, unit)| unit * s.distance)
+        .collect();
+    let neighbours = nearest_neighbours(&units, opts.neighbours);
+    let normals: Vec<_> = neighbours
+        .iter()
+        .enumerate()
+        .map(|(i, neighbours)| normal(&points, i, neighbours))
+        .collect();
+
+    // A midpoint closer than the minimal spacing to both samples isn't worth measuring.
+    let min_spacing = opts.min_spacing_deg.to_radians();
+    let mut edges: Vec<(f32, usize, usize)> = Vec::new();
+    for (i, neighbours) in neighbours.iter().enumerate() {
+        for &j in neighbours {
+            let spacing = units[i].dot(&units[j]).min(1.0).acos();
+            if spacing < 2.0 * min_spacing {
+                continue;
+            }
+            let score = edge_score(samples, &normals, opts, i, j);
+            if score > 1.0 {
+                edges.push((score, i.min(j), i.max(j)));
+            }
+        }
+    }
+    // Pairs found from both sides are only taken once.
+    edges.sort_by_key(|&(_, i, j)| (i, j));
+    edges.dedup_by_key(|&mut (_, i, j)| (i, j));
+    edges.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut added: Vec<(Direction, Vector3<f32>)> = Vec::new();
+    for (_, i, j) in edges {
+        if added.len() >= limit {
+            break;
+        }
+        // Samples are close, so the average of their axis angles lies between them.
+        // Neighbours across the seam of a full turn are a turn apart in yaw.
+        let (a, b) = (samples[i].direction, samples[j].direction);
+        let b_yaw = b.yaw + ((a.yaw - b.yaw) / TAU).round() * TAU;
+        let direction = Direction {
+            yaw: (a.yaw + b_yaw) / 2.0,
+            pitch: (a.pitch + b.pitch) / 2.0,
+        };
+        let unit = unit_vector(direction);
+        let mut others = units.iter().chain(added.iter().map(|(_, other)| other));
+        if others.all(|other| unit.dot(other).min(1.0).acos() >= min_spacing) {
+            added.push((direction, unit));
+        }
+    }
+    added.into_iter().map(|(direction, _)| direction).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples every 2° of `yaw` and `pitch` in degrees, `range` away.
+    fn grid(
+        yaw: std::ops::Range<i32>,
+        pitch: std::ops::Range<i32>,
+        range: impl Fn(Direction) -> f32,
+    ) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for yaw in yaw.step_by(2) {
+            for pitch in pitch.clone().step_by(2) {
+                let direction = Direction {
+                    yaw: (yaw as f32).to_radians(),
+                    pitch: (pitch as f32).to_radians(),
+                };
+                let distance = range(direction);
+                samples.push(Sample {
+                    direction,
+                    distance,
+                });
+            }
+        }
+        samples
+    }
+
+    /// Only range jumps count, normals next to a step mix both sides.
+    fn range_only() -> RefinementOptions {
+        RefinementOptions {
+            normal_change_deg: 180.0,
+            ..RefinementOptions::default()
+        }
+    }
+
+    #[test]
+    fn flat_wall_gets_no_points() {
+        // Wall 2 m ahead across the y axis.
+        let samples = grid(-20..21, 70..111, |d| 2.0 / (d.yaw.cos() * d.pitch.sin()));
+        assert!(refine(&samples, &RefinementOptions::default(), 1000).is_empty());
+    }
+
+    #[test]
+    fn range_step_gets_midpoints() {
+        let samples = grid(-9..10, 80..101, |d| if d.yaw < 0.0 { 1.0 } else { 2.0 });
+        let added = refine(&samples, &range_only(), 1000);
+        assert!(!added.is_empty());
+        for direction in added {
+            // Between the columns at -1° and 1°.
+            assert!(direction.yaw.abs() < 1e-4, "{:?}", direction);
+            let pitch = direction.pitch.to_degrees();
+            assert!((80.0..=100.0).contains(&pitch), "{:?}", direction);
+        }
+    }
+
+    #[test]
+    fn midpoints_across_the_seam() {
+        // Columns at 355° to 359° and 1° to 5°, the step is at 0°.
+        let samples = grid(-5..6, 80..101, |d| if d.yaw < 0.0 { 1.0 } else { 2.0 })
+            .into_iter()
+            .map(|mut s| {
+                s.direction.yaw = s.direction.yaw.rem_euclid(TAU);
+                s
+            })
+            .collect::<Vec<_>>();
+        let added = refine(&samples, &range_only(), 1000);
+        assert!(!added.is_empty());
+        for direction in added {
+            let off_seam = (direction.yaw - (direction.yaw / TAU).round() * TAU).abs();
+            assert!(off_seam < 1e-3, "{:?}", direction);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Part of the sphere around the head to scan.
+//!
+//! All angles are axis angles in degrees, see [`crate::kinematics`]. In TOML:
+//! ```toml
+//! yaw = { from = 300.0, to = 60.0 } # Wraps through 0°
+//! pitch = { from = 20.0, to = 150.0 }
+//! exclude = [
+//!     { shape = "cone", yaw = 0.0, pitch = 90.0, radius = 10.0 },
+//!     { shape = "rect", yaw = { from = 170.0, to = 190.0 }, pitch = { from = 100.0, to = 150.0 } },
+//! ]
+//! ```
+
+use crate::sphere::Direction;
+use anyhow::{format_err, Result};
+use serde::{Deserialize, Serialize};
+
+/// Samples per axis when estimating area left after exclusions.
+const AREA_SAMPLES: u32 = 200;
+/// Bounds are widened by this many degrees, so rounding doesn't drop points on the edge.
+const ANGLE_EPSILON: f32 = 1e-3;
+
+/// Yaw going from `from` in the positive direction untill `to`, wrapping around 360°.
+///
+/// Generated yaw goes up continuously from `from`, so `300..60` results in angles
+/// from 300° to 420° and `-60..60` from -60° to 60°, same area at different motor positions.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct YawRange {
+    pub from: f32,
+    pub to: f32,
+}
+
+impl YawRange {
+    pub const FULL: YawRange = YawRange {
+        from: 0.0,
+        to: 360.0,
+    };
+
+    /// Width of the range, 360 for a full turn.
+    pub fn span(&self) -> f32 {
+        if self.to - self.from >= 360.0 {
+            360.0
+        } else {
+            (self.to - self.from).rem_euclid(360.0)
+        }
+    }
+
+    /// End of the range, counting continuously from `from`.
+    pub fn end(&self) -> f32 {
+        self.from + self.span()
+    }
+
+    /// Angle from `from` to `yaw` in the positive direction, if `yaw` is in range.
+    pub fn offset(&self, yaw: f32) -> Option<f32> {
+        let offset = match (yaw - self.from).rem_euclid(360.0) {
+            offset if offset > 360.0 - ANGLE_EPSILON => 0.0,
+            offset => offset,
+        };
+        (offset <= self.span() + ANGLE_EPSILON).then_some(offset.min(self.span()))
+    }
+
+    pub fn contains(&self, yaw: f32) -> bool {
+        self.offset(yaw).is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.from.is_finite() || !self.to.is_finite() {
+            return Err(format_err!("yaw range must be finite"));
+        }
+        if self.to - self.from > 360.0 {
+            return Err(format_err!(
+                "yaw range {}..{} is over a full turn",
+                self.from,
+                self.to
+            ));
+        }
+        if self.span() == 0.0 {
+            return Err(format_err!("yaw range {}..{} is empty", self.from, self.to));
+        }
+        Ok(())
+    }
+}
+
+/// Pitch from `from` to `to`, 0 pointing up and 180 down.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PitchRange {
+    pub from: f32,
+    pub to: f32,
+}
+
+impl PitchRange {
+    pub const FULL: PitchRange = PitchRange {
+        from: 0.0,
+        to: 180.0,
+    };
+
+    pub fn contains(&self, pitch: f32) -> bool {
+        (self.from - ANGLE_EPSILON..=self.to + ANGLE_EPSILON).contains(&pitch)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=180.0).contains(&self.from) || !(0.0..=180.0).contains(&self.to) {
+            return Err(format_err!(
+                "pitch range {}..{} must be within 0..180",
+                self.from,
+                self.to
+            ));
+        }
+        if self.from >= self.to {
+            return Err(format_err!(
+                "pitch range {}..{} is empty",
+                self.from,
+                self.to
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Area left out of a scan, e.g. the tripod.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Exclusion {
+    Rect {
+        yaw: YawRange,
+        pitch: PitchRange,
+    },
+    /// Everything within `radius` degrees of `yaw` and `pitch` direction.
+    Cone {
+        yaw: f32,
+        pitch: f32,
+        radius: f32,
+    },
+}
+
+impl Exclusion {
+    pub fn contains(&self, direction: Direction) -> bool {
+        match *self {
+            Exclusion::Rect { yaw, pitch } => {
+                yaw.contains(direction.yaw.to_degrees())
+                    && pitch.contains(direction.pitch.to_degrees())
+            }
+            Exclusion::Cone { yaw, pitch, radius } => {
+                let center = Direction {
+                    yaw: yaw.to_radians(),
+                    pitch: pitch.to_radians(),
+                }
+                .to_point(1.0);
+                let point = direction.to_point(1.0);
+                let cos = center.x * point.x + center.y * point.y + center.z * point.z;
+                cos >= radius.to_radians().cos()
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Exclusion::Rect { yaw, pitch } => yaw.validate().and(pitch.validate()),
+            Exclusion::Cone { radius, .. } if !(*radius > 0.0 && *radius <= 180.0) => Err(
+                format_err!("exclusion cone radius {radius} must be within 0..180"),
+            ),
+            Exclusion::Cone { .. } => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScanRegion {
+    pub yaw: YawRange,
+    pub pitch: PitchRange,
+    #[serde(default)]
+    pub exclude: Vec<Exclusion>,
+}
+
+impl Default for ScanRegion {
+    fn default() -> Self {
+        ScanRegion {
+            yaw: YawRange::FULL,
+            pitch: PitchRange::FULL,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl ScanRegion {
+    /// `true` if `direction` is inside the bounds and not excluded.
+    pub fn contains(&self, direction: Direction) -> bool {
+        self.yaw.contains(direction.yaw.to_degrees())
+            && self.pitch.contains(direction.pitch.to_degrees())
+            && !self.exclude.iter().any(|zone| zone.contains(direction))
+    }
+
+    /// Area inside yaw and pitch bounds, steradians.
+    pub fn bounds_area(&self) -> f32 {
+        self.yaw.span().to_radians()
+            * (self.pitch.from.to_radians().cos() - self.pitch.to.to_radians().cos())
+    }
+
+    /// Part of [`Self::bounds_area`] left after exclusions, estimated on a grid.
+    pub fn included_fraction(&self) -> f32 {
+        if self.exclude.is_empty() {
+            return 1.0;
+        }
+        let (z_from, z_to) = (
+            self.pitch.from.to_radians().cos(),
+            self.pitch.to.to_radians().cos(),
+        );
+        let center = |i: u32| (i as f32 + 0.5) / AREA_SAMPLES as f32;
+        let included = (0..AREA_SAMPLES)
+            .flat_map(|i| (0..AREA_SAMPLES).map(move |j| (i, j)))
+            .filter(|&(i, j)| {
+                // Equal steps of z and yaw are cells of equal area.
+                let z = z_from + (z_to - z_from) * center(i);
+                let yaw = self.yaw.from + self.yaw.span() * center(j);
+                self.contains(Direction {
+                    yaw: yaw.to_radians(),
+                    pitch: z.acos(),
+                })
+            })
+            .count();
+        included as f32 / (AREA_SAMPLES * AREA_SAMPLES) as f32
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.yaw.validate()?;
+        self.pitch.validate()?;
+        for zone in &self.exclude {
+            zone.validate()?;
+        }
+        if self.included_fraction() == 0.0 {
+            return Err(format_err!("whole region is excluded"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direction(yaw: f32, pitch: f32) -> Direction {
+        Direction {
+            yaw: yaw.to_radians(),
+            pitch: pitch.to_radians(),
+        }
+    }
+
+    #[test]
+    fn yaw_range_wraps_around() {
+        let range = YawRange {
+            from: 300.0,
+            to: 60.0,
+        };
+        assert_eq!(range.span(), 120.0);
+        assert_eq!(range.end(), 420.0);
+        assert!(range.contains(330.0));
+        assert!(range.contains(0.0));
+        assert!(range.contains(-30.0));
+        assert!(range.contains(59.0));
+        assert!(!range.contains(90.0));
+        assert!(!range.contains(299.0));
+        assert_eq!(range.offset(10.0), Some(70.0));
+    }
+
+    #[test]
+    fn full_yaw_range_contains_everything() {
+        assert_eq!(YawRange::FULL.span(), 360.0);
+        for yaw in [-720.0, -1.0, 0.0, 180.0, 359.9, 360.0, 1000.0] {
+            assert!(YawRange::FULL.contains(yaw), "{}", yaw);
+        }
+    }
+
+    #[test]
+    fn invalid_regions_are_rejected() {
+        let valid = ScanRegion::default();
+        assert!(valid.validate().is_ok());
+
+        let empty_yaw = ScanRegion {
+            yaw: YawRange {
+                from: 10.0,
+                to: 10.0,
+            },
+            ..valid.clone()
+        };
+        assert!(empty_yaw.validate().is_err());
+
+        let over_full_turn = ScanRegion {
+            yaw: YawRange {
+                from: 0.0,
+                to: 400.0,
+            },
+            ..valid.clone()
+        };
+        assert!(over_full_turn.validate().is_err());
+
+        for (from, to) in [(90.0, 30.0), (-10.0, 90.0), (0.0, 190.0), (f32::NAN, 90.0)] {
+            let pitch = ScanRegion {
+                pitch: PitchRange { from, to },
+                ..valid.clone()
+            };
+            assert!(pitch.validate().is_err(), "{}..{}", from, to);
+        }
+
+        let bad_cone = ScanRegion {
+            exclude: vec![Exclusion::Cone {
+                yaw: 0.0,
+                pitch: 90.0,
+                radius: -5.0,
+            }],
+            ..valid.clone()
+        };
+        assert!(bad_cone.validate().is_err());
+
+        let all_excluded = ScanRegion {
+            exclude: vec![Exclusion::Rect {
+                yaw: YawRange::FULL,
+                pitch: PitchRange::FULL,
+            }],
+            ..valid
+        };
+        assert!(all_excluded.validate().is_err());
+    }
+
+    #[test]
+    fn exclusions() {
+        let cone = Exclusion::Cone {
+            yaw: 0.0,
+            pitch: 90.0,
+            radius: 10.0,
+        };
+        assert!(cone.contains(direction(5.0, 95.0)));
+        assert!(cone.contains(direction(359.0, 90.0)));
+        assert!(!cone.contains(direction(15.0, 90.0)));
+        assert!(!cone.contains(direction(180.0, 90.0)));
+
+        let tripod = Exclusion::Rect {
+            yaw: YawRange::FULL,
+            pitch: PitchRange {
+                from: 150.0,
+                to: 180.0,
+            },
+        };
+        assert!(tripod.contains(direction(123.0, 170.0)));
+        assert!(!tripod.contains(direction(123.0, 140.0)));
+    }
+
+    #[test]
+    fn included_fraction_matches_excluded_area() {
+        // Cap below 120° pitch is a quarter of the sphere.
+        let region = ScanRegion {
+            exclude: vec![Exclusion::Rect {
+                yaw: YawRange::FULL,
+                pitch: PitchRange {
+                    from: 120.0,
+                    to: 180.0,
+                },
+            }],
+            ..ScanRegion::default()
+        };
+        assert!((region.included_fraction() - 0.75).abs() < 0.01);
+
+        // Cone of 60° radius at the pole is a quarter of the sphere too.
+        let region = ScanRegion {
+            exclude: vec![Exclusion::Cone {
+                yaw: 0.0,
+                pitch: 0.0,
+                radius: 60.0,
+            }],
+            ..ScanRegion::default()
+        };
+        assert!((region.included_fraction() - 0.75).abs() < 0.01);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let region = ScanRegion {
+            yaw: YawRange {
+                from: 300.0,
+                to: 60.0,
+            },
+            pitch: PitchRange {
+                from: 20.0,
+                to: 150.0,
+            },
+            exclude: vec![
+                Exclusion::Cone {
+                    yaw: 0.0,
+                    pitch: 90.0,
+                    radius: 10.0,
+                },
+                Exclusion::Rect {
+                    yaw: YawRange {
+                        from: 170.0,
+                        to: 190.0,
+                    },
+                    pitch: PitchRange {
+                        from: 100.0,
+                        to: 150.0,
+                    },
+                },
+            ],
+        };
+        let json = serde_json::to_string(&region).unwrap();
+        assert_eq!(serde_json::from_str::<ScanRegion>(&json).unwrap(), region);
+
+        let toml: ScanRegion = toml::from_str(
+            r#"
+            yaw = { from = 300.0, to = 60.0 }
+            pitch = { from = 20.0, to = 150.0 }
+            exclude = [
+                { shape = "cone", yaw = 0.0, pitch = 90.0, radius = 10.0 },
+                { shape = "rect", yaw = { from = 170.0, to = 190.0 }, pitch = { from = 100.0, to = 150.0 } },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(toml, region);
+    }
+}
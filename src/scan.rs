@@ -1,85 +1,531 @@
 #![allow(clippy::new_without_default)] // TODO remove after finished developing
 
+use crate::config;
+use crate::export::{export, ExportFormat};
+use crate::hardware::distance::DistanceReading;
+use crate::hardware::motor::StepMotorController;
+use crate::hardware::stall_detection::*;
+use crate::hardware::{
+    DISTANCE_CONTROLLER, KINEMATICS, ORIENTATION_CONTROLLER, PITCH_CONTROLLER, YAW_CONTROLLER,
+};
+use crate::kinematics::AxisKinematics;
+use crate::pattern::{PatternSpec, ScanPattern};
+use crate::planner::{path_cost, plan_path, PlanProgress};
+use crate::refine::{refine, RefinementOptions, Sample};
 use crate::shared::*;
+use crate::shutdown::{self, on_shutdown, ShutdownStage};
 use crate::sphere::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::Mutex;
-use crate::hardware::distance::DistanceReading;
-use crate::hardware::{
-    DISTANCE_CONTROLLER, MPU_CONTROLLER, ORIENTATION_CONTROLLER, PITCH_CONTROLLER, YAW_CONTROLLER,
-};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, info_span, warn};
+
+/// File scanned points are saved to.
+pub const SCAN_FILE_PATH: &str = "points.json";
+
+/// Directory every scan is kept in, as `<id>/info.json` and `<id>/points.json`.
+pub const SCANS_DIR: &str = "scans";
 
-#[derive(Serialize, Deserialize)]
+/// How often a scan waiting for its refinement pass to be planned checks on it.
+const PLANNING_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScannedCheckpoint {
-    x: f32,
-    y: f32,
-    z: f32,
-    waypoint_yaw: i32,
-    waypoint_pitch: i32,
-    current_yaw: i32,
-    current_pitch: i32,
-    roll: f32,
-    pitch: f32,
-    yaw: f32,
-    distance: u32,
-    quality: u32,
-}
-
-#[derive(Eq, PartialEq, Clone, Copy)]
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub waypoint_yaw: i32,
+    pub waypoint_pitch: i32,
+    pub current_yaw: i32,
+    pub current_pitch: i32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub distance: u32,
+    pub quality: u32,
+    /// An axis missed steps earlier in the scan, position might be off.
+    pub misregistered: bool,
+    /// Pass the point was scanned in, 0 for the coarse one.
+    pub pass: u32,
+}
+
+/// Events published by a scan job, see [`ScanJob::subscribe`].
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ScanEvent {
+    /// Current scan and everything scanned so far, see [`ScanJob::subscribe_with_snapshot`].
+    /// Never published.
+    Snapshot {
+        status: Option<ScanStatus>,
+        points: Vec<ScannedCheckpoint>,
+    },
+    /// Path planner found a shorter path or finished.
+    Planning(PlanProgress),
+    /// Scan changed its state.
+    State {
+        id: u64,
+        state: ScanState,
+    },
+    /// Refinement pass was queued.
+    Refinement {
+        pass: u32,
+        points: usize,
+    },
+    /// Point was scanned, `index` is its position in the scan.
+    Point {
+        index: usize,
+        point: ScannedCheckpoint,
+    },
+    /// Axis move disagreed with the IMU.
+    MissedSteps {
+        axis: String,
+        check: MoveCheck,
+    },
+    /// Rangefinder failed to measure point `index`, it's measured again.
+    MeasurementFailed {
+        index: usize,
+        error: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum ScanState {
+    /// Path is generated, scan wasn't started yet.
+    Created,
     Scanning,
     Paused,
+    Finished,
+    Cancelled,
     Dead,
 }
 
+impl ScanState {
+    pub const ALL: [ScanState; 6] = [
+        ScanState::Created,
+        ScanState::Scanning,
+        ScanState::Paused,
+        ScanState::Finished,
+        ScanState::Cancelled,
+        ScanState::Dead,
+    ];
+}
+
+impl fmt::Display for ScanState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ScanState::Created => "created",
+            ScanState::Scanning => "scanning",
+            ScanState::Paused => "paused",
+            ScanState::Finished => "finished",
+            ScanState::Cancelled => "cancelled",
+            ScanState::Dead => "dead",
+        };
+        f.write_str(name)
+    }
+}
+
 impl IsDead for ScanState {
     fn is_dead(&self) -> bool {
         *self == ScanState::Dead
     }
 }
 
+/// Scan as it's kept in the history.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScanInfo {
+    pub id: u64,
+    /// Unix time the scan was created, seconds.
+    pub created: u64,
+    pub state: ScanState,
+    pub options: ScanOptions,
+    pub pattern: PatternSpec,
+    pub points_scanned: usize,
+    /// Refinement passes made after the coarse one.
+    pub passes: u32,
+}
+
+/// Progress of the current scan.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScanStatus {
+    #[serde(flatten)]
+    pub info: ScanInfo,
+    /// Waypoints queued so far, refinement passes queue more.
+    pub waypoints: usize,
+    /// Path is still being planned in the background.
+    pub planning: bool,
+    /// Time spent scanning, seconds.
+    pub elapsed_s: f32,
+    /// Time left to scan the queued waypoints, seconds. `None` until a point is scanned.
+    pub eta_s: Option<f32>,
+}
+
+/// Path of the current scan.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScanPath {
+    pub waypoints: Vec<Waypoint>,
+    /// Motor steps travelled along the path.
+    pub cost: u64,
+    /// Path is still being planned in the background and may change.
+    pub planning: bool,
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    /// Options or pattern can't be scanned.
+    Invalid(anyhow::Error),
+    /// There is no such scan.
+    NotFound,
+    /// Scan can't do that in its current state.
+    Conflict(String),
+    /// Reading or writing saved scans failed.
+    Storage(anyhow::Error),
+    /// Scan thread is gone, nothing can be scanned till restart.
+    Stopped,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::Invalid(e) => write!(f, "invalid scan: {e}"),
+            ScanError::NotFound => write!(f, "scan not found"),
+            ScanError::Conflict(reason) => write!(f, "{reason}"),
+            ScanError::Storage(e) => write!(f, "scan storage failed: {e}"),
+            ScanError::Stopped => write!(f, "scan thread has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+fn scan_dir(id: u64) -> PathBuf {
+    PathBuf::from(SCANS_DIR).join(id.to_string())
+}
+
+/// Every scan saved in [`SCANS_DIR`], oldest first.
+pub fn saved_scans() -> anyhow::Result<Vec<ScanInfo>> {
+    let entries = match std::fs::read_dir(SCANS_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut scans = Vec::new();
+    for entry in entries {
+        let path = entry?.path().join("info.json");
+        // Directories without a readable info aren't scans.
+        if let Ok(json) = std::fs::read_to_string(&path) {
+            scans.push(serde_json::from_str::<ScanInfo>(&json)?);
+        }
+    }
+    scans.sort_by_key(|scan| scan.id);
+    Ok(scans)
+}
+
+fn load_scan(id: u64) -> Result<ScanInfo, ScanError> {
+    let json =
+        std::fs::read_to_string(scan_dir(id).join("info.json")).map_err(|_| ScanError::NotFound)?;
+    serde_json::from_str(&json).map_err(|e| ScanError::Storage(e.into()))
+}
+
+fn load_points(id: u64) -> Result<Vec<ScannedCheckpoint>, ScanError> {
+    let json = std::fs::read_to_string(scan_dir(id).join("points.json"))
+        .map_err(|_| ScanError::NotFound)?;
+    serde_json::from_str(&json).map_err(|e| ScanError::Storage(e.into()))
+}
+
 struct ScanJobData {
+    /// Scan being worked on, `None` until the first one is created.
+    scan: Option<ScanInfo>,
     waypoints: Vec<Waypoint>,
     scanned_points: Vec<ScannedCheckpoint>,
+    refinement: Option<RefinementOptions>,
+    /// Pass waypoints are currently scanned from, 0 for the coarse one.
+    pass: u32,
+    /// Time spent scanning points.
+    scan_time: Duration,
+    /// Cancels the background planner, `None` if the path isn't being planned.
+    planning: Option<Arc<AtomicBool>>,
+    /// Scan is paused before the next move or measurement, see [`ScanJob::halt`].
+    halt: bool,
+    /// Axes that missed steps in this scan and weren't corrected since, points scanned while
+    /// there are any are marked as misregistered. Kept over pauses.
+    misregistered_axes: Vec<&'static str>,
+    /// Published while holding the lock, so events are in order with the data.
+    events: Arc<EventStream<ScanEvent>>,
 }
 
 impl ScanJobData {
-    pub fn new() -> Self {
+    pub fn new(events: Arc<EventStream<ScanEvent>>) -> Self {
         ScanJobData {
-            waypoints: Vec::new(), 
-            scanned_points: Vec::new(), 
+            scan: None,
+            waypoints: Vec::new(),
+            scanned_points: Vec::new(),
+            refinement: None,
+            pass: 0,
+            scan_time: Duration::ZERO,
+            planning: None,
+            halt: false,
+            misregistered_axes: Vec::new(),
+            events,
         }
     }
 }
 
+/// Scanned samples a refinement pass is planned from.
+struct RefinementPass {
+    samples: Vec<Sample>,
+    opts: RefinementOptions,
+    /// Points left in the budget.
+    limit: usize,
+}
+
+impl RefinementPass {
+    /// New waypoints, unplanned.
+    fn waypoints(&self) -> Vec<Waypoint> {
+        refine(&self.samples, &self.opts, self.limit)
+            .into_iter()
+            .map(|direction| KINEMATICS.waypoint(direction))
+            .collect()
+    }
+}
+
+enum NextPoint {
+    Waypoint(usize, Waypoint),
+    Refine(RefinementPass),
+    Finished,
+}
+
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
 use std::thread;
-use std::time::{Instant, Duration};
-use spinners::{Spinner, Spinners};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 impl ScanJobData {
-    pub fn generate_path(&mut self, opts: ScanOptions) {
-        let start = Instant::now();
-        let mut sp = Spinner::new(Spinners::Dots9, "Building a path.".into());
+    /// Replace the path with unplanned `waypoints`, stopping the planner of the old one.
+    fn set_path(&mut self, waypoints: Vec<Waypoint>, refinement: Option<RefinementOptions>) {
+        self.cancel_planning();
+        self.waypoints = waypoints;
+        self.refinement = refinement;
+        self.pass = 0;
+    }
 
-        let points = crate::sphere::generate_points(opts);
-        let waypoints: Vec<Waypoint> = points.into_iter().map(|p| p.into()).collect();
-        self.waypoints = optimize_path(waypoints, Duration::from_secs(30));
-        sp.stop_and_persist(
-            "✔",
-            format!("Done path building in {:?}", start.elapsed()),
-            );
+    /// Stop the background planner, the best path so far stays.
+    fn cancel_planning(&mut self) {
+        if let Some(cancelled) = self.planning.take() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Next waypoint to scan, or a refinement pass once the queued ones are done.
+    fn next_point(&self) -> NextPoint {
+        let point_number = self.scanned_points.len();
+        if let Some(waypoint) = self.waypoints.get(point_number) {
+            return NextPoint::Waypoint(point_number, *waypoint);
+        }
+        let opts = match &self.refinement {
+            Some(opts) => opts,
+            None => return NextPoint::Finished,
+        };
+        let out_of_time = opts
+            .max_duration_s
+            .is_some_and(|max| self.scan_time.as_secs_f32() >= max);
+        let limit = (opts.max_points as usize).saturating_sub(point_number);
+        if self.pass >= opts.max_passes || limit == 0 || out_of_time {
+            return NextPoint::Finished;
+        }
+        let samples = self
+            .scanned_points
+            .iter()
+            .map(|checkpoint| Sample {
+                direction: KINEMATICS.direction(Waypoint {
+                    yaw: checkpoint.waypoint_yaw,
+                    pitch: checkpoint.waypoint_pitch,
+                }),
+                distance: checkpoint.distance as f32 / 1000.0,
+            })
+            .collect();
+        NextPoint::Refine(RefinementPass {
+            samples,
+            opts: opts.clone(),
+            limit,
+        })
+    }
+
+    /// Current scan with its point counts.
+    fn info(&self) -> Option<ScanInfo> {
+        self.scan.clone().map(|scan| ScanInfo {
+            points_scanned: self.scanned_points.len(),
+            passes: self.pass,
+            ..scan
+        })
+    }
+
+    /// Current scan, while its path can still be changed.
+    fn created_scan(&self) -> Result<&ScanInfo, ScanError> {
+        let scan = self.scan.as_ref().ok_or(ScanError::NotFound)?;
+        if scan.state != ScanState::Created {
+            return Err(ScanError::Conflict(format!(
+                "scan {} was already started",
+                scan.id
+            )));
+        }
+        Ok(scan)
+    }
+
+    fn status(&self) -> Option<ScanStatus> {
+        let info = self.info()?;
+        let scanned = self.scanned_points.len();
+        let elapsed_s = self.scan_time.as_secs_f32();
+        let eta_s = (scanned > 0).then(|| {
+            let remaining = self.waypoints.len().saturating_sub(scanned);
+            elapsed_s / scanned as f32 * remaining as f32
+        });
+        Some(ScanStatus {
+            info,
+            waypoints: self.waypoints.len(),
+            planning: self.planning.is_some(),
+            elapsed_s,
+            eta_s,
+        })
+    }
+
+    fn set_state(&mut self, state: ScanState) {
+        if let Some(scan) = &mut self.scan {
+            scan.state = state;
+            let id = scan.id;
+            self.events.publish(ScanEvent::State { id, state });
+        }
+    }
+
+    /// Save points to [`SCAN_FILE_PATH`], and the whole scan to [`SCANS_DIR`].
+    fn save(&self) -> anyhow::Result<()> {
+        let json_string = serde_json::to_string(&self.scanned_points)?;
+        std::fs::write(SCAN_FILE_PATH, &json_string)?;
+        if let Some(info) = self.info() {
+            let dir = scan_dir(info.id);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("points.json"), json_string)?;
+            std::fs::write(dir.join("info.json"), serde_json::to_string(&info)?)?;
+        }
+        Ok(())
+    }
+
+    fn save_or_complain(&self) {
+        if let Err(e) = self.save() {
+            error!(error = %e, "Failed to save scan");
+            self.events.publish(ScanEvent::Error {
+                message: format!("Failed to save scan: {e}"),
+            });
+        }
     }
 }
 
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError};
+
+/// Plan the path from waypoint `from` on in a background thread, updating it whenever a shorter
+/// one is found. Waypoints before `from` are left as they are.
+///
+/// The job lock is only taken to store the path, and the path is left alone once planning is
+/// cancelled, so a scan can start with the best path so far.
+fn plan_in_background(data: &Arc<Mutex<ScanJobData>>, from: usize) {
+    let opts = config::current().path_planner.unwrap_or_default();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (waypoints, events) = {
+        let mut data = data.lock().unwrap();
+        data.planning = Some(cancelled.clone());
+        (data.waypoints[from..].to_vec(), data.events.clone())
+    };
+    let data = data.clone();
+    thread::spawn(move || {
+        plan_path(waypoints, &opts, &cancelled, |path, progress| {
+            {
+                let mut data = data.lock().unwrap();
+                if !cancelled.load(Ordering::Relaxed) {
+                    data.waypoints.truncate(from);
+                    data.waypoints.extend_from_slice(path);
+                    if progress.finished {
+                        data.planning = None;
+                    }
+                }
+            }
+            if progress.finished {
+                info!(
+                    elapsed_s = progress.elapsed.as_secs_f32(),
+                    cost = progress.cost,
+                    initial_cost = progress.initial_cost,
+                    "Done path planning"
+                );
+            }
+            events.publish(ScanEvent::Planning(progress));
+        });
+    });
+}
+
+enum AxisMove {
+    Done,
+    /// Axis missed steps and its position was corrected by the measured rotation.
+    Rehomed,
+    /// Axis missed steps, position is off from now on.
+    Stalled,
+    /// Axis missed steps, position is off till it's rehomed.
+    Pause,
+}
+
+/// Move axis to `target`, handling missed steps according to `config`.
+fn move_axis(
+    name: &str,
+    motor: &StepMotorController,
+    axis: &AxisKinematics,
+    target: i32,
+    config: &StallDetectionConfig,
+    events: &EventStream<ScanEvent>,
+) -> AxisMove {
+    let mut attempts = 0;
+    loop {
+        let check = checked_move(motor, axis, target, config, current_orientation);
+        let measured_pos = match check.measured_pos(axis) {
+            Some(measured_pos) if check.is_stall(config) => measured_pos,
+            _ if attempts > 0 => return AxisMove::Rehomed,
+            _ => return AxisMove::Done,
+        };
+        warn!(
+            axis = name,
+            measured_deg = check.measured_deg.unwrap_or_default(),
+            commanded_deg = check.commanded_deg,
+            start_pos = check.start_pos,
+            end_pos = check.end_pos,
+            "Axis missed steps"
+        );
+        events.publish(ScanEvent::MissedSteps {
+            axis: name.to_string(),
+            check,
+        });
+        match config.action {
+            StallAction::Annotate => return AxisMove::Stalled,
+            StallAction::Rehome if attempts < config.max_rehome_attempts => {
+                warn!(axis = name, pos = measured_pos, "Correcting axis position");
+                motor.set_target_pos(measured_pos);
+                motor.set_current_pos(measured_pos);
+                attempts += 1;
+            }
+            StallAction::Pause | StallAction::Rehome => return AxisMove::Pause,
+        }
+    }
+}
 
 enum ScanJobMsg {
-    GeneratePath(ScanOptions),
     StartScan,
     PauseScan,
+    CancelScan,
     SaveFile,
 }
 
@@ -91,122 +537,478 @@ pub struct ScanJob {
 impl ScanJob {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::sync_channel(1); // FIXME maybe 0?
-        let data = Arc::new(Mutex::new(ScanJobData::new()));
+        let events = Arc::new(EventStream::new());
+        let data = Arc::new(Mutex::new(ScanJobData::new(events)));
         let data_clone = data.clone();
-        thread::spawn( move || {
+
+        // Lock may be poisoned if shutdown was caused by a panic in the scan thread.
+        let shutdown_data = Arc::downgrade(&data);
+        on_shutdown(ShutdownStage::SaveScan, "save scan", move || {
+            if let Some(data) = shutdown_data.upgrade() {
+                let data = data.lock().unwrap_or_else(PoisonError::into_inner);
+                if !data.scanned_points.is_empty() {
+                    match data.save() {
+                        Ok(()) => info!(path = SCAN_FILE_PATH, "Saved scan"),
+                        Err(e) => error!(error = %e, "Failed to save scan"),
+                    }
+                }
+            }
+        });
+
+        thread::spawn(move || {
             scan_job(rx, data_clone);
         });
-        ScanJob{data, tx}
+        ScanJob { data, tx }
+    }
+
+    /// Receive events of this job from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<ScanEvent> {
+        self.data.lock().unwrap().events.subscribe()
+    }
+
+    /// [`ScanEvent::Snapshot`] of the current scan and events following it,
+    /// without missing or repeating points in between.
+    pub fn subscribe_with_snapshot(&self) -> (ScanEvent, UnboundedReceiver<ScanEvent>) {
+        let data = self.data.lock().unwrap();
+        let snapshot = ScanEvent::Snapshot {
+            status: data.status(),
+            points: data.scanned_points.clone(),
+        };
+        (snapshot, data.events.subscribe())
+    }
+
+    /// Replace the current scan with a new one, generating its path through `pattern`.
+    ///
+    /// Unfinished current scan is saved as cancelled. Fails while scanning.
+    pub fn create_scan(
+        &self,
+        options: ScanOptions,
+        pattern: PatternSpec,
+    ) -> Result<ScanInfo, ScanError> {
+        options.validate().map_err(ScanError::Invalid)?;
+        let mut data = self.data.lock().unwrap();
+        if let Some(scan) = &data.scan {
+            match scan.state {
+                ScanState::Scanning => {
+                    return Err(ScanError::Conflict(format!("scan {} is running", scan.id)))
+                }
+                ScanState::Created | ScanState::Paused => {
+                    data.set_state(ScanState::Cancelled);
+                    data.save_or_complain();
+                }
+                ScanState::Finished | ScanState::Cancelled | ScanState::Dead => {}
+            }
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        data.cancel_planning();
+        *data = ScanJobData::new(data.events.clone());
+        data.scan = Some(ScanInfo {
+            id: now.as_millis() as u64,
+            created: now.as_secs(),
+            state: ScanState::Created,
+            options,
+            pattern: PatternSpec::default(),
+            points_scanned: 0,
+            passes: 0,
+        });
+        data.set_state(ScanState::Created);
+        drop(data);
+        self.generate_path(pattern)?;
+        Ok(self.data.lock().unwrap().info().unwrap())
     }
 
-    pub fn generate_path(&self, opts: ScanOptions) {
-        self.tx.send(ScanJobMsg::GeneratePath(opts)).unwrap();
+    /// Generate path of the current scan through `pattern` and plan it in the background.
+    ///
+    /// Planning progress is published as [`ScanEvent::Planning`]. Starting the scan before
+    /// planning is done scans the best path so far. Fails once the scan was started.
+    pub fn generate_path(&self, pattern: PatternSpec) -> Result<(), ScanError> {
+        let (id, options) = {
+            let data = self.data.lock().unwrap();
+            let scan = data.created_scan()?;
+            (scan.id, scan.options.clone())
+        };
+        // Large patterns take a while, status and events aren't held up meanwhile.
+        let waypoints = pattern.waypoints(&options, &KINEMATICS);
+        if waypoints.is_empty() {
+            return Err(ScanError::Invalid(anyhow::format_err!(
+                "pattern has no points inside the region"
+            )));
+        }
+        let mut data = self.data.lock().unwrap();
+        if data.created_scan()?.id != id {
+            return Err(ScanError::Conflict(
+                "scan was replaced while generating its path".to_string(),
+            ));
+        }
+        let ordered = pattern.is_ordered();
+        let scan = data.scan.as_mut().unwrap();
+        scan.pattern = pattern;
+        let refinement = scan.options.refinement.clone();
+        data.set_path(waypoints, refinement);
+        data.save_or_complain();
+        drop(data);
+        if !ordered {
+            plan_in_background(&self.data, 0);
+        }
+        Ok(())
+    }
+
+    /// Path of the current scan.
+    pub fn path(&self) -> Option<ScanPath> {
+        let data = self.data.lock().unwrap();
+        data.scan.as_ref().map(|_| ScanPath {
+            waypoints: data.waypoints.clone(),
+            cost: path_cost(&data.waypoints),
+            planning: data.planning.is_some(),
+        })
+    }
+
+    pub fn status(&self) -> Option<ScanStatus> {
+        self.data.lock().unwrap().status()
+    }
+
+    /// Start the current scan, or resume it if paused.
+    pub fn start_scan(&self) -> Result<(), ScanError> {
+        self.start_from(&[ScanState::Created, ScanState::Paused])
+    }
+
+    /// Resume the paused current scan.
+    pub fn resume_scan(&self) -> Result<(), ScanError> {
+        self.start_from(&[ScanState::Paused])
+    }
+
+    fn start_from(&self, states: &[ScanState]) -> Result<(), ScanError> {
+        let mut data = self.data.lock().unwrap();
+        let scan = data.scan.as_ref().ok_or(ScanError::NotFound)?;
+        if !states.contains(&scan.state) {
+            return Err(ScanError::Conflict(format!(
+                "scan {} is {}",
+                scan.id, scan.state
+            )));
+        }
+        // Set right away, so a second start is refused before the scan thread picks this one up.
+        let state = scan.state;
+        data.set_state(ScanState::Scanning);
+        drop(data);
+        self.send(ScanJobMsg::StartScan)
+            .inspect_err(|_| self.data.lock().unwrap().set_state(state))
+    }
+
+    /// Stop planning the path, keeping the best one so far.
+    pub fn cancel_planning(&self) {
+        self.data.lock().unwrap().cancel_planning();
     }
 
-    pub fn start_scan(&self) {
-        self.tx.send(ScanJobMsg::StartScan).unwrap();
+    /// Pause the running scan after the point being scanned.
+    pub fn pause_scan(&self) -> Result<(), ScanError> {
+        let data = self.data.lock().unwrap();
+        let scan = data.scan.as_ref().ok_or(ScanError::NotFound)?;
+        if scan.state != ScanState::Scanning {
+            return Err(ScanError::Conflict(format!(
+                "scan {} isn't running",
+                scan.id
+            )));
+        }
+        drop(data);
+        self.send(ScanJobMsg::PauseScan)
     }
 
-    pub fn pause_scan(&self) {
-        self.tx.send(ScanJobMsg::PauseScan).unwrap();
+    /// Pause the current scan without finishing the point it's at, so the head can be stopped
+    /// right away. The point is scanned again on resume. Does nothing if no scan is running.
+    pub fn halt(&self) {
+        let mut data = self.data.lock().unwrap();
+        if data.scan.as_ref().map(|scan| scan.state) == Some(ScanState::Scanning) {
+            data.halt = true;
+        }
     }
 
-    pub fn save_file(&self) {
-        self.tx.send(ScanJobMsg::SaveFile).unwrap();
+    /// Stop the current scan for good, keeping points scanned so far.
+    pub fn cancel_scan(&self) -> Result<(), ScanError> {
+        let mut data = self.data.lock().unwrap();
+        let scan = data.scan.as_ref().ok_or(ScanError::NotFound)?;
+        match scan.state {
+            ScanState::Scanning => {
+                drop(data);
+                self.send(ScanJobMsg::CancelScan)?;
+            }
+            ScanState::Created | ScanState::Paused => {
+                data.cancel_planning();
+                data.set_state(ScanState::Cancelled);
+                data.save_or_complain();
+            }
+            ScanState::Finished | ScanState::Cancelled | ScanState::Dead => {
+                return Err(ScanError::Conflict(format!(
+                    "scan {} is {}",
+                    scan.id, scan.state
+                )));
+            }
+        }
+        Ok(())
     }
 
-    // There are no way to reset scanned points for now
-    pub fn reset(&self) {
-        todo!()
+    /// Current scan if it's `id`, or a saved one.
+    pub fn scan(&self, id: u64) -> Result<ScanInfo, ScanError> {
+        match self.data.lock().unwrap().info() {
+            Some(info) if info.id == id => Ok(info),
+            _ => load_scan(id),
+        }
+    }
+
+    /// Saved scans with the current one up to date, oldest first.
+    pub fn scans(&self) -> Result<Vec<ScanInfo>, ScanError> {
+        let mut scans = saved_scans().map_err(ScanError::Storage)?;
+        if let Some(current) = self.data.lock().unwrap().info() {
+            scans.retain(|scan| scan.id != current.id);
+            scans.push(current);
+        }
+        Ok(scans)
+    }
+
+    /// Points of scan `id` in `format`.
+    pub fn export(&self, id: u64, format: ExportFormat) -> Result<String, ScanError> {
+        let data = self.data.lock().unwrap();
+        let result = match &data.scan {
+            Some(scan) if scan.id == id => export(&data.scanned_points, format),
+            _ => {
+                drop(data);
+                export(&load_points(id)?, format)
+            }
+        };
+        result.map_err(ScanError::Storage)
+    }
+
+    pub fn save_file(&self) -> Result<(), ScanError> {
+        self.send(ScanJobMsg::SaveFile)
+    }
+
+    /// Hand `msg` to the scan thread without waiting. A running scan only takes messages
+    /// between points, one already waiting there makes this one a conflict.
+    fn send(&self, msg: ScanJobMsg) -> Result<(), ScanError> {
+        self.tx.try_send(msg).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                ScanError::Conflict("scan is still busy with an earlier request".to_string())
+            }
+            TrySendError::Disconnected(_) => ScanError::Stopped,
+        })
     }
 }
 
 fn scan_job(rx: Receiver<ScanJobMsg>, data: Arc<Mutex<ScanJobData>>) {
+    let events = data.lock().unwrap().events.clone();
     while let Ok(msg) = rx.recv() {
         match msg {
-            ScanJobMsg::GeneratePath(opts) => data.lock().unwrap().generate_path(opts),
             ScanJobMsg::StartScan => {
-                /* Main scan loop */
-                loop {
+                let scan_id = {
                     let mut data = data.lock().unwrap();
-                    let point_number = data.scanned_points.len();
-                    let waypoint = data.waypoints.get(point_number);
-
-                    if let Some(waypoint) = waypoint {
-                        if let Ok(msg) = rx.try_recv() {
-                            match msg {
-                                ScanJobMsg::PauseScan => {
-                                    eprintln!("Pausing a scan");
-                                    break;
-                                }
-                                _ => { eprintln!("I got a bullshit request while scanning. ._.")}
-                            }
-                        }
+                    data.cancel_planning();
+                    data.halt = false;
+                    data.info().map(|info| info.id)
+                };
+                let _span = info_span!("scan", id = scan_id).entered();
+                info!("Scanning");
+                let stall_config = config::current().stall_detection.unwrap_or_default();
+                let mut end_state = ScanState::Paused;
 
-                        eprintln!("Going to point {point_number}.");
-                        YAW_CONTROLLER.set_target_pos(waypoint.yaw);
-                        YAW_CONTROLLER.wait_stop();
-                        PITCH_CONTROLLER.set_target_pos(waypoint.pitch);
-                        PITCH_CONTROLLER.wait_stop();
-
-                        let measurement = DISTANCE_CONTROLLER.get_measurement();
-                        match measurement {
-                            DistanceReading::Ok {
-                                distance,
-                                quality,
-                                measuring_time,
-                            } => {
-                                let p =
-                                    Point::from_yaw_pitch_distance(waypoint.yaw, waypoint.pitch, distance.as_mm());
-                                let (roll, pitch, yaw) = ORIENTATION_CONTROLLER
-                                    .lock()
-                                    .unwrap()
-                                    .as_ref()
-                                    .unwrap()
-                                    .get_quat()
-                                    .euler_angles();
-                                let scanned_checkpoint = ScannedCheckpoint {
-                                    x: p.x,
-                                    y: p.y,
-                                    z: p.z,
-                                    waypoint_yaw: waypoint.yaw,
-                                    waypoint_pitch: waypoint.pitch,
-                                    current_yaw: YAW_CONTROLLER.get_current_pos(),
-                                    current_pitch: PITCH_CONTROLLER.get_current_pos(),
-                                    roll,
-                                    pitch,
-                                    yaw,
-                                    distance: distance.as_mm(),
-                                    quality: quality as u32,
-                                };
-                                data.scanned_points.push(scanned_checkpoint);
+                /* Main scan loop */
+                while !shutdown::is_shutting_down() {
+                    if let Ok(msg) = rx.try_recv() {
+                        match msg {
+                            ScanJobMsg::PauseScan => {
+                                info!("Pausing a scan");
+                                break;
                             }
-                            DistanceReading::Err {
-                                measuring_time,
-                                error,
-                            } => {
-                                eprintln!("Error measuring point. {error:?}");
+                            ScanJobMsg::CancelScan => {
+                                info!("Cancelling a scan");
+                                end_state = ScanState::Cancelled;
+                                break;
                             }
-                            DistanceReading::NoReading => {
-                                unreachable!()
+                            _ => {
+                                warn!("Ignoring a scan job request while scanning")
                             }
                         }
-                    } else {
+                    }
+
+                    {
+                        let data = data.lock().unwrap();
+                        if data.halt {
+                            info!("Halting a scan");
+                            break;
+                        }
+                        // Refinement passes are scanned once planned, messages are still taken.
+                        if data.planning.is_some() {
+                            drop(data);
+                            thread::sleep(PLANNING_POLL_INTERVAL);
+                            continue;
+                        }
+                    }
+
+                    // Not holding the lock while moving, so scan can be saved any time.
+                    let next_point = data.lock().unwrap().next_point();
+                    let (point_number, waypoint) = match next_point {
+                        NextPoint::Waypoint(point_number, waypoint) => (point_number, waypoint),
+                        NextPoint::Refine(refinement) => {
+                            let waypoints = refinement.waypoints();
+                            if waypoints.is_empty() {
+                                info!("Nothing left to refine");
+                                data.lock().unwrap().refinement = None;
+                                end_state = ScanState::Finished;
+                                break;
+                            }
+                            let mut locked = data.lock().unwrap();
+                            locked.pass += 1;
+                            info!(
+                                pass = locked.pass,
+                                points = waypoints.len(),
+                                "Refinement pass"
+                            );
+                            locked.events.publish(ScanEvent::Refinement {
+                                pass: locked.pass,
+                                points: waypoints.len(),
+                            });
+                            let from = locked.waypoints.len();
+                            locked.waypoints.extend(waypoints);
+                            drop(locked);
+                            plan_in_background(&data, from);
+                            continue;
+                        }
                         /* Finished scan */
+                        NextPoint::Finished => {
+                            end_state = ScanState::Finished;
+                            break;
+                        }
+                    };
+                    let point_start = Instant::now();
+
+                    debug!(
+                        point = point_number,
+                        yaw = waypoint.yaw,
+                        pitch = waypoint.pitch,
+                        "Going to point"
+                    );
+                    let axes: [(&'static str, &StepMotorController, &AxisKinematics, i32); 2] = [
+                        ("Yaw", &YAW_CONTROLLER, &KINEMATICS.yaw, waypoint.yaw),
+                        (
+                            "Pitch",
+                            &PITCH_CONTROLLER,
+                            &KINEMATICS.pitch,
+                            waypoint.pitch,
+                        ),
+                    ];
+                    let mut pause = false;
+                    let mut halted = false;
+                    for (name, motor, axis, target) in axes {
+                        if data.lock().unwrap().halt {
+                            halted = true;
+                            break;
+                        }
+                        let axis_move =
+                            move_axis(name, motor, axis, target, &stall_config, &events);
+                        let mut data = data.lock().unwrap();
+                        let misregistered = &mut data.misregistered_axes;
+                        match axis_move {
+                            AxisMove::Done => {}
+                            AxisMove::Rehomed => misregistered.retain(|&axis| axis != name),
+                            AxisMove::Stalled | AxisMove::Pause => {
+                                if !misregistered.contains(&name) {
+                                    misregistered.push(name);
+                                }
+                            }
+                        }
+                        if let AxisMove::Pause = axis_move {
+                            pause = true;
+                            break;
+                        }
+                    }
+                    if halted || data.lock().unwrap().halt {
+                        info!(point = point_number, "Halting a scan");
                         break;
                     }
+                    if pause {
+                        warn!(point = point_number, "Pausing a scan after missed steps");
+                        events.publish(ScanEvent::Error {
+                            message: "Paused a scan after missed steps".to_string(),
+                        });
+                        break;
+                    }
+
+                    let measurement = DISTANCE_CONTROLLER.get_measurement();
+                    if shutdown::is_shutting_down() {
+                        // Motors were stopped half way, measurement isn't for this waypoint.
+                        break;
+                    }
+                    match measurement {
+                        DistanceReading::Ok {
+                            distance, quality, ..
+                        } => {
+                            let p = KINEMATICS.point(waypoint, distance.as_mm());
+                            let (roll, pitch, yaw) = ORIENTATION_CONTROLLER
+                                .lock()
+                                .unwrap()
+                                .as_ref()
+                                .unwrap()
+                                .get_quat()
+                                .euler_angles();
+                            let mut data = data.lock().unwrap();
+                            let scanned_checkpoint = ScannedCheckpoint {
+                                x: p.x,
+                                y: p.y,
+                                z: p.z,
+                                waypoint_yaw: waypoint.yaw,
+                                waypoint_pitch: waypoint.pitch,
+                                current_yaw: YAW_CONTROLLER.get_current_pos(),
+                                current_pitch: PITCH_CONTROLLER.get_current_pos(),
+                                roll,
+                                pitch,
+                                yaw,
+                                distance: distance.as_mm(),
+                                quality: quality as u32,
+                                misregistered: !data.misregistered_axes.is_empty(),
+                                pass: data.pass,
+                            };
+                            data.events.publish(ScanEvent::Point {
+                                index: point_number,
+                                point: scanned_checkpoint.clone(),
+                            });
+                            data.scanned_points.push(scanned_checkpoint);
+                            data.scan_time += point_start.elapsed();
+                        }
+                        DistanceReading::Err { error, .. } => {
+                            warn!(
+                                point = point_number,
+                                error = ?error,
+                                code = error as u8,
+                                "Failed measuring point"
+                            );
+                            events.publish(ScanEvent::MeasurementFailed {
+                                index: point_number,
+                                error: format!("{error:?}"),
+                            });
+                        }
+                        DistanceReading::NoReading => {
+                            unreachable!()
+                        }
+                    }
                 }
 
-            },
-            ScanJobMsg::PauseScan => { /* Doing nothing, cause this arm will be matched only while in a paused state*/},
-            ScanJobMsg::SaveFile => {
-                let data = data.lock().unwrap();
-                let json_string = serde_json::to_string(&data.scanned_points).unwrap();
-                std::fs::write("points.json", json_string).unwrap();
-            },
+                let mut data = data.lock().unwrap();
+                info!(
+                    state = %end_state,
+                    points = data.scanned_points.len(),
+                    "Scan stopped"
+                );
+                // A refinement pass being planned is taken as far as it got.
+                data.cancel_planning();
+                data.set_state(end_state);
+                data.save_or_complain();
+            }
+            ScanJobMsg::PauseScan => { /* Doing nothing, cause this arm will be matched only while in a paused state*/
+            }
+            // Scans that aren't running are cancelled right away by `ScanJob::cancel_scan`.
+            ScanJobMsg::CancelScan => {}
+            ScanJobMsg::SaveFile => data.lock().unwrap().save_or_complain(),
 
             #[allow(unreachable_patterns)]
-            _ => unreachable!()
+            _ => unreachable!(),
         }
     }
 }
@@ -0,0 +1,104 @@
+//! Access settings of the HTTP API: listeners, API tokens and allowed origins.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// What a client can do, every role can do everything the roles before it can.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Status, streams and downloads.
+    ReadOnly,
+    /// Motion, scans and calibration.
+    Control,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token: String,
+    pub role: Role,
+    /// Who the token was given to, only for the operator.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+    /// PEM certificate chain.
+    pub cert_path: PathBuf,
+    /// PEM private key of the certificate.
+    pub key_path: PathBuf,
+}
+
+fn default_tls_port() -> u16 {
+    8443
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Plain HTTP port, only redirects to HTTPS when `tls` is set.
+    pub port: u16,
+    /// No tokens turns authentication off, every client gets control.
+    pub tokens: Vec<ApiToken>,
+    /// Origins allowed to call the API from other sites, `*` for any. The web UI served by
+    /// the server itself doesn't need one.
+    pub cors_origins: Vec<String>,
+    /// HTTPS listener, off if missing.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            port: 8000,
+            tokens: Vec::new(),
+            cors_origins: Vec::new(),
+            tls: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.port > 0, "port must be positive");
+        if let Some(tls) = &self.tls {
+            ensure!(tls.port > 0, "tls.port must be positive");
+            ensure!(tls.port != self.port, "tls.port must differ from port");
+        }
+        for (i, token) in self.tokens.iter().enumerate() {
+            ensure!(!token.token.is_empty(), "token {i} is empty");
+            ensure!(
+                !self.tokens[..i].iter().any(|t| t.token == token.token),
+                "token {i} is a duplicate"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn auth_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Role of a client presenting `token`, `None` if it isn't allowed in at all.
+    pub fn role(&self, token: Option<&str>) -> Option<Role> {
+        if !self.auth_enabled() {
+            return Some(Role::Control);
+        }
+        let token = token?;
+        self.tokens
+            .iter()
+            .filter(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes()))
+            .map(|t| t.role)
+            .max()
+    }
+}
+
+/// Compares without bailing on the first difference, so timing doesn't leak the token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,4 +1,5 @@
 use std::sync::{Condvar, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 pub trait IsDead {
     fn is_dead(&self) -> bool;
@@ -46,3 +47,38 @@ impl<S: std::cmp::PartialEq + std::cmp::Eq + IsDead + Copy> SharedState<S> {
         }
     }
 }
+
+/// Events sent to every subscriber, subscribers that went away are dropped.
+///
+/// Receivers can be awaited from async code, or read with `blocking_recv` from threads.
+pub struct EventStream<E: Clone> {
+    subscribers: Mutex<Vec<UnboundedSender<E>>>,
+}
+
+impl<E: Clone> EventStream<E> {
+    pub fn new() -> Self {
+        EventStream {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Receive every event published from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<E> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: E) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+impl<E: Clone> Default for EventStream<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
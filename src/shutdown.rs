@@ -0,0 +1,131 @@
+//! Safe shutdown on Ctrl-C, SIGTERM and panics.
+//!
+//! Hardware registers what has to be done on exit with [`on_shutdown`] once it's
+//! initialized, so nothing gets initialized just to be shut down. Binaries call
+//! [`install`] to run registered hooks, in [`ShutdownStage`] order, on a signal or panic.
+//!
+//! # Example
+//! ```ignore
+//! fn main() {
+//!     lidarino::shutdown::install().expect("Failed to install shutdown handlers");
+//!     // ... use hardware ...
+//!     lidarino::shutdown::shutdown(0);
+//! }
+//! ```
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Process is killed if hooks take longer than this.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Exit code after a panic, same as an unhandled panic in `main`.
+const PANIC_EXIT_CODE: i32 = 101;
+
+/// Order in which shutdown hooks are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownStage {
+    /// Stop motors at their current position.
+    StopMotion,
+    /// Drive motor coils low.
+    PowerDown,
+    /// Turn the distance sensor laser off.
+    LaserOff,
+    /// Write in-progress scans to disk.
+    SaveScan,
+}
+
+struct Hook {
+    stage: ShutdownStage,
+    name: &'static str,
+    run: Box<dyn Fn() + Send>,
+}
+
+lazy_static! {
+    static ref HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Register `hook` to be run at `stage` of the shutdown.
+pub fn on_shutdown<F>(stage: ShutdownStage, name: &'static str, hook: F)
+where
+    F: Fn() + Send + 'static,
+{
+    HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Hook {
+            stage,
+            name,
+            run: Box::new(hook),
+        });
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Shut down on SIGINT, SIGTERM or a panic in any thread.
+pub fn install() -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!(signal, "Got signal, shutting down");
+            shutdown(128 + signal);
+        }
+    });
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // Default hook only prints to stderr, log files should have it too.
+        error!(thread = thread::current().name(), "{info}");
+        // Panics in hooks are caught by `shutdown` itself.
+        if is_shutting_down() {
+            return;
+        }
+        // Panicking thread has to unwind, it might own hardware which is
+        // shut down on drop (e.g. MCP23S17 controller thread).
+        let shutdown_thread = thread::spawn(|| shutdown(PANIC_EXIT_CODE));
+        if thread::current().name() == Some("main") {
+            // Process would exit once `main` unwinds.
+            let _ = shutdown_thread.join();
+        }
+    }));
+    Ok(())
+}
+
+/// Run all the registered hooks and exit the process with `exit_code`.
+///
+/// Only the first call runs hooks, the others block untill the process exits.
+pub fn shutdown(exit_code: i32) -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        loop {
+            thread::park();
+        }
+    }
+
+    thread::spawn(move || {
+        thread::sleep(SHUTDOWN_TIMEOUT);
+        error!("Shutdown timed out");
+        std::process::exit(exit_code);
+    });
+
+    let mut hooks = std::mem::take(&mut *HOOKS.lock().unwrap_or_else(PoisonError::into_inner));
+    // Stable sort, hooks of the same stage run in registration order.
+    hooks.sort_by_key(|hook| hook.stage);
+    for hook in hooks {
+        if panic::catch_unwind(AssertUnwindSafe(&hook.run)).is_err() {
+            error!(hook = hook.name, "Shutdown hook panicked");
+        }
+    }
+    std::process::exit(exit_code)
+}
@@ -0,0 +1,462 @@
+"use strict";
+
+// Lidarino web UI, served by http_server and talking to its JSON API.
+
+const wsBase = (location.protocol === "https:" ? "wss://" : "ws://") + location.host;
+const $ = (id) => document.getElementById(id);
+
+// API token, kept in the browser between visits.
+let token = localStorage.getItem("lidarino-token") || "";
+$("token").value = token;
+$("token").addEventListener("change", () => {
+    token = $("token").value.trim();
+    localStorage.setItem("lidarino-token", token);
+    location.reload();
+});
+
+// Websockets can't send headers, the token goes hex encoded in a second subprotocol.
+function openSocket(path) {
+    const protocols = ["lidarino"];
+    if (token) {
+        const bytes = Array.from(new TextEncoder().encode(token));
+        const hex = bytes.map((b) => b.toString(16).padStart(2, "0")).join("");
+        protocols.push(`lidarino.token.${hex}`);
+    }
+    return new WebSocket(wsBase + path, protocols);
+}
+
+// Links can't send headers either, downloads are fetched and handed over as a blob.
+async function download(path, filename) {
+    const headers = token ? { "Authorization": `Bearer ${token}` } : {};
+    const response = await fetch(path, { headers });
+    if (!response.ok) {
+        const data = await response.json().catch(() => null);
+        throw new Error((data && data.error) || response.statusText);
+    }
+    const url = URL.createObjectURL(await response.blob());
+    const link = document.createElement("a");
+    link.href = url;
+    link.download = filename;
+    link.click();
+    URL.revokeObjectURL(url);
+}
+
+async function api(method, path, body) {
+    const options = { method, headers: { "Accept": "application/json" } };
+    if (token) {
+        options.headers["Authorization"] = `Bearer ${token}`;
+    }
+    if (body !== undefined) {
+        options.headers["Content-Type"] = "application/json";
+        options.body = JSON.stringify(body);
+    }
+    const response = await fetch(path, options);
+    const data = await response.json().catch(() => null);
+    if (!response.ok) {
+        throw new Error((data && data.error) || response.statusText);
+    }
+    return data;
+}
+
+function showError(e) {
+    $("error").textContent = e ? e.message : "";
+}
+
+/* Point cloud viewer */
+
+const viewer = {
+    canvas: $("viewer"),
+    points: [],
+    azimuth: 0.6,
+    elevation: 0.4,
+    distance: 8,
+    dirty: true,
+};
+
+function resetView() {
+    viewer.azimuth = 0.6;
+    viewer.elevation = 0.4;
+    viewer.distance = 8;
+    viewer.dirty = true;
+}
+
+function setPoints(points) {
+    viewer.points = points;
+    viewer.dirty = true;
+}
+
+function addPoint(point) {
+    viewer.points.push(point);
+    viewer.dirty = true;
+}
+
+function pointColor(p, maxDistance, maxPass) {
+    switch ($("color-by").value) {
+        case "pass":
+            return `hsl(${(p.pass / Math.max(maxPass, 1)) * 240}, 90%, 60%)`;
+        case "quality":
+            return `hsl(${Math.min(p.quality, 255) / 255 * 120}, 90%, 55%)`;
+        default:
+            return `hsl(${(1 - p.distance / maxDistance) * 240}, 90%, 60%)`;
+    }
+}
+
+// Orbit camera around the scanner, z pointing up.
+function project(p, width, height) {
+    const ca = Math.cos(viewer.azimuth), sa = Math.sin(viewer.azimuth);
+    const ce = Math.cos(viewer.elevation), se = Math.sin(viewer.elevation);
+    const x = ca * p.x - sa * p.y;
+    const y = sa * p.x + ca * p.y;
+    const depth = ce * y + se * p.z + viewer.distance;
+    const up = -se * y + ce * p.z;
+    if (depth < 0.05) {
+        return null;
+    }
+    const f = height / depth;
+    return [width / 2 + x * f, height / 2 - up * f];
+}
+
+function drawViewer() {
+    const canvas = viewer.canvas;
+    const width = canvas.clientWidth, height = canvas.clientHeight;
+    if (canvas.width !== width || canvas.height !== height) {
+        canvas.width = width;
+        canvas.height = height;
+    }
+    const ctx = canvas.getContext("2d");
+    ctx.clearRect(0, 0, width, height);
+
+    // Axes from the scanner, 1 m long: x red, y green, z blue.
+    const origin = project({ x: 0, y: 0, z: 0 }, width, height);
+    [[{ x: 1, y: 0, z: 0 }, "#e44"], [{ x: 0, y: 1, z: 0 }, "#4e4"], [{ x: 0, y: 0, z: 1 }, "#48f"]]
+        .forEach(([axis, color]) => {
+            const end = project(axis, width, height);
+            if (origin && end) {
+                ctx.strokeStyle = color;
+                ctx.beginPath();
+                ctx.moveTo(origin[0], origin[1]);
+                ctx.lineTo(end[0], end[1]);
+                ctx.stroke();
+            }
+        });
+
+    let maxDistance = 1, maxPass = 0;
+    for (const p of viewer.points) {
+        maxDistance = Math.max(maxDistance, p.distance);
+        maxPass = Math.max(maxPass, p.pass || 0);
+    }
+    for (const p of viewer.points) {
+        const s = project(p, width, height);
+        if (s) {
+            ctx.fillStyle = pointColor(p, maxDistance, maxPass);
+            ctx.fillRect(s[0] - 1, s[1] - 1, 2, 2);
+        }
+    }
+    $("point-count").textContent = `${viewer.points.length} points`;
+}
+
+function animate() {
+    if (viewer.dirty) {
+        viewer.dirty = false;
+        drawViewer();
+    }
+    requestAnimationFrame(animate);
+}
+
+let drag = null;
+viewer.canvas.addEventListener("mousedown", (e) => drag = { x: e.clientX, y: e.clientY });
+window.addEventListener("mouseup", () => drag = null);
+window.addEventListener("mousemove", (e) => {
+    if (!drag) {
+        return;
+    }
+    viewer.azimuth += (e.clientX - drag.x) * 0.01;
+    viewer.elevation = Math.max(-1.5, Math.min(1.5, viewer.elevation + (e.clientY - drag.y) * 0.01));
+    drag = { x: e.clientX, y: e.clientY };
+    viewer.dirty = true;
+});
+viewer.canvas.addEventListener("wheel", (e) => {
+    e.preventDefault();
+    viewer.distance = Math.max(0.5, viewer.distance * Math.exp(e.deltaY * 0.001));
+    viewer.dirty = true;
+});
+window.addEventListener("resize", () => viewer.dirty = true);
+$("reset-view").addEventListener("click", resetView);
+$("color-by").addEventListener("change", () => viewer.dirty = true);
+
+/* Live scan events and saved scans */
+
+let liveScanId = null;
+
+function showStatus(status) {
+    if (!status) {
+        $("scan-status").textContent = "No scan";
+        return;
+    }
+    liveScanId = status.id;
+    const eta = status.eta_s == null ? "–" : `${Math.round(status.eta_s)} s`;
+    $("scan-status").textContent =
+        `Scan ${status.id}: ${status.state}, ${status.points_scanned}/${status.waypoints} points` +
+        `, pass ${status.passes}, ETA ${eta}${status.planning ? ", planning path" : ""}`;
+}
+
+function connectScanEvents() {
+    const ws = openSocket("/scans/events");
+    ws.onmessage = (message) => {
+        const event = JSON.parse(message.data);
+        const live = $("source").value === "live";
+        switch (event.event) {
+            case "snapshot":
+                showStatus(event.status);
+                if (live) {
+                    setPoints(event.points);
+                }
+                break;
+            case "state":
+                if (event.id !== liveScanId && live) {
+                    setPoints([]);
+                }
+                refreshStatus();
+                if (event.state !== "scanning") {
+                    refreshScans();
+                }
+                break;
+            case "point":
+                if (live) {
+                    addPoint(event.point);
+                }
+                refreshStatus();
+                break;
+            case "planning":
+                if (event.finished) {
+                    refreshStatus();
+                }
+                break;
+            case "error":
+                showError(new Error(event.message));
+                break;
+            case "missed_steps":
+                showError(new Error(`${event.axis} axis missed steps`));
+                break;
+        }
+    };
+    ws.onclose = () => setTimeout(connectScanEvents, 2000);
+}
+
+async function refreshStatus() {
+    try {
+        showStatus(await api("GET", "/scans/current"));
+    } catch (e) {
+        showStatus(null);
+    }
+}
+
+async function refreshScans() {
+    let scans;
+    try {
+        scans = await api("GET", "/scans");
+    } catch (e) {
+        showError(e);
+        return;
+    }
+    const list = $("scans");
+    const source = $("source");
+    const selected = source.value;
+    list.innerHTML = "";
+    source.length = 1;
+    for (const scan of scans.slice().reverse()) {
+        const item = document.createElement("li");
+        const created = new Date(scan.created * 1000).toLocaleString();
+        item.textContent = `${created}, ${scan.state}, ${scan.points_scanned} points `;
+        for (const format of ["json", "csv", "ply", "xyz"]) {
+            const link = document.createElement("a");
+            link.href = "#";
+            link.addEventListener("click", (event) => {
+                event.preventDefault();
+                download(`/scans/${scan.id}/points/${format}`, `scan_${scan.id}.${format}`)
+                    .catch(showError);
+            });
+            link.textContent = format;
+            item.appendChild(link);
+        }
+        list.appendChild(item);
+        source.add(new Option(`Scan ${scan.id} (${created})`, scan.id));
+    }
+    source.value = selected;
+    if (source.value !== selected) {
+        source.value = "live";
+    }
+}
+
+$("source").addEventListener("change", async () => {
+    const id = $("source").value;
+    try {
+        setPoints(await api("GET", `/scans/${id === "live" ? "current" : id}/points/json`));
+    } catch (e) {
+        setPoints([]);
+    }
+});
+
+/* Scan job controls */
+
+$("create-scan").addEventListener("submit", async (e) => {
+    e.preventDefault();
+    const form = Object.fromEntries(new FormData(e.target));
+    const body = {
+        options: {
+            amount_of_points: Number(form.amount_of_points),
+            region: {
+                yaw: { from: Number(form.yaw_from), to: Number(form.yaw_to) },
+                pitch: { from: Number(form.pitch_from), to: Number(form.pitch_to) },
+            },
+            // Default refinement options are filled in by the server.
+            refinement: form.refine ? {} : null,
+        },
+        pattern: { type: form.pattern },
+    };
+    try {
+        await api("POST", "/scans", body);
+        showError(null);
+        $("source").value = "live";
+        setPoints([]);
+        refreshScans();
+    } catch (e) {
+        showError(e);
+    }
+});
+
+document.querySelectorAll(".scan-buttons button").forEach((button) => {
+    button.addEventListener("click", async () => {
+        try {
+            showStatus(await api("POST", `/scans/current/${button.dataset.action}`));
+            showError(null);
+        } catch (e) {
+            showError(e);
+        }
+    });
+});
+$("refresh-scans").addEventListener("click", refreshScans);
+
+/* Head controls */
+
+async function refreshHead() {
+    try {
+        const motion = await api("GET", "/motion");
+        $("head-state").textContent =
+            `yaw ${motion.yaw.deg.toFixed(1)}°, pitch ${motion.pitch.deg.toFixed(1)}°`;
+    } catch (e) {
+        $("head-state").textContent = "yaw –, pitch –";
+    }
+}
+
+async function motion(path, body) {
+    try {
+        await api("POST", path, body);
+        showError(null);
+    } catch (e) {
+        showError(e);
+    }
+    refreshHead();
+}
+
+// A click moves one step, holding the button jogs till it's released. Jogs are renewed well
+// within their timeout, the head stops by itself if the page goes away.
+const JOG_HOLD_MS = 300;
+const JOG_RENEW_MS = 200;
+
+document.querySelectorAll(".jog-buttons button").forEach((button) => {
+    const axis = button.dataset.axis;
+    const dir = Number(button.dataset.dir);
+    let holdTimer = null;
+    let jogTimer = null;
+
+    button.addEventListener("pointerdown", () => {
+        holdTimer = setTimeout(() => {
+            holdTimer = null;
+            const jog = () => api("POST", "/motion/jog", { [axis]: dir, timeout_ms: 500 }).catch(showError);
+            jog();
+            jogTimer = setInterval(jog, JOG_RENEW_MS);
+        }, JOG_HOLD_MS);
+    });
+    const release = () => {
+        if (holdTimer !== null) {
+            clearTimeout(holdTimer);
+            holdTimer = null;
+            motion("/motion/move", { [axis]: Number($("jog-step").value) * dir, relative: true });
+        } else if (jogTimer !== null) {
+            clearInterval(jogTimer);
+            jogTimer = null;
+            motion("/motion/jog", { [axis]: 0 });
+        }
+    };
+    button.addEventListener("pointerup", release);
+    button.addEventListener("pointerleave", release);
+});
+
+$("stop").addEventListener("click", () => motion("/motion/stop"));
+$("home").addEventListener("click", () => motion("/motion/home"));
+
+$("measure").addEventListener("click", async () => {
+    $("measurement").textContent = "measuring…";
+    try {
+        const reading = await api("POST", "/measure_distance");
+        $("measurement").textContent = reading.err
+            ? reading.err
+            : `${reading.distance_mm} mm, quality ${reading.quality}`;
+    } catch (e) {
+        $("measurement").textContent = e.message;
+    }
+});
+
+/* Orientation gizmo, fed with "roll,pitch,yaw" radians by /orientation */
+
+function drawGizmo(roll, pitch, yaw) {
+    const canvas = $("gizmo");
+    const ctx = canvas.getContext("2d");
+    const c = canvas.width / 2, r = canvas.width * 0.4;
+    ctx.clearRect(0, 0, canvas.width, canvas.height);
+
+    // Columns of Rz(yaw) * Ry(pitch) * Rx(roll), the head axes in world frame.
+    const [cr, sr, cp, sp, cy, sy] =
+        [Math.cos(roll), Math.sin(roll), Math.cos(pitch), Math.sin(pitch), Math.cos(yaw), Math.sin(yaw)];
+    const axes = [
+        [[cy * cp, sy * cp, -sp], "#e44"],
+        [[cy * sp * sr - sy * cr, sy * sp * sr + cy * cr, cp * sr], "#4e4"],
+        [[cy * sp * cr + sy * sr, sy * sp * cr - cy * sr, cp * cr], "#48f"],
+    ];
+    // Fixed oblique view of the world frame.
+    const view = ([x, y, z]) => [c + (x - y * 0.5) * r, c - (z - y * 0.35) * r];
+    for (const [axis, color] of axes) {
+        const [x, y] = view(axis);
+        ctx.strokeStyle = color;
+        ctx.lineWidth = 3;
+        ctx.beginPath();
+        ctx.moveTo(c, c);
+        ctx.lineTo(x, y);
+        ctx.stroke();
+    }
+}
+
+function connectOrientation() {
+    const ws = openSocket("/orientation");
+    let pending = null;
+    ws.onmessage = (message) => {
+        // Messages come at 60 Hz, only the latest one is drawn.
+        if (pending === null) {
+            requestAnimationFrame(() => {
+                drawGizmo(...pending);
+                pending = null;
+            });
+        }
+        pending = message.data.split(",").map(Number);
+    };
+    ws.onclose = () => setTimeout(connectOrientation, 2000);
+}
+
+resetView();
+animate();
+connectScanEvents();
+connectOrientation();
+refreshScans();
+refreshHead();
+setInterval(refreshHead, 2000);